@@ -0,0 +1,14 @@
+; Locals bound by let, let* and letrec in a hot loop. Without inlining each of them allocates
+; a frame and makes a call.
+(define (sum-of-squares n)
+  (let loop ((i 0) (acc 0))
+    (if (= i n)
+      acc
+      (let* ((sq (* i i))
+             (next (+ i 1)))
+        (let ((acc (+ acc sq)))
+          (letrec ((total acc))
+            (loop next total)))))))
+
+(display (sum-of-squares 50000))
+(newline)
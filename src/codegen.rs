@@ -1,3 +1,4 @@
+use crate::lexer::Meta;
 use crate::syntax;
 use crate::obj::*;
 use crate::vm::Inst;
@@ -17,6 +18,10 @@ impl CodeGen {
     pub fn generate(&mut self, ast: &syntax::AST, is_main: bool) -> Vec<Inst> {
         self.builder.init();
 
+        for c in &ast.id_ctxs {
+            self.builder.add_id_ctx(c.id, c.parent, c.scope);
+        }
+
         for t in &ast.body {
             t.gen(&mut self.builder, false);
         }
@@ -42,7 +47,7 @@ pub fn join(l: Vec<Inst>, r: Vec<Inst>) -> Vec<Inst> {
         match inst {
             Inst::Jump(a) => *a += len_l as u32,
             Inst::JumpIf(a) => *a += len_l as u32,
-            Inst::CreateClosure(a) => *a += len_l as u32,
+            Inst::CreateClosure(a, _) => *a += len_l as u32,
             Inst::PushReturnContext(a) => *a += len_l as u32,
             _ => (),
         }
@@ -53,12 +58,18 @@ pub fn join(l: Vec<Inst>, r: Vec<Inst>) -> Vec<Inst> {
 
 impl Id {
     fn new(id: &syntax::Id, builder: &Builder) -> Self {
-        let id_ctx = builder.get_true_id_ctx(&id.v, id.id_ctx);
+        match builder.resolve(&id.v, id.id_ctx) {
+            Some((_, Some(alias), _)) => Self(alias.clone()),
+            Some((id_ctx, None, _)) => Self::binding(&id.v, *id_ctx),
+            None => Self::binding(&id.v, builder.root_ctx(id.id_ctx)),
+        }
+    }
 
+    fn binding(v: &str, id_ctx: u32) -> Self {
         if id_ctx == 0 {
-            Self(id.v.clone())
+            Self(v.into())
         } else {
-            Self(format!("{}~{}", id.v, id_ctx))
+            Self(format!("{}~{}", v, id_ctx))
         }
     }
 }
@@ -74,14 +85,57 @@ impl Gen for syntax::Toplevel {
             syntax::Toplevel::Exp(t) => t.gen(builder, false),
             syntax::Toplevel::Define(t) => t.gen(builder, false),
             syntax::Toplevel::Load(t) => t.gen(builder, false),
+            syntax::Toplevel::Library(t) => {
+                t.gen(builder, false);
+                builder.push(Inst::Push(Obj::Null));
+            }
+            syntax::Toplevel::Import(t) => t.gen(builder, false),
+            syntax::Toplevel::Include(t) => {
+                for t in &t.body {
+                    t.gen(builder, false);
+                }
+            }
         }
     }
 }
 
+impl Gen for syntax::Library {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        // Library bodies may refer to definitions that come later
+        for id in &self.ids {
+            builder.def(&id.v, id.id_ctx);
+        }
+
+        for t in &self.body {
+            t.gen(builder, false);
+        }
+    }
+}
+
+impl Gen for syntax::Import {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        for library in &self.libraries {
+            library.gen(builder, false);
+        }
+
+        for binding in &self.bindings {
+            let target = Id::new(&binding.target, builder);
+            builder.def_alias(&binding.id.v, binding.id.id_ctx, target.0);
+        }
+
+        builder.push(Inst::Push(Obj::Null));
+    }
+}
+
 impl Gen for syntax::Load {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
         self.src.gen(builder, false);
-        builder.push(Inst::Load);
+
+        if self.is_once {
+            builder.push(Inst::Require);
+        } else {
+            builder.push(Inst::Load);
+        }
     }
 }
 
@@ -90,6 +144,8 @@ impl Gen for syntax::Define {
         match self {
             Self::Var(t) => t.gen(builder, false),
             Self::Func(t) => t.gen(builder, false),
+            Self::Values(t) => t.gen(builder, false),
+            Self::RecordType(t) => t.gen(builder, false),
         }
     }
 }
@@ -98,7 +154,13 @@ impl Gen for syntax::DefVar {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
         builder.def(&self.id.v, self.id.id_ctx);
         builder.push(Inst::Def(Id::new(&self.id, builder)));
-        self.exp.gen(builder, false);
+
+        match &self.exp {
+            syntax::Exp::Lambda(lambda) => lambda.gen_named(builder, Some(&self.id)),
+            syntax::Exp::CaseLambda(lambda) => lambda.gen_named(builder, Some(&self.id)),
+            _ => self.exp.gen(builder, false),
+        }
+
         builder.push(Inst::Set(Id::new(&self.id, builder)));
         builder.push(Inst::Push(Obj::Null));
     }
@@ -123,6 +185,149 @@ impl Gen for syntax::DefFunc {
         };
 
         set.gen(builder, false);
+    }
+}
+
+impl Gen for syntax::DefValues {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        let (args, varg) = match &self.formals {
+            syntax::Arg::Args(args) => (args.args.clone(), args.varg.clone()),
+            syntax::Arg::VArg(id) => (vec![], Some(id.clone())),
+        };
+
+        for id in args.iter().chain(varg.iter()) {
+            builder.def(&id.v, id.id_ctx);
+            builder.push(Inst::Def(Id::new(id, builder)));
+        }
+
+        self.exp.gen(builder, false);
+        builder.push(Inst::ValuesToList);
+
+        for id in &args {
+            builder.push(Inst::Dup);
+            builder.push(Inst::Car);
+            builder.push(Inst::Set(Id::new(id, builder)));
+            builder.push(Inst::Cdr);
+        }
+
+        if let Some(id) = &varg {
+            builder.push(Inst::Set(Id::new(id, builder)));
+        } else {
+            builder.push(Inst::Pop);
+        }
+
+        builder.push(Inst::Push(Obj::Null));
+    }
+}
+
+impl Gen for syntax::DefRecordType {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        let name = self.id.v.trim_start_matches('<').trim_end_matches('>');
+
+        builder.def(&self.id.v, self.id.id_ctx);
+        builder.push(Inst::Def(Id::new(&self.id, builder)));
+        builder.push(Inst::MakeRecordType(
+            Id(name.into()),
+            self.fields.iter().map(|f| Id(f.id.v.clone())).collect(),
+        ));
+        builder.push(Inst::Set(Id::new(&self.id, builder)));
+
+        let meta = &self.meta;
+        let rtd = syntax::Exp::Id(self.id.clone());
+        let record = syntax::Id {
+            meta: meta.clone(),
+            id_ctx: 0,
+            v: " record".into(),
+        };
+        let value = syntax::Id {
+            meta: meta.clone(),
+            id_ctx: 0,
+            v: " value".into(),
+        };
+
+        let mut procs = vec![];
+
+        if let Some(c) = &self.constructor {
+            let mut list =
+                syntax::Exp::Const(syntax::Const::Null(syntax::Null { meta: meta.clone() }));
+
+            for f in self.fields.iter().rev() {
+                let v = match c.args.iter().find(|a| a.v == f.id.v) {
+                    Some(a) => syntax::Exp::Id(a.clone()),
+                    None => syntax::Exp::Const(syntax::Const::Bool(syntax::Bool {
+                        meta: f.meta.clone(),
+                        v: false,
+                    })),
+                };
+
+                list = apply_exp(meta, "cons", vec![v, list]);
+            }
+
+            procs.push((
+                c.id.clone(),
+                c.args.clone(),
+                apply_exp(meta, "~make-record", vec![rtd.clone(), list]),
+            ));
+        }
+
+        procs.push((
+            self.predicate.clone(),
+            vec![record.clone()],
+            apply_exp(meta, "~record?", vec![rtd.clone(), syntax::Exp::Id(record.clone())]),
+        ));
+
+        for (i, f) in self.fields.iter().enumerate() {
+            let idx = syntax::Exp::Const(syntax::Const::Num(syntax::Num {
+                meta: f.meta.clone(),
+                v: Number::Int(i as i64),
+            }));
+
+            procs.push((
+                f.accessor.clone(),
+                vec![record.clone()],
+                apply_exp(
+                    &f.meta,
+                    "~record-ref",
+                    vec![rtd.clone(), syntax::Exp::Id(record.clone()), idx.clone()],
+                ),
+            ));
+
+            if let Some(modifier) = &f.modifier {
+                procs.push((
+                    modifier.clone(),
+                    vec![record.clone(), value.clone()],
+                    apply_exp(
+                        &f.meta,
+                        "~record-set!",
+                        vec![
+                            rtd.clone(),
+                            syntax::Exp::Id(record.clone()),
+                            idx,
+                            syntax::Exp::Id(value.clone()),
+                        ],
+                    ),
+                ));
+            }
+        }
+
+        for (id, args, exp) in procs {
+            syntax::DefVar {
+                meta: id.meta.clone(),
+                id,
+                exp: syntax::Exp::Lambda(Box::new(syntax::Lambda {
+                    meta: meta.clone(),
+                    arg: syntax::Arg::Args(syntax::Args {
+                        meta: meta.clone(),
+                        args,
+                        varg: None,
+                    }),
+                    body: wrap_body(meta, exp),
+                })),
+            }
+            .gen(builder, false);
+            builder.push(Inst::Pop);
+        }
+
         builder.push(Inst::Push(Obj::Null));
     }
 }
@@ -135,31 +340,56 @@ impl Gen for syntax::Exp {
             Self::Lambda(t) => t.gen(builder, is_tail),
             Self::Apply(t) => t.gen(builder, is_tail),
             Self::Quote(t) => t.gen(builder, is_tail),
+            Self::QuasiQuote(t) => t.gen(builder, is_tail),
             Self::Set(t) => t.gen(builder, is_tail),
             Self::Let(t) => t.gen(builder, is_tail),
             Self::LetAster(t) => t.gen(builder, is_tail),
             Self::LetRec(t) => t.gen(builder, is_tail),
+            Self::LetValues(t) => t.gen(builder, is_tail),
+            Self::LetAsterValues(t) => t.gen(builder, is_tail),
+            Self::Receive(t) => t.gen(builder, is_tail),
             Self::If(t) => t.gen(builder, is_tail),
             Self::Cond(t) => t.gen(builder, is_tail),
+            Self::Case(t) => t.gen(builder, is_tail),
+            Self::When(t) => t.gen(builder, is_tail),
+            Self::Unless(t) => t.gen(builder, is_tail),
+            Self::CaseLambda(t) => t.gen(builder, is_tail),
+            Self::Assert(t) => t.gen(builder, is_tail),
             Self::And(t) => t.gen(builder, is_tail),
             Self::Or(t) => t.gen(builder, is_tail),
             Self::Begin(t) => t.gen(builder, is_tail),
             Self::Do(t) => t.gen(builder, is_tail),
+            Self::LetSyntax(t) => t.gen(builder, is_tail),
         }
     }
 }
 
+impl Gen for syntax::LetSyntax {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        builder.enter_new_scope();
+        self.body.gen(builder, is_tail);
+        builder.exit_cur_scope();
+    }
+}
+
 impl Gen for syntax::Lambda {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        self.gen_named(builder, None);
+    }
+}
+
+impl syntax::Lambda {
+    fn gen_named(&self, builder: &mut Builder, name: Option<&syntax::Id>) {
         builder.enter_new_scope();
 
         let lambda_id = builder.get_label();
         let label = builder.get_label();
 
-        builder.push_temp(TempInst::CreateClosure(lambda_id));
+        builder.push_temp(TempInst::CreateClosure(lambda_id, name.map(|id| id.v.as_str().into())));
         builder.push_temp(TempInst::Jump(label));
 
         builder.push_label(lambda_id);
+        builder.enter_lambda();
 
         match &self.arg {
             syntax::Arg::Args(args) => {
@@ -174,7 +404,6 @@ impl Gen for syntax::Lambda {
                     builder.push(Inst::Def(Id::new(id, builder)));
                     builder.push(Inst::CollectVArg(Id::new(id, builder)));
                     builder.push(Inst::Set(Id::new(id, builder)));
-                    builder.push(Inst::Push(Obj::Null));
                 }
             }
             syntax::Arg::VArg(id) => {
@@ -182,7 +411,6 @@ impl Gen for syntax::Lambda {
                 builder.push(Inst::Def(Id::new(id, builder)));
                 builder.push(Inst::CollectVArg(Id::new(id, builder)));
                 builder.push(Inst::Set(Id::new(id, builder)));
-                builder.push(Inst::Push(Obj::Null));
             }
         }
 
@@ -190,6 +418,7 @@ impl Gen for syntax::Lambda {
 
         builder.push(Inst::Ret);
 
+        builder.exit_lambda();
         builder.push_label(label);
 
         builder.exit_cur_scope();
@@ -212,7 +441,7 @@ impl Gen for syntax::Apply {
 
         let builtin_inst = if let syntax::Exp::Id(id) = func {
             match id.v.as_str() {
-                "display" => Some(Inst::Display),
+                "~display" => Some(Inst::Display),
                 "~+" => Some(Inst::Add),
                 "~-" => Some(Inst::Sub),
                 "~*" => Some(Inst::Mul),
@@ -235,6 +464,12 @@ impl Gen for syntax::Apply {
                 "string?" => Some(Inst::IsString),
                 "proc?" => Some(Inst::IsProc),
                 "symbol?" => Some(Inst::IsSymbol),
+                "vector?" => Some(Inst::IsVector),
+                "vector-length" => Some(Inst::VectorLength),
+                "vector-ref" => Some(Inst::VectorRef),
+                "vector-set!" => Some(Inst::VectorSet),
+                "vector->list" => Some(Inst::VectorToList),
+                "list->vector" => Some(Inst::ListToVector),
                 "eq?" => Some(Inst::IsEq),
                 "equal?" => Some(Inst::IsEqual),
                 "symbol->string" => Some(Inst::SymToStr),
@@ -242,6 +477,54 @@ impl Gen for syntax::Apply {
                 "string->number" => Some(Inst::StrToNum),
                 "number->string" => Some(Inst::NumToStr),
                 "~string-append" => Some(Inst::StringAppend),
+                "~append" => Some(Inst::Append),
+                "~append!" => Some(Inst::AppendDestructive),
+                "~length" => Some(Inst::Length),
+                "~reverse" => Some(Inst::Reverse),
+                "~list-tail" => Some(Inst::ListTail),
+                "~list-copy" => Some(Inst::ListCopy),
+                "~sort" => Some(Inst::Sort),
+                "~sort!" => Some(Inst::SortDestructive),
+                "~merge" => Some(Inst::Merge),
+                "~memq" | "~memv" => Some(Inst::Memq),
+                "~member" => Some(Inst::Member),
+                "~assq" | "~assv" => Some(Inst::Assq),
+                "~assoc" => Some(Inst::Assoc),
+                "~delete" => Some(Inst::Delete),
+                "~delete-duplicates" => Some(Inst::DeleteDuplicates),
+                "~iota" => Some(Inst::Iota),
+                "~cars" => Some(Inst::Cars),
+                "~cdrs" => Some(Inst::Cdrs),
+                "~rename" => Some(Inst::Rename),
+                "~write" => Some(Inst::Write),
+                "~write-shared" => Some(Inst::WriteShared),
+                "~display->string" => Some(Inst::DisplayString),
+                "~write->string" => Some(Inst::WriteString),
+                "~write-shared->string" => Some(Inst::WriteSharedString),
+                "~call/cc" => Some(Inst::CallCC),
+                "~wind" => Some(Inst::Wind),
+                "~unwind" => Some(Inst::Unwind),
+                "~make-coroutine" => Some(Inst::MakeCoroutine),
+                "~resume" => Some(Inst::Resume),
+                "~yield" => Some(Inst::Yield),
+                "~make-thread" => Some(Inst::MakeThread),
+                "~in-thread" => Some(Inst::InThread),
+                "~on-preempt" => Some(Inst::OnPreempt),
+                "~clock" => Some(Inst::Clock),
+                "~sleep" => Some(Inst::Sleep),
+                "~error" => Some(Inst::Raise),
+                "~list->values" => Some(Inst::ListToValues),
+                "~values->list" => Some(Inst::ValuesToList),
+                "~make-record" => Some(Inst::MakeRecord),
+                "~record-ref" => Some(Inst::RecordRef),
+                "~record-set!" => Some(Inst::RecordSet),
+                "~record?" => Some(Inst::IsRecord),
+                "~eval" => Some(Inst::Eval),
+                "macroexpand" => Some(Inst::MacroExpand),
+                "macroexpand-1" => Some(Inst::MacroExpand1),
+                "~interaction-environment" => Some(Inst::InteractionEnv),
+                "~scheme-report-environment" => Some(Inst::ReportEnv),
+                "~null-environment" => Some(Inst::NullEnv),
                 _ => None,
             }
         } else {
@@ -291,16 +574,81 @@ impl Gen for syntax::Quote {
     }
 }
 
+impl Gen for syntax::QuasiQuote {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        self.template.gen(builder, false);
+    }
+}
+
+impl Gen for syntax::QuasiSExp {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        match self {
+            Self::SExp(t) => t.gen(builder, false),
+            Self::Unquote(t) | Self::UnquoteSplicing(t) => t.gen(builder, false),
+            Self::Pair(t) => t.gen(builder, false),
+            Self::Vector(t) => {
+                t.gen(builder, false);
+                builder.push(Inst::ListToVector);
+            }
+        }
+    }
+}
+
+impl Gen for syntax::QuasiPair {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        if let Some(last) = &self.last {
+            last.gen(builder, false);
+        } else {
+            builder.push(Inst::Push(Obj::Null));
+        }
+
+        for exp in self.exps.iter().rev() {
+            exp.gen(builder, false);
+
+            if let syntax::QuasiSExp::UnquoteSplicing(_) = exp {
+                builder.push(Inst::Append);
+            } else {
+                builder.push(Inst::Cons);
+            }
+        }
+    }
+}
+
 impl Gen for syntax::Set {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
         self.exp.gen(builder, false);
-        builder.push(Inst::Set(Id::new(&self.id, builder)));
+
+        if builder.is_global(&self.id.v, self.id.id_ctx) {
+            builder.push(Inst::SetGlobal(Id::new(&self.id, builder)));
+        } else {
+            builder.push(Inst::Set(Id::new(&self.id, builder)));
+        }
+
         builder.push(Inst::Push(Obj::Null));
     }
 }
 
 impl Gen for syntax::Let {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        if self.id.is_none() && is_inlinable(builder, &self.bindings, &self.body) {
+            for b in self.bindings.bindings.iter().rev() {
+                b.exp.gen(builder, false);
+            }
+
+            builder.enter_new_scope();
+
+            for b in &self.bindings.bindings {
+                let id = def_inline(builder, &b.id);
+                builder.push(Inst::Set(id));
+            }
+
+            self.body.gen(builder, is_tail);
+
+            builder.exit_cur_scope();
+
+            return;
+        }
+
         let arg = syntax::Arg::Args(syntax::Args {
             meta: self.meta.clone(),
             args: self.bindings.bindings.iter().map(|b| b.id.clone()).collect(),
@@ -313,6 +661,7 @@ impl Gen for syntax::Let {
                 arg,
                 body: syntax::Body {
                     meta: self.meta.clone(),
+                    scope: 0,
                     defs: vec![syntax::Define::Func(syntax::DefFunc {
                         meta: self.meta.clone(),
                         id: id.clone(),
@@ -346,12 +695,29 @@ impl Gen for syntax::Let {
             exps: self.bindings.bindings.iter().map(|b| b.exp.clone()).collect(),
         };
 
-        apply.gen(builder, false);
+        apply.gen(builder, is_tail);
     }
 }
 
 impl Gen for syntax::LetAster {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        if is_inlinable(builder, &self.bindings, &self.body) {
+            builder.enter_new_scope();
+
+            for b in &self.bindings.bindings {
+                b.exp.gen(builder, false);
+
+                let id = def_inline(builder, &b.id);
+                builder.push(Inst::Set(id));
+            }
+
+            self.body.gen(builder, is_tail);
+
+            builder.exit_cur_scope();
+
+            return;
+        }
+
         let mut t = syntax::Let {
             meta: self.meta.clone(),
             id: None,
@@ -372,18 +738,41 @@ impl Gen for syntax::LetAster {
                 },
                 body: syntax::Body {
                     meta: self.meta.clone(),
+                    scope: 0,
                     defs: vec![],
                     exps: syntax::NonEmptyVec::new(syntax::Exp::Let(Box::new(t))),
                 },
             };
         }
 
-        t.gen(builder, false);
+        t.gen(builder, is_tail);
     }
 }
 
 impl Gen for syntax::LetRec {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        if is_inlinable(builder, &self.bindings, &self.body) {
+            builder.enter_new_scope();
+
+            let ids = self
+                .bindings
+                .bindings
+                .iter()
+                .map(|b| def_inline(builder, &b.id))
+                .collect::<Vec<_>>();
+
+            for (b, id) in self.bindings.bindings.iter().zip(ids) {
+                b.exp.gen(builder, false);
+                builder.push(Inst::Set(id));
+            }
+
+            self.body.gen(builder, is_tail);
+
+            builder.exit_cur_scope();
+
+            return;
+        }
+
         let bindings = self
             .bindings
             .bindings
@@ -399,7 +788,8 @@ impl Gen for syntax::LetRec {
 
         let mut exps = self.body.exps.clone();
 
-        for b in &self.bindings.bindings {
+        // Each binding is put in front of the others, so the last one goes first.
+        for b in self.bindings.bindings.iter().rev() {
             exps.insert(
                 0,
                 syntax::Exp::Set(Box::new(syntax::Set {
@@ -419,11 +809,271 @@ impl Gen for syntax::LetRec {
             },
             body: syntax::Body {
                 meta: self.body.meta.clone(),
+                scope: self.body.scope,
                 defs: self.body.defs.clone(),
                 exps,
             },
         }
-        .gen(builder, false);
+        .gen(builder, is_tail);
+    }
+}
+
+fn def_inline(builder: &mut Builder, id: &syntax::Id) -> Id {
+    let label = builder.get_label();
+    let alias = format!("{} {}", Id::binding(&id.v, id.id_ctx).0, label);
+
+    builder.def_alias(&id.v, id.id_ctx, alias.clone());
+    builder.push(Inst::Def(Id(alias.clone())));
+
+    Id(alias)
+}
+
+// Inlined locals live in the frame of the enclosing lambda, so outside of one they would be
+// left behind as globals.
+fn is_inlinable(builder: &Builder, bindings: &syntax::Bindings, body: &syntax::Body) -> bool {
+    builder.is_in_lambda()
+        && body.defs.is_empty()
+        && !bindings.bindings.iter().any(|b| creates_closure(&b.exp))
+        && !body_creates_closure(body)
+}
+
+fn body_creates_closure(body: &syntax::Body) -> bool {
+    body.defs.iter().any(|def| match def {
+        syntax::Define::Var(t) => creates_closure(&t.exp),
+        syntax::Define::Values(t) => creates_closure(&t.exp),
+        syntax::Define::Func(_) | syntax::Define::RecordType(_) => true,
+    }) || body.exps.get().iter().any(creates_closure)
+}
+
+fn creates_closure(exp: &syntax::Exp) -> bool {
+    let any = |exps: &[syntax::Exp]| exps.iter().any(creates_closure);
+    let bindings =
+        |bindings: &syntax::Bindings| bindings.bindings.iter().any(|b| creates_closure(&b.exp));
+    let values =
+        |bindings: &[syntax::ValuesBinding]| bindings.iter().any(|b| creates_closure(&b.exp));
+    let clause = |body: &syntax::ClauseBody| match body {
+        syntax::ClauseBody::Exps(exps) => any(exps.get()),
+        syntax::ClauseBody::Arrow(f) => creates_closure(f),
+    };
+
+    match exp {
+        syntax::Exp::Const(_) | syntax::Exp::Id(_) | syntax::Exp::Quote(_) => false,
+        syntax::Exp::Lambda(_) => true,
+        syntax::Exp::Apply(t) => creates_closure(&t.func) || any(&t.exps),
+        syntax::Exp::QuasiQuote(t) => quasi_creates_closure(&t.template),
+        syntax::Exp::Set(t) => creates_closure(&t.exp),
+        syntax::Exp::Let(t) => {
+            t.id.is_some() || bindings(&t.bindings) || body_creates_closure(&t.body)
+        }
+        syntax::Exp::LetAster(t) => bindings(&t.bindings) || body_creates_closure(&t.body),
+        syntax::Exp::LetRec(t) => bindings(&t.bindings) || body_creates_closure(&t.body),
+        syntax::Exp::LetValues(t) => values(&t.bindings) || body_creates_closure(&t.body),
+        syntax::Exp::LetAsterValues(t) => values(&t.bindings) || body_creates_closure(&t.body),
+        syntax::Exp::Receive(t) => creates_closure(&t.exp) || body_creates_closure(&t.body),
+        syntax::Exp::If(t) => {
+            creates_closure(&t.cond) || creates_closure(&t.then) || t.el.iter().any(creates_closure)
+        }
+        syntax::Exp::Cond(t) => {
+            t.matches.iter().any(|m| creates_closure(&m.cond) || clause(&m.then))
+                || t.el.iter().any(|el| any(el.get()))
+        }
+        syntax::Exp::Case(t) => {
+            creates_closure(&t.key)
+                || t.clauses.iter().any(|c| clause(&c.then))
+                || t.el.iter().any(clause)
+        }
+        syntax::Exp::When(t) => creates_closure(&t.cond) || any(t.body.get()),
+        syntax::Exp::Unless(t) => creates_closure(&t.cond) || any(t.body.get()),
+        syntax::Exp::CaseLambda(_) => true,
+        syntax::Exp::LetSyntax(t) => body_creates_closure(&t.body),
+        syntax::Exp::Assert(t) => creates_closure(&t.exp),
+        syntax::Exp::And(t) => any(&t.exps),
+        syntax::Exp::Or(t) => any(&t.exps),
+        syntax::Exp::Begin(t) => any(&t.exps),
+        syntax::Exp::Do(t) => {
+            t.bindings.iter().any(|b| creates_closure(&b.i) || creates_closure(&b.u))
+                || creates_closure(&t.cond)
+                || any(&t.value)
+                || t.body.as_ref().is_some_and(body_creates_closure)
+        }
+    }
+}
+
+fn quasi_creates_closure(t: &syntax::QuasiSExp) -> bool {
+    match t {
+        syntax::QuasiSExp::SExp(_) => false,
+        syntax::QuasiSExp::Unquote(exp) | syntax::QuasiSExp::UnquoteSplicing(exp) => {
+            creates_closure(exp)
+        }
+        syntax::QuasiSExp::Pair(t) | syntax::QuasiSExp::Vector(t) => {
+            t.exps.iter().any(quasi_creates_closure) || t.last.iter().any(quasi_creates_closure)
+        }
+    }
+}
+
+fn id_exp(meta: &Meta, v: &str) -> syntax::Exp {
+    syntax::Exp::Id(syntax::Id {
+        meta: meta.clone(),
+        id_ctx: 0,
+        v: v.into(),
+    })
+}
+
+fn apply_exp(meta: &Meta, func: &str, exps: Vec<syntax::Exp>) -> syntax::Exp {
+    syntax::Exp::Apply(Box::new(syntax::Apply {
+        meta: meta.clone(),
+        func: id_exp(meta, func),
+        exps,
+    }))
+}
+
+fn values_to_list(meta: &Meta, exp: &syntax::Exp) -> syntax::Exp {
+    apply_exp(meta, "~values->list", vec![exp.clone()])
+}
+
+fn bind_values(
+    meta: &Meta,
+    formals: &syntax::Arg,
+    list: syntax::Exp,
+    body: syntax::Body,
+) -> syntax::Exp {
+    syntax::Exp::Apply(Box::new(syntax::Apply {
+        meta: meta.clone(),
+        func: id_exp(meta, "apply"),
+        exps: vec![
+            syntax::Exp::Lambda(Box::new(syntax::Lambda {
+                meta: meta.clone(),
+                arg: formals.clone(),
+                body,
+            })),
+            list,
+        ],
+    }))
+}
+
+// Runs an expression that needs locals in a lambda of its own when it is not in one already.
+fn gen_in_lambda(builder: &mut Builder, meta: &Meta, exp: syntax::Exp, is_tail: bool) {
+    syntax::Apply {
+        meta: meta.clone(),
+        func: syntax::Exp::Lambda(Box::new(syntax::Lambda {
+            meta: meta.clone(),
+            arg: syntax::Arg::Args(syntax::Args {
+                meta: meta.clone(),
+                args: vec![],
+                varg: None,
+            }),
+            body: wrap_body(meta, exp),
+        })),
+        exps: vec![],
+    }
+    .gen(builder, is_tail);
+}
+
+fn wrap_body(meta: &Meta, exp: syntax::Exp) -> syntax::Body {
+    syntax::Body {
+        meta: meta.clone(),
+        scope: 0,
+        defs: vec![],
+        exps: syntax::NonEmptyVec::new(exp),
+    }
+}
+
+impl Gen for syntax::LetValues {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        if self.bindings.len() < 2 {
+            return syntax::LetAsterValues {
+                meta: self.meta.clone(),
+                bindings: self.bindings.clone(),
+                body: self.body.clone(),
+            }
+            .gen(builder, is_tail);
+        }
+
+        let tmp_ids = (0..self.bindings.len())
+            .map(|i| syntax::Id {
+                meta: self.meta.clone(),
+                id_ctx: 0,
+                v: format!(" values{}", i),
+            })
+            .collect::<Vec<_>>();
+
+        let mut body = self.body.clone();
+
+        for (b, tmp) in self.bindings.iter().zip(&tmp_ids).rev() {
+            body = wrap_body(
+                &b.meta,
+                bind_values(&b.meta, &b.formals, syntax::Exp::Id(tmp.clone()), body),
+            );
+        }
+
+        syntax::Let {
+            meta: self.meta.clone(),
+            id: None,
+            bindings: syntax::Bindings {
+                meta: self.meta.clone(),
+                bindings: self
+                    .bindings
+                    .iter()
+                    .zip(tmp_ids)
+                    .map(|(b, tmp)| syntax::Binding {
+                        meta: b.meta.clone(),
+                        id: tmp,
+                        exp: values_to_list(&b.meta, &b.exp),
+                    })
+                    .collect(),
+            },
+            body,
+        }
+        .gen(builder, is_tail);
+    }
+}
+
+impl Gen for syntax::LetAsterValues {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        let mut bindings = self.bindings.iter().rev();
+
+        let Some(last) = bindings.next() else {
+            return syntax::Let {
+                meta: self.meta.clone(),
+                id: None,
+                bindings: syntax::Bindings {
+                    meta: self.meta.clone(),
+                    bindings: vec![],
+                },
+                body: self.body.clone(),
+            }
+            .gen(builder, is_tail);
+        };
+
+        let mut exp = bind_values(
+            &last.meta,
+            &last.formals,
+            values_to_list(&last.meta, &last.exp),
+            self.body.clone(),
+        );
+
+        for b in bindings {
+            exp = bind_values(
+                &b.meta,
+                &b.formals,
+                values_to_list(&b.meta, &b.exp),
+                wrap_body(&b.meta, exp),
+            );
+        }
+
+        exp.gen(builder, is_tail);
+    }
+}
+
+impl Gen for syntax::Receive {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        bind_values(
+            &self.meta,
+            &self.formals,
+            values_to_list(&self.meta, &self.exp),
+            self.body.clone(),
+        )
+        .gen(builder, is_tail);
     }
 }
 
@@ -454,21 +1104,46 @@ impl Gen for syntax::If {
 
 impl Gen for syntax::Cond {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        let has_arrow = self.matches.iter().any(|m| matches!(m.then, syntax::ClauseBody::Arrow(_)));
+
+        if has_arrow && !builder.is_in_lambda() {
+            let exp = syntax::Exp::Cond(Box::new(self.clone()));
+            return gen_in_lambda(builder, &self.meta, exp, is_tail);
+        }
+
         let label_exit = builder.get_label();
 
         for m in &self.matches {
-            m.cond.gen(builder, false);
-            builder.push(Inst::Not);
-
             let label = builder.get_label();
 
-            builder.push_temp(TempInst::JumpIf(label));
+            match &m.then {
+                syntax::ClauseBody::Exps(exps) => {
+                    m.cond.gen(builder, false);
+                    builder.push(Inst::Not);
+                    builder.push_temp(TempInst::JumpIf(label));
 
-            for (i, exp) in m.then.get().iter().enumerate() {
-                exp.gen(builder, is_tail);
+                    gen_seq(builder, exps.get(), is_tail);
+                }
+                syntax::ClauseBody::Arrow(_) => {
+                    builder.enter_new_scope();
+
+                    let test = syntax::Id {
+                        meta: m.meta.clone(),
+                        id_ctx: 0,
+                        v: " test".into(),
+                    };
+
+                    m.cond.gen(builder, false);
+
+                    let id = def_inline(builder, &test);
+                    builder.push(Inst::Set(id.clone()));
+                    builder.push(Inst::Get(id));
+                    builder.push(Inst::Not);
+                    builder.push_temp(TempInst::JumpIf(label));
+
+                    gen_clause_body(builder, &m.then, &test, is_tail);
 
-                if i < m.then.len() - 1 {
-                    builder.push(Inst::Pop);
+                    builder.exit_cur_scope();
                 }
             }
 
@@ -477,18 +1152,229 @@ impl Gen for syntax::Cond {
         }
 
         if let Some(el) = &self.el {
-            for (i, exp) in el.get().iter().enumerate() {
-                exp.gen(builder, is_tail);
+            gen_seq(builder, el.get(), is_tail);
+        } else {
+            builder.push(Inst::Push(Obj::Null));
+        }
 
-                if i < el.len() - 1 {
-                    builder.push(Inst::Pop);
-                }
+        builder.push_label(label_exit);
+    }
+}
+
+fn gen_seq(builder: &mut Builder, exps: &[syntax::Exp], is_tail: bool) {
+    for (i, exp) in exps.iter().enumerate() {
+        let is_last = i == exps.len() - 1;
+
+        exp.gen(builder, is_tail && is_last);
+
+        if !is_last {
+            builder.push(Inst::Pop);
+        }
+    }
+}
+
+fn gen_clause_body(
+    builder: &mut Builder,
+    body: &syntax::ClauseBody,
+    arg: &syntax::Id,
+    is_tail: bool,
+) {
+    match body {
+        syntax::ClauseBody::Exps(exps) => gen_seq(builder, exps.get(), is_tail),
+        syntax::ClauseBody::Arrow(f) => syntax::Apply {
+            meta: arg.meta.clone(),
+            func: f.clone(),
+            exps: vec![syntax::Exp::Id(arg.clone())],
+        }
+        .gen(builder, is_tail),
+    }
+}
+
+impl Gen for syntax::Case {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        if !builder.is_in_lambda() {
+            let exp = syntax::Exp::Case(Box::new(self.clone()));
+            return gen_in_lambda(builder, &self.meta, exp, is_tail);
+        }
+
+        builder.enter_new_scope();
+
+        let key = syntax::Id {
+            meta: self.meta.clone(),
+            id_ctx: 0,
+            v: " key".into(),
+        };
+
+        self.key.gen(builder, false);
+
+        let id = def_inline(builder, &key);
+        builder.push(Inst::Set(id.clone()));
+
+        let labels = self.clauses.iter().map(|_| builder.get_label()).collect::<Vec<_>>();
+        let label_exit = builder.get_label();
+
+        for (clause, label) in self.clauses.iter().zip(&labels) {
+            // case compares with eqv?, and a string literal is a fresh object that is never eqv?
+            // to the key, so string data can never match
+            let data = clause
+                .data
+                .iter()
+                .filter(|datum| !matches!(datum, syntax::SExp::Const(syntax::Const::String(_))));
+
+            for datum in data {
+                builder.push(Inst::Get(id.clone()));
+                datum.gen(builder, false);
+                builder.push(Inst::IsEq);
+                builder.push_temp(TempInst::JumpIf(*label));
             }
+        }
+
+        if let Some(el) = &self.el {
+            gen_clause_body(builder, el, &key, is_tail);
         } else {
             builder.push(Inst::Push(Obj::Null));
         }
 
+        for (clause, label) in self.clauses.iter().zip(labels) {
+            builder.push_temp(TempInst::Jump(label_exit));
+            builder.push_label(label);
+
+            gen_clause_body(builder, &clause.then, &key, is_tail);
+        }
+
+        builder.push_label(label_exit);
+
+        builder.exit_cur_scope();
+    }
+}
+
+impl Gen for syntax::When {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        let label_else = builder.get_label();
+        let label_exit = builder.get_label();
+
+        self.cond.gen(builder, false);
+        builder.push(Inst::Not);
+        builder.push_temp(TempInst::JumpIf(label_else));
+
+        gen_seq(builder, self.body.get(), is_tail);
+
+        builder.push_temp(TempInst::Jump(label_exit));
+        builder.push_label(label_else);
+        builder.push(Inst::Push(Obj::Null));
+        builder.push_label(label_exit);
+    }
+}
+
+impl Gen for syntax::Unless {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        let label_else = builder.get_label();
+        let label_exit = builder.get_label();
+
+        self.cond.gen(builder, false);
+        builder.push_temp(TempInst::JumpIf(label_else));
+
+        gen_seq(builder, self.body.get(), is_tail);
+
+        builder.push_temp(TempInst::Jump(label_exit));
+        builder.push_label(label_else);
+        builder.push(Inst::Push(Obj::Null));
+        builder.push_label(label_exit);
+    }
+}
+
+impl Gen for syntax::CaseLambda {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        self.gen_named(builder, None);
+    }
+}
+
+impl syntax::CaseLambda {
+    fn gen_named(&self, builder: &mut Builder, name: Option<&syntax::Id>) {
+        let lambda_id = builder.get_label();
+        let label = builder.get_label();
+
+        builder.push_temp(TempInst::CreateClosure(lambda_id, name.map(|id| id.v.as_str().into())));
+        builder.push_temp(TempInst::Jump(label));
+
+        builder.push_label(lambda_id);
+        builder.enter_lambda();
+
+        let args = Id(" args".into());
+
+        builder.push(Inst::Def(args.clone()));
+        builder.push(Inst::CollectVArg(args.clone()));
+        builder.push(Inst::Set(args.clone()));
+
+        let labels = self.clauses.iter().map(|_| builder.get_label()).collect::<Vec<_>>();
+
+        for (clause, label) in self.clauses.iter().zip(&labels) {
+            let (n, has_rest) = match &clause.arg {
+                syntax::Arg::Args(t) => (t.args.len(), t.varg.is_some()),
+                syntax::Arg::VArg(_) => (0, true),
+            };
+
+            builder.push(Inst::Get(args.clone()));
+            builder.push(Inst::MatchArity(n, has_rest));
+            builder.push_temp(TempInst::JumpIf(*label));
+        }
+
+        builder.push(Inst::Get(args.clone()));
+        builder.push(Inst::Error("No matching clause in case-lambda for arguments".into()));
+
+        for (clause, label) in self.clauses.iter().zip(labels) {
+            builder.push_label(label);
+
+            builder.enter_new_scope();
+
+            let (ids, varg) = match &clause.arg {
+                syntax::Arg::Args(t) => (t.args.clone(), t.varg.clone()),
+                syntax::Arg::VArg(id) => (vec![], Some(id.clone())),
+            };
+
+            builder.push(Inst::Get(args.clone()));
+
+            for id in &ids {
+                builder.def(&id.v, id.id_ctx);
+                builder.push(Inst::Def(Id::new(id, builder)));
+                builder.push(Inst::Dup);
+                builder.push(Inst::Car);
+                builder.push(Inst::Set(Id::new(id, builder)));
+                builder.push(Inst::Cdr);
+            }
+
+            if let Some(id) = &varg {
+                builder.def(&id.v, id.id_ctx);
+                builder.push(Inst::Def(Id::new(id, builder)));
+                builder.push(Inst::Set(Id::new(id, builder)));
+            } else {
+                builder.push(Inst::Pop);
+            }
+
+            clause.body.gen(builder, true);
+
+            builder.push(Inst::Ret);
+
+            builder.exit_cur_scope();
+        }
+
+        builder.exit_lambda();
+        builder.push_label(label);
+    }
+}
+
+impl Gen for syntax::Assert {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        let label_exit = builder.get_label();
+
+        self.exp.gen(builder, false);
+        builder.push_temp(TempInst::JumpIf(label_exit));
+
+        self.s_exp.gen(builder, false);
+        builder.push(Inst::Error("Assertion failed".into()));
+
         builder.push_label(label_exit);
+        builder.push(Inst::Push(Obj::Null));
     }
 }
 
@@ -556,11 +1442,15 @@ impl Gen for syntax::Do {
         let lambda_id = builder.get_label();
         let label_lambda_exit = builder.get_label();
 
-        builder.push_temp(TempInst::PushReturnContext(label_exit));
-        builder.push_temp(TempInst::CreateClosure(lambda_id));
+        if !is_tail {
+            builder.push_temp(TempInst::PushReturnContext(label_exit));
+        }
+
+        builder.push_temp(TempInst::CreateClosure(lambda_id, None));
         builder.push_temp(TempInst::Jump(label_lambda_exit));
 
         builder.push_label(lambda_id);
+        builder.enter_lambda();
 
         for b in &self.bindings {
             syntax::DefVar {
@@ -579,8 +1469,10 @@ impl Gen for syntax::Do {
         let label_ret = builder.get_label();
         builder.push_temp(TempInst::JumpIf(label_ret));
 
-        self.body.gen(builder, false);
-        builder.push(Inst::Pop);
+        if let Some(body) = &self.body {
+            body.gen(builder, false);
+            builder.push(Inst::Pop);
+        }
 
         for b in &self.bindings {
             syntax::Set {
@@ -596,8 +1488,12 @@ impl Gen for syntax::Do {
 
         builder.push_label(label_ret);
 
+        if self.value.is_empty() {
+            builder.push(Inst::Push(Obj::Null));
+        }
+
         for (i, v) in self.value.iter().enumerate() {
-            v.gen(builder, false);
+            v.gen(builder, i == self.value.len() - 1);
 
             if i < self.value.len() - 1 {
                 builder.push(Inst::Pop);
@@ -605,9 +1501,10 @@ impl Gen for syntax::Do {
         }
 
         builder.push(Inst::Ret);
+        builder.exit_lambda();
         builder.push_label(label_lambda_exit);
 
-        builder.push(Inst::Call);
+        builder.push(if is_tail { Inst::OptCall } else { Inst::Call });
         builder.push_label(label_exit);
 
         builder.exit_cur_scope();
@@ -616,6 +1513,10 @@ impl Gen for syntax::Do {
 
 impl Gen for syntax::Body {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        if self.scope != 0 {
+            builder.enter_syntax_scope(self.scope);
+        }
+
         for def in &self.defs {
             def.gen(builder, false);
             builder.push(Inst::Pop);
@@ -661,9 +1562,20 @@ impl Gen for syntax::SExp {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
         match self {
             Self::Const(t) => t.gen(builder, false),
-            Self::Id(t) => builder.push(Inst::Push(Obj::Id(Id::new(t, builder)))),
+            Self::Id(t) => builder.push(Inst::Push(Obj::Id(Id(t.v.clone())))),
             Self::Pair(t) => t.gen(builder, false),
+            Self::Vector(t) => t.gen(builder, false),
+        }
+    }
+}
+
+impl Gen for syntax::Vector {
+    fn gen(&self, builder: &mut Builder, is_tail: bool) {
+        for exp in self.exps.iter().rev() {
+            exp.gen(builder, false);
         }
+
+        builder.push(Inst::Vector(self.exps.len()));
     }
 }
 
@@ -719,20 +1631,28 @@ impl Gen for syntax::Null {
 
 impl Gen for syntax::Id {
     fn gen(&self, builder: &mut Builder, is_tail: bool) {
-        builder.push(Inst::Get(Id::new(&self, builder)));
+        if builder.is_global(&self.v, self.id_ctx) {
+            builder.push(Inst::GetGlobal(Id::new(&self, builder)));
+        } else {
+            builder.push(Inst::Get(Id::new(&self, builder)));
+        }
     }
 }
 
 mod builder {
     use std::collections::HashMap;
+    use std::sync::Arc;
     use super::*;
 
     pub struct Builder {
         label: u32,
         insts: Vec<TempInst>,
 
-        id_table: HashMap<String, Vec<u32>>,
+        id_table: HashMap<String, Vec<(u32, Option<String>, usize)>>,
         id_def_history: Vec<Vec<String>>,
+        id_ctx_parents: HashMap<u32, (u32, u32)>,
+        scope_depths: HashMap<u32, usize>,
+        lambda_depth: usize,
     }
 
     #[derive(Debug)]
@@ -740,7 +1660,7 @@ mod builder {
         Raw(Inst),
         Jump(u32),
         JumpIf(u32),
-        CreateClosure(u32),
+        CreateClosure(u32, Option<Arc<str>>),
         PushReturnContext(u32),
         Label(u32),
     }
@@ -753,6 +1673,9 @@ mod builder {
 
                 id_table: Default::default(),
                 id_def_history: vec![vec![]],
+                id_ctx_parents: Default::default(),
+                scope_depths: Default::default(),
+                lambda_depth: 0,
             }
         }
 
@@ -798,8 +1721,8 @@ mod builder {
                     TempInst::Raw(i) => insts.push(i.clone()),
                     TempInst::Jump(i) => insts.push(Inst::Jump(*label_to_pc.get(i).unwrap())),
                     TempInst::JumpIf(i) => insts.push(Inst::JumpIf(*label_to_pc.get(i).unwrap())),
-                    TempInst::CreateClosure(i) => {
-                        insts.push(Inst::CreateClosure(*label_to_pc.get(i).unwrap()))
+                    TempInst::CreateClosure(i, name) => {
+                        insts.push(Inst::CreateClosure(*label_to_pc.get(i).unwrap(), name.clone()))
                     }
                     TempInst::PushReturnContext(i) => {
                         insts.push(Inst::PushReturnContext(*label_to_pc.get(i).unwrap()))
@@ -815,6 +1738,18 @@ mod builder {
             self.id_def_history.push(vec![]);
         }
 
+        pub fn enter_lambda(&mut self) {
+            self.lambda_depth += 1;
+        }
+
+        pub fn exit_lambda(&mut self) {
+            self.lambda_depth -= 1;
+        }
+
+        pub fn is_in_lambda(&self) -> bool {
+            self.lambda_depth > 0
+        }
+
         pub fn exit_cur_scope(&mut self) {
             let history = self.id_def_history.pop().unwrap();
 
@@ -826,25 +1761,98 @@ mod builder {
         }
 
         pub fn def(&mut self, id: &String, id_ctx: u32) {
+            let depth = self.id_def_history.len();
+
+            // A local that shadows another local with the same runtime name gets its own name,
+            // so that macro-introduced references can still reach the outer one.
+            let is_shadowing = self.id_table.get(id).is_some_and(|table| {
+                table.iter().any(|(ctx, alias, d)| {
+                    *ctx == id_ctx && alias.is_none() && 1 < *d && *d < depth
+                })
+            });
+
+            if is_shadowing {
+                let label = self.get_label();
+                let alias = format!("{} {}", Id::binding(id, id_ctx).0, label);
+
+                self.def_entry(id, id_ctx, Some(alias));
+            } else {
+                self.def_entry(id, id_ctx, None);
+            }
+        }
+
+        pub fn def_alias(&mut self, id: &String, id_ctx: u32, alias: String) {
+            self.def_entry(id, id_ctx, Some(alias));
+        }
+
+        fn def_entry(&mut self, id: &String, id_ctx: u32, alias: Option<String>) {
+            let depth = self.id_def_history.len();
+
             self.id_def_history.last_mut().unwrap().push(id.clone());
 
             if let Some(table) = self.id_table.get_mut(id) {
-                table.push(id_ctx);
+                table.push((id_ctx, alias, depth));
             } else {
-                self.id_table.insert(id.clone(), vec![id_ctx]);
+                self.id_table.insert(id.clone(), vec![(id_ctx, alias, depth)]);
             }
         }
 
-        pub fn get_true_id_ctx(&self, id: &String, id_ctx: u32) -> u32 {
-            let Some(table) = self.id_table.get(id) else {
-                return 0;
-            };
+        pub fn add_id_ctx(&mut self, id_ctx: u32, parent: u32, scope: u32) {
+            self.id_ctx_parents.insert(id_ctx, (parent, scope));
+        }
 
-            if table.contains(&id_ctx) {
-                id_ctx
-            } else {
-                *table.last().unwrap_or(&0)
+        pub fn enter_syntax_scope(&mut self, scope: u32) {
+            self.scope_depths.insert(scope, self.id_def_history.len());
+        }
+
+        // An identifier introduced by a macro first looks for bindings introduced by the same
+        // expansion, then falls back to the bindings visible where the macro was defined.
+        pub fn resolve(&self, id: &String, id_ctx: u32) -> Option<&(u32, Option<String>, usize)> {
+            let table = self.id_table.get(id)?;
+
+            let mut id_ctx = id_ctx;
+            let mut depth = usize::MAX;
+
+            loop {
+                if let Some(entry) =
+                    table.iter().rev().find(|(ctx, _, d)| *ctx == id_ctx && *d <= depth)
+                {
+                    return Some(entry);
+                }
+
+                if id_ctx == 0 {
+                    return None;
+                }
+
+                let (parent, scope) = *self.id_ctx_parents.get(&id_ctx)?;
+
+                if parent == id_ctx {
+                    return None;
+                }
+
+                id_ctx = parent;
+                depth = depth.min(self.scope_depths.get(&scope).copied().unwrap_or(1));
             }
         }
+
+        // Free names in a library body are the library's own globals, which stay undefined
+        // unless it defines them. Library contexts are their own parent.
+        pub fn root_ctx(&self, id_ctx: u32) -> u32 {
+            let mut id_ctx = id_ctx;
+
+            while let Some(&(parent, _)) = self.id_ctx_parents.get(&id_ctx) {
+                if parent == id_ctx {
+                    return id_ctx;
+                }
+
+                id_ctx = parent;
+            }
+
+            0
+        }
+
+        pub fn is_global(&self, id: &String, id_ctx: u32) -> bool {
+            id_ctx != 0 && self.resolve(id, id_ctx).is_none_or(|(_, _, depth)| *depth == 1)
+        }
     }
 }
@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use anyhow::{bail, ensure, Context as _, Result};
+use crate::lexer::{Token, TokenKind};
+use crate::obj::{Cons, Id, Obj};
+use crate::syntax::{SyntaxRules, TokenTree};
+
+impl TokenTree {
+    pub fn parse(tokens: &[Token]) -> Result<Self> {
+        let mut i = 0;
+
+        let tree = Self::parse_at(tokens, &mut i)?;
+
+        ensure!(i == tokens.len(), "Invalid syntax");
+
+        Ok(tree)
+    }
+
+    pub fn parse_all(tokens: &[Token]) -> Result<Vec<Self>> {
+        let mut i = 0;
+
+        let mut res = vec![];
+
+        while i < tokens.len() {
+            res.push(Self::parse_at(tokens, &mut i)?);
+        }
+
+        Ok(res)
+    }
+
+    fn parse_at(tokens: &[Token], i: &mut usize) -> Result<Self> {
+        let t = tokens.get(*i).context("Unexpected end of syntax")?.clone();
+        *i += 1;
+
+        if let Some(kind) = t.kind.abbreviation() {
+            let name = Token {
+                meta: t.meta.clone(),
+                kind,
+            };
+
+            return Ok(Self::List(vec![Self::Token(name), Self::parse_at(tokens, i)?], None));
+        }
+
+        match t.kind {
+            TokenKind::ParenOpen | TokenKind::VectorOpen => {
+                let mut items = vec![];
+                let mut tail = None;
+
+                loop {
+                    let next = tokens.get(*i).context("')' expected")?;
+
+                    match next.kind {
+                        TokenKind::ParenClose => {
+                            *i += 1;
+                            break;
+                        }
+                        TokenKind::Period
+                            if t.kind == TokenKind::ParenOpen && !items.is_empty() =>
+                        {
+                            *i += 1;
+                            tail = Some(Self::parse_at(tokens, i)?);
+
+                            ensure!(
+                                tokens.get(*i).map(|t| &t.kind) == Some(&TokenKind::ParenClose),
+                                "')' expected"
+                            );
+                            *i += 1;
+                            break;
+                        }
+                        _ => items.push(Self::parse_at(tokens, i)?),
+                    }
+                }
+
+                if t.kind == TokenKind::VectorOpen {
+                    Ok(Self::Vector(items))
+                } else {
+                    Ok(Self::list(items, tail))
+                }
+            }
+            TokenKind::ParenClose | TokenKind::Period => bail!("Invalid syntax"),
+            _ => Ok(Self::Token(t)),
+        }
+    }
+
+    fn list(mut items: Vec<TokenTree>, tail: Option<TokenTree>) -> Self {
+        match tail {
+            Some(Self::List(rest, tail)) => {
+                items.extend(rest);
+                Self::List(items, tail)
+            }
+            Some(tail) if items.is_empty() => tail,
+            tail => Self::List(items, tail.map(Box::new)),
+        }
+    }
+
+    pub fn flatten(self, tokens: &mut Vec<Token>) {
+        let token = |kind| Token {
+            meta: Default::default(),
+            kind,
+        };
+
+        match self {
+            Self::Token(t) => tokens.push(t),
+            Self::List(items, tail) => {
+                tokens.push(token(TokenKind::ParenOpen));
+
+                for item in items {
+                    item.flatten(tokens);
+                }
+
+                if let Some(tail) = tail {
+                    tokens.push(token(TokenKind::Period));
+                    tail.flatten(tokens);
+                }
+
+                tokens.push(token(TokenKind::ParenClose));
+            }
+            Self::Vector(items) => {
+                tokens.push(token(TokenKind::VectorOpen));
+
+                for item in items {
+                    item.flatten(tokens);
+                }
+
+                tokens.push(token(TokenKind::ParenClose));
+            }
+        }
+    }
+
+    pub fn to_obj(&self) -> Result<Obj> {
+        match self {
+            Self::Token(t) => Ok(match &t.kind {
+                TokenKind::Num(v) => Obj::Number(*v),
+                TokenKind::Bool(v) => Obj::Bool(*v),
+                TokenKind::Str(v) => Obj::String(v.clone()),
+                kind => {
+                    let id = Id(kind.identifier().context("Invalid syntax")?.to_string());
+
+                    match t.meta.id_ctx {
+                        0 => Obj::Id(id),
+                        id_ctx => Obj::Identifier(id, id_ctx),
+                    }
+                }
+            }),
+            Self::List(items, tail) => {
+                let mut res = match tail {
+                    Some(tail) => tail.to_obj()?,
+                    None => Obj::Null,
+                };
+
+                for item in items.iter().rev() {
+                    res = Obj::Pair(Arc::new(RwLock::new(Cons(item.to_obj()?, res))));
+                }
+
+                Ok(res)
+            }
+            Self::Vector(items) => {
+                let items = items.iter().map(Self::to_obj).collect::<Result<_>>()?;
+
+                Ok(Obj::Vector(Arc::new(RwLock::new(items))))
+            }
+        }
+    }
+
+    pub fn head(&self) -> Option<String> {
+        match self {
+            Self::List(items, _) => match &items.first()? {
+                Self::Token(Token {
+                    kind: TokenKind::Id(id),
+                    ..
+                }) => Some(id.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn identifier(&self) -> Option<&str> {
+        match self {
+            Self::Token(t) => t.kind.identifier(),
+            _ => None,
+        }
+    }
+
+    fn identifiers<'a>(&'a self, res: &mut Vec<&'a str>) {
+        match self {
+            Self::Token(_) => res.extend(self.identifier()),
+            Self::List(items, tail) => {
+                for item in items.iter().chain(tail.as_deref()) {
+                    item.identifiers(res);
+                }
+            }
+            Self::Vector(items) => {
+                for item in items {
+                    item.identifiers(res);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Binding {
+    One(TokenTree),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+impl SyntaxRules {
+    pub fn check_pattern(&self, pattern: &TokenTree) -> Result<()> {
+        let items = match pattern {
+            TokenTree::List(items, _) | TokenTree::Vector(items) => items,
+            TokenTree::Token(_) => return Ok(()),
+        };
+
+        let ellipses = items.iter().filter(|i| self.is_ellipsis(i, &self.ellipsis)).count();
+
+        ensure!(ellipses <= 1, "Multiple ellipses in a pattern");
+        ensure!(
+            items.first().is_none_or(|i| !self.is_ellipsis(i, &self.ellipsis)),
+            "Ellipsis without a preceding pattern"
+        );
+
+        for item in items {
+            self.check_pattern(item)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn expand(&self, form: &TokenTree, mark: &mut dyn FnMut(u32) -> u32) -> Result<TokenTree> {
+        let TokenTree::List(form_items, form_tail) = form else {
+            bail!("Invalid syntax")
+        };
+
+        for rule in &self.syntax_rules {
+            let TokenTree::List(items, tail) = &rule.pattern else {
+                continue;
+            };
+
+            let Some((_, items)) = items.split_first() else {
+                continue;
+            };
+
+            let mut binds = Bindings::new();
+
+            if !form_items.is_empty()
+                && self.match_seq(
+                    items,
+                    tail.as_deref(),
+                    &form_items[1..],
+                    form_tail.as_deref(),
+                    &mut binds,
+                )
+            {
+                return self.expand_template(&rule.template, &binds, &self.ellipsis, mark);
+            }
+        }
+
+        bail!("No matching syntax rule")
+    }
+
+    fn is_literal(&self, name: &str) -> bool {
+        self.literals.iter().any(|l| l == name)
+    }
+
+    fn is_ellipsis(&self, tree: &TokenTree, ellipsis: &str) -> bool {
+        !ellipsis.is_empty() && tree.identifier() == Some(ellipsis) && !self.is_literal(ellipsis)
+    }
+
+    fn match_pattern(&self, pattern: &TokenTree, form: &TokenTree, binds: &mut Bindings) -> bool {
+        match (pattern, form) {
+            (TokenTree::Token(p), _) => match p.kind.identifier() {
+                Some(name) if self.is_literal(name) => form.identifier() == Some(name),
+                Some("_") => true,
+                Some(name) => {
+                    binds.insert(name.to_string(), Binding::One(form.clone()));
+                    true
+                }
+                None => matches!(form, TokenTree::Token(f) if f.kind == p.kind),
+            },
+            (TokenTree::List(items, tail), TokenTree::List(form_items, form_tail)) => {
+                self.match_seq(items, tail.as_deref(), form_items, form_tail.as_deref(), binds)
+            }
+            (TokenTree::Vector(items), TokenTree::Vector(form_items)) => {
+                self.match_seq(items, None, form_items, None, binds)
+            }
+            _ => false,
+        }
+    }
+
+    fn match_seq(
+        &self,
+        items: &[TokenTree],
+        tail: Option<&TokenTree>,
+        form_items: &[TokenTree],
+        form_tail: Option<&TokenTree>,
+        binds: &mut Bindings,
+    ) -> bool {
+        let Some(pos) = items.iter().position(|i| self.is_ellipsis(i, &self.ellipsis)) else {
+            if form_items.len() < items.len()
+                || (tail.is_none() && (form_items.len() != items.len() || form_tail.is_some()))
+            {
+                return false;
+            }
+
+            if !self.match_all(items, &form_items[..items.len()], binds) {
+                return false;
+            }
+
+            return match tail {
+                Some(tail) => {
+                    let rest =
+                        TokenTree::list(form_items[items.len()..].to_vec(), form_tail.cloned());
+
+                    self.match_pattern(tail, &rest, binds)
+                }
+                None => true,
+            };
+        };
+
+        let before = &items[..pos - 1];
+        let rep = &items[pos - 1];
+        let after = &items[pos + 1..];
+
+        if form_items.len() < before.len() + after.len() || (tail.is_none() && form_tail.is_some())
+        {
+            return false;
+        }
+
+        let n = form_items.len() - before.len() - after.len();
+
+        if !self.match_all(before, &form_items[..before.len()], binds) {
+            return false;
+        }
+
+        let mut reps = vec![];
+
+        for form in &form_items[before.len()..before.len() + n] {
+            let mut rep_binds = Bindings::new();
+
+            if !self.match_pattern(rep, form, &mut rep_binds) {
+                return false;
+            }
+
+            reps.push(rep_binds);
+        }
+
+        let mut vars = vec![];
+        rep.identifiers(&mut vars);
+        vars.sort();
+        vars.dedup();
+
+        for var in vars {
+            if self.is_literal(var) || var == "_" || var == self.ellipsis {
+                continue;
+            }
+
+            let many = reps.iter_mut().filter_map(|b| b.remove(var)).collect();
+            binds.insert(var.to_string(), Binding::Many(many));
+        }
+
+        if !self.match_all(after, &form_items[before.len() + n..], binds) {
+            return false;
+        }
+
+        match tail {
+            Some(tail) => {
+                let rest = form_tail.cloned().unwrap_or(TokenTree::List(vec![], None));
+                self.match_pattern(tail, &rest, binds)
+            }
+            None => true,
+        }
+    }
+
+    fn match_all(&self, items: &[TokenTree], forms: &[TokenTree], binds: &mut Bindings) -> bool {
+        items.iter().zip(forms).all(|(item, form)| self.match_pattern(item, form, binds))
+    }
+
+    fn expand_template(
+        &self,
+        template: &TokenTree,
+        binds: &Bindings,
+        ellipsis: &str,
+        mark: &mut dyn FnMut(u32) -> u32,
+    ) -> Result<TokenTree> {
+        match template {
+            TokenTree::Token(t) => {
+                if let Some(name) = t.kind.identifier() {
+                    match binds.get(name) {
+                        Some(Binding::One(tree)) => return Ok(tree.clone()),
+                        Some(Binding::Many(_)) => {
+                            bail!("Pattern variable {} is used without an ellipsis", name)
+                        }
+                        None => (),
+                    }
+                }
+
+                let mut t = t.clone();
+                t.meta.id_ctx = mark(t.meta.id_ctx);
+
+                Ok(TokenTree::Token(t))
+            }
+            TokenTree::List(items, tail) => {
+                if items.len() == 2 && tail.is_none() && self.is_ellipsis(&items[0], ellipsis) {
+                    return self.expand_template(&items[1], binds, "", mark);
+                }
+
+                let items = self.expand_seq(items, binds, ellipsis, mark)?;
+                let tail = match tail {
+                    Some(tail) => Some(self.expand_template(tail, binds, ellipsis, mark)?),
+                    None => None,
+                };
+
+                Ok(TokenTree::list(items, tail))
+            }
+            TokenTree::Vector(items) => {
+                Ok(TokenTree::Vector(self.expand_seq(items, binds, ellipsis, mark)?))
+            }
+        }
+    }
+
+    fn expand_seq(
+        &self,
+        items: &[TokenTree],
+        binds: &Bindings,
+        ellipsis: &str,
+        mark: &mut dyn FnMut(u32) -> u32,
+    ) -> Result<Vec<TokenTree>> {
+        let mut res = vec![];
+
+        let mut i = 0;
+
+        while i < items.len() {
+            let mut depth = 0;
+
+            while items.get(i + 1 + depth).is_some_and(|t| self.is_ellipsis(t, ellipsis)) {
+                depth += 1;
+            }
+
+            if depth == 0 {
+                res.push(self.expand_template(&items[i], binds, ellipsis, mark)?);
+            } else {
+                self.expand_ellipsis(&items[i], binds, depth, ellipsis, mark, &mut res)?;
+            }
+
+            i += 1 + depth;
+        }
+
+        Ok(res)
+    }
+
+    fn expand_ellipsis(
+        &self,
+        template: &TokenTree,
+        binds: &Bindings,
+        depth: usize,
+        ellipsis: &str,
+        mark: &mut dyn FnMut(u32) -> u32,
+        res: &mut Vec<TokenTree>,
+    ) -> Result<()> {
+        let mut vars = vec![];
+        template.identifiers(&mut vars);
+
+        let vars: Vec<(&str, &Vec<Binding>)> = vars
+            .into_iter()
+            .filter_map(|v| match binds.get(v) {
+                Some(Binding::Many(many)) => Some((v, many)),
+                _ => None,
+            })
+            .collect();
+
+        ensure!(!vars.is_empty(), "No pattern variable to repeat under an ellipsis");
+
+        let len = vars[0].1.len();
+
+        ensure!(
+            vars.iter().all(|(_, many)| many.len() == len),
+            "Pattern variables under an ellipsis have different lengths"
+        );
+
+        for i in 0..len {
+            let mut binds = binds.clone();
+
+            for (var, many) in &vars {
+                binds.insert(var.to_string(), many[i].clone());
+            }
+
+            if depth == 1 {
+                res.push(self.expand_template(template, &binds, ellipsis, mark)?);
+            } else {
+                self.expand_ellipsis(template, &binds, depth - 1, ellipsis, mark, res)?;
+            }
+        }
+
+        Ok(())
+    }
+}
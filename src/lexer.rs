@@ -27,23 +27,50 @@ impl Meta {
 pub enum TokenKind {
     ParenOpen,
     ParenClose,
+    VectorOpen,
     Period,
     Ellipsis,
     SingleQuote,
+    Backquote,
+    Comma,
+    CommaAt,
 
     DefineSyntax,
+    LetSyntax,
+    LetRecSyntax,
     SyntaxRules,
+    ErMacroTransformer,
+    DefineLibrary,
+    Import,
+    Export,
+    Include,
     Load,
+    Require,
     Define,
     Lambda,
     Quote,
+    QuasiQuote,
+    Unquote,
+    UnquoteSplicing,
     Set,
     Let,
     LetAster,
     LetRec,
+    LetRecAster,
+    LetValues,
+    LetAsterValues,
+    DefineValues,
+    DefineRecordType,
+    Receive,
     If,
     Cond,
+    Case,
     Else,
+    Arrow,
+    When,
+    Unless,
+    CaseLambda,
+    Assert,
     And,
     Or,
     Begin,
@@ -79,28 +106,17 @@ pub fn get_tokens(src: String, is_strict: bool) -> Result<Vec<Token>> {
             "\n" => None,
             "(" => Some(TokenKind::ParenOpen),
             ")" => Some(TokenKind::ParenClose),
+            "#(" => Some(TokenKind::VectorOpen),
             "." => Some(TokenKind::Period),
             "..." => Some(TokenKind::Ellipsis),
             "'" => Some(TokenKind::SingleQuote),
-            "define-syntax" => Some(TokenKind::DefineSyntax),
-            "syntax-rules" => Some(TokenKind::SyntaxRules),
-            "load" => Some(TokenKind::Load),
-            "define" => Some(TokenKind::Define),
-            "lambda" => Some(TokenKind::Lambda),
-            "quote" => Some(TokenKind::Quote),
-            "set!" => Some(TokenKind::Set),
-            "let" => Some(TokenKind::Let),
-            "let*" => Some(TokenKind::LetAster),
-            "letrec" => Some(TokenKind::LetRec),
-            "if" => Some(TokenKind::If),
-            "cond" => Some(TokenKind::Cond),
-            "else" => Some(TokenKind::Else),
-            "and" => Some(TokenKind::And),
-            "or" => Some(TokenKind::Or),
-            "begin" => Some(TokenKind::Begin),
-            "do" => Some(TokenKind::Do),
+            "`" => Some(TokenKind::Backquote),
+            "," => Some(TokenKind::Comma),
+            ",@" => Some(TokenKind::CommaAt),
             _ => {
-                if symbol.starts_with('"') && symbol.ends_with('"') {
+                if let Some(kind) = keyword(&symbol) {
+                    Some(kind)
+                } else if symbol.starts_with('"') && symbol.ends_with('"') {
                     Some(TokenKind::Str(
                         symbol.chars().skip(1).take(symbol.chars().count() - 2).collect(),
                     ))
@@ -129,6 +145,177 @@ pub fn get_tokens(src: String, is_strict: bool) -> Result<Vec<Token>> {
     Ok(tokens)
 }
 
+pub fn get_tokens_from_obj(obj: &Obj) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+
+    push_obj_tokens(obj, &mut tokens)?;
+
+    Ok(tokens)
+}
+
+fn push_obj_tokens(obj: &Obj, tokens: &mut Vec<Token>) -> Result<()> {
+    let token = |kind| Token {
+        meta: Default::default(),
+        kind,
+    };
+
+    match obj {
+        Obj::Bool(v) => tokens.push(token(TokenKind::Bool(*v))),
+        Obj::Number(v) => tokens.push(token(TokenKind::Num(*v))),
+        Obj::String(v) => tokens.push(token(TokenKind::Str(v.clone()))),
+        Obj::Id(v) => tokens.push(token(match v.0.as_str() {
+            "..." => TokenKind::Ellipsis,
+            _ => keyword(&v.0).unwrap_or(TokenKind::Id(v.0.clone())),
+        })),
+        Obj::Identifier(v, id_ctx) => tokens.push(Token {
+            meta: Meta {
+                id_ctx: *id_ctx,
+                ..Default::default()
+            },
+            kind: match v.0.as_str() {
+                "..." => TokenKind::Ellipsis,
+                _ => keyword(&v.0).unwrap_or(TokenKind::Id(v.0.clone())),
+            },
+        }),
+        Obj::Null => {
+            tokens.push(token(TokenKind::ParenOpen));
+            tokens.push(token(TokenKind::ParenClose));
+        }
+        Obj::Pair(_) => {
+            tokens.push(token(TokenKind::ParenOpen));
+
+            let mut cur = obj.clone();
+
+            while let Obj::Pair(p) = cur {
+                let (car, cdr) = p.read().unwrap().cloned();
+                push_obj_tokens(&car, tokens)?;
+                cur = cdr;
+            }
+
+            if cur != Obj::Null {
+                tokens.push(token(TokenKind::Period));
+                push_obj_tokens(&cur, tokens)?;
+            }
+
+            tokens.push(token(TokenKind::ParenClose));
+        }
+        Obj::Vector(v) => {
+            tokens.push(token(TokenKind::VectorOpen));
+
+            for e in v.read().unwrap().iter() {
+                push_obj_tokens(e, tokens)?;
+            }
+
+            tokens.push(token(TokenKind::ParenClose));
+        }
+        _ => bail!("Cannot evaluate {}", obj),
+    }
+
+    Ok(())
+}
+
+const KEYWORDS: &[(&str, TokenKind)] = &[
+    ("define-syntax", TokenKind::DefineSyntax),
+    ("let-syntax", TokenKind::LetSyntax),
+    ("letrec-syntax", TokenKind::LetRecSyntax),
+    ("syntax-rules", TokenKind::SyntaxRules),
+    ("er-macro-transformer", TokenKind::ErMacroTransformer),
+    ("define-library", TokenKind::DefineLibrary),
+    ("import", TokenKind::Import),
+    ("export", TokenKind::Export),
+    ("include", TokenKind::Include),
+    ("load", TokenKind::Load),
+    ("require", TokenKind::Require),
+    ("define", TokenKind::Define),
+    ("lambda", TokenKind::Lambda),
+    ("quote", TokenKind::Quote),
+    ("quasiquote", TokenKind::QuasiQuote),
+    ("unquote", TokenKind::Unquote),
+    ("unquote-splicing", TokenKind::UnquoteSplicing),
+    ("set!", TokenKind::Set),
+    ("let", TokenKind::Let),
+    ("let*", TokenKind::LetAster),
+    ("letrec", TokenKind::LetRec),
+    ("letrec*", TokenKind::LetRecAster),
+    ("let-values", TokenKind::LetValues),
+    ("let*-values", TokenKind::LetAsterValues),
+    ("define-values", TokenKind::DefineValues),
+    ("define-record-type", TokenKind::DefineRecordType),
+    ("receive", TokenKind::Receive),
+    ("if", TokenKind::If),
+    ("cond", TokenKind::Cond),
+    ("case", TokenKind::Case),
+    ("else", TokenKind::Else),
+    ("=>", TokenKind::Arrow),
+    ("when", TokenKind::When),
+    ("unless", TokenKind::Unless),
+    ("case-lambda", TokenKind::CaseLambda),
+    ("assert", TokenKind::Assert),
+    ("and", TokenKind::And),
+    ("or", TokenKind::Or),
+    ("begin", TokenKind::Begin),
+    ("do", TokenKind::Do),
+];
+
+fn keyword(symbol: &str) -> Option<TokenKind> {
+    KEYWORDS.iter().find(|(s, _)| *s == symbol).map(|(_, kind)| kind.clone())
+}
+
+pub fn keyword_name(kind: &TokenKind) -> Option<&'static str> {
+    KEYWORDS.iter().find(|(_, k)| k == kind).map(|(s, _)| *s)
+}
+
+impl TokenKind {
+    pub fn abbreviation(&self) -> Option<TokenKind> {
+        match self {
+            TokenKind::SingleQuote => Some(TokenKind::Quote),
+            TokenKind::Backquote => Some(TokenKind::QuasiQuote),
+            TokenKind::Comma => Some(TokenKind::Unquote),
+            TokenKind::CommaAt => Some(TokenKind::UnquoteSplicing),
+            _ => None,
+        }
+    }
+
+    // Keywords added on top of the original syntax are only reserved where they start a form,
+    // so that programs can keep using them as names.
+    pub fn is_contextual(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::LetSyntax
+                | TokenKind::LetRecSyntax
+                | TokenKind::ErMacroTransformer
+                | TokenKind::DefineLibrary
+                | TokenKind::Import
+                | TokenKind::Export
+                | TokenKind::Include
+                | TokenKind::Require
+                | TokenKind::QuasiQuote
+                | TokenKind::Unquote
+                | TokenKind::UnquoteSplicing
+                | TokenKind::LetRecAster
+                | TokenKind::LetValues
+                | TokenKind::LetAsterValues
+                | TokenKind::DefineValues
+                | TokenKind::DefineRecordType
+                | TokenKind::Receive
+                | TokenKind::Case
+                | TokenKind::Arrow
+                | TokenKind::When
+                | TokenKind::Unless
+                | TokenKind::CaseLambda
+                | TokenKind::Assert
+        )
+    }
+
+    pub fn identifier(&self) -> Option<&str> {
+        match self {
+            TokenKind::Id(id) => Some(id),
+            TokenKind::Ellipsis => Some("..."),
+            kind => keyword_name(kind),
+        }
+    }
+}
+
 fn read_next_symbol(reader: &mut reader::Reader) -> Option<String> {
     while reader.peek() == Some(';') {
         while reader.has_data() && reader.read() != Some('\n') {}
@@ -154,6 +341,16 @@ fn read_next_symbol(reader: &mut reader::Reader) -> Option<String> {
         }
     }
 
+    if symbol == "," && reader.peek() == Some('@') {
+        reader.read();
+        symbol.push('@');
+    }
+
+    if symbol == "#" && reader.peek() == Some('(') {
+        reader.read();
+        symbol.push('(');
+    }
+
     Some(symbol)
 }
 
@@ -171,6 +368,8 @@ fn read_next_string(reader: &mut reader::Reader) -> Option<String> {
             let c = reader.read()?;
             str.push(match c {
                 'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
                 _ => c,
             });
             continue;
@@ -211,7 +410,7 @@ mod reader {
         }
 
         pub fn is_symbol_ended(&self) -> bool {
-            let separators = vec![' ', '(', ')', '\n', ';', '\''];
+            let separators = vec![' ', '(', ')', '\n', ';', '\'', '`', ','];
 
             self.src.get(self.idx - 1).map(|c| separators.contains(c)).unwrap_or(false)
                 || self.src.get(self.idx).map(|c| separators.contains(c)).unwrap_or(false)
@@ -1,31 +1,186 @@
 use std::env;
 use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 use crate::vm::Inst;
 
 mod lexer;
 mod parser;
+mod expander;
 mod syntax;
 mod vm;
 mod codegen;
 mod obj;
 mod repl;
+mod heap;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let search_paths = search_paths(&mut args);
+    let limits = limits(&mut args);
 
-    if args.len() < 2 {
-        repl::run();
+    if args.is_empty() {
+        repl::run(&search_paths, limits);
     }
 
-    let src = &args[1];
+    if args[0] == "--expand" {
+        expand(&args[1..], search_paths);
+        return;
+    }
 
-    let src = read_to_string(src).expect("Failed to open file");
+    if args[0] == "--parallel" {
+        parallel(&args[1..], search_paths, limits);
+        return;
+    }
 
     let mut vm = vm::VM::new();
 
-    let _ = vm.exec(prelude(), None, None, false);
-    let _ = vm.exec(src, None, None, true);
+    for dir in search_paths {
+        vm.add_search_path(dir);
+    }
+
+    let _ = vm.load_prelude(prelude());
+
+    match vm.exec_file(Path::new(&args[0]), limits) {
+        Ok(ret) => vm.release(&ret),
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn search_paths(args: &mut Vec<String>) -> Vec<PathBuf> {
+    let mut paths = vec![];
+
+    while let Some(i) = args.iter().position(|a| a == "-L") {
+        args.remove(i);
+
+        if i >= args.len() {
+            eprintln!("Directory expected after -L");
+            std::process::exit(1);
+        }
+
+        paths.push(PathBuf::from(args.remove(i)));
+    }
+
+    if let Some(var) = env::var_os("MINI_SCHEME_PATH") {
+        paths.extend(env::split_paths(&var));
+    }
+
+    paths
+}
+
+fn limits(args: &mut Vec<String>) -> vm::Limits {
+    vm::Limits {
+        fuel: flag_value(args, "--fuel"),
+        timeout: flag_value(args, "--timeout").map(Duration::from_secs_f64),
+        max_heap: flag_value(args, "--max-heap"),
+    }
+}
+
+fn flag_value<T: FromStr>(args: &mut Vec<String>, flag: &str) -> Option<T> {
+    let i = args.iter().position(|a| a == flag)?;
+    args.remove(i);
+
+    if i >= args.len() {
+        eprintln!("Value expected after {}", flag);
+        std::process::exit(1);
+    }
+
+    let Ok(value) = args.remove(i).parse() else {
+        eprintln!("Invalid value for {}", flag);
+        std::process::exit(1);
+    };
+
+    Some(value)
+}
+
+fn expand(args: &[String], search_paths: Vec<PathBuf>) {
+    let show_renames = args.iter().any(|a| a == "--show-renames");
+    let Some(path) = args.iter().find(|a| !a.starts_with("--")) else {
+        eprintln!("File expected after --expand");
+        std::process::exit(1);
+    };
+
+    let src = match read_to_string(path) {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut parser = parser::Parser::new();
+
+    for dir in search_paths {
+        parser.add_search_path(dir);
+    }
+
+    if let Err(e) = parser.parse(prelude(), false) {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+
+    match parser.expand(src, show_renames) {
+        Ok(forms) => {
+            for form in forms {
+                println!("{}", form.pretty(80));
+            }
+        }
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Runs each file in a VM of its own on a worker thread and prints the value of each file.
+fn parallel(files: &[String], search_paths: Vec<PathBuf>, limits: vm::Limits) {
+    let workers: Vec<_> = files
+        .iter()
+        .map(|file| {
+            let mut vm = vm::VM::new();
+
+            for dir in &search_paths {
+                vm.add_search_path(dir.clone());
+            }
+
+            let path = PathBuf::from(file);
+
+            std::thread::spawn(move || {
+                vm.load_prelude(prelude())?;
+
+                let ret = vm.exec_file(&path, limits)?;
+                let copy = ret.deep_copy();
+                vm.release(&ret);
+
+                copy
+            })
+        })
+        .collect();
+
+    let mut is_failed = false;
+
+    for (file, worker) in files.iter().zip(workers) {
+        match worker.join() {
+            Ok(Ok(obj)) => println!("{}: {}", file, obj.write()),
+            Ok(Err(e)) => {
+                eprintln!("{}: {:#}", file, e);
+                is_failed = true;
+            }
+            Err(_) => {
+                eprintln!("{}: Worker panicked", file);
+                is_failed = true;
+            }
+        }
+    }
+
+    if is_failed {
+        std::process::exit(1);
+    }
 }
 
 fn prelude() -> String {
@@ -1,6 +1,6 @@
-use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
 use anyhow::{bail, Result};
 
 #[derive(Debug, Clone)]
@@ -9,12 +9,112 @@ pub enum Obj {
     Number(Number),
     String(String),
     Id(Id),
-    Pair(Rc<RefCell<(Obj, Obj)>>),
-    Closure { addr: u32, fp: u32 },
-    Context { pc: u32, fp: u32 },
+    Identifier(Id, u32),
+    Pair(Arc<RwLock<Cons>>),
+    Vector(Arc<RwLock<Vec<Obj>>>),
+    Closure {
+        addr: u32,
+        fp: u32,
+        name: Option<Arc<str>>,
+    },
+    Context {
+        pc: u32,
+        fp: u32,
+    },
+    Environment(u32),
+    Continuation(Arc<Continuation>),
+    Coroutine(Arc<RwLock<Coroutine>>),
+    Values(Arc<[Obj]>),
+    RecordType(Arc<RecordType>),
+    Record(Arc<Record>),
     Null,
 }
 
+#[derive(Debug)]
+pub struct Cons(pub Obj, pub Obj);
+
+impl Cons {
+    pub fn cloned(&self) -> (Obj, Obj) {
+        (self.0.clone(), self.1.clone())
+    }
+}
+
+impl Drop for Cons {
+    fn drop(&mut self) {
+        let mut objs = vec![
+            std::mem::replace(&mut self.0, Obj::Null),
+            std::mem::replace(&mut self.1, Obj::Null),
+        ];
+
+        while let Some(obj) = objs.pop() {
+            match obj {
+                Obj::Pair(p) => {
+                    if let Ok(cell) = Arc::try_unwrap(p) {
+                        let mut cons = cell.into_inner().unwrap();
+                        objs.push(std::mem::replace(&mut cons.0, Obj::Null));
+                        objs.push(std::mem::replace(&mut cons.1, Obj::Null));
+                    }
+                }
+                Obj::Vector(v) => {
+                    if let Ok(cell) = Arc::try_unwrap(v) {
+                        objs.extend(cell.into_inner().unwrap());
+                    }
+                }
+                Obj::Record(r) => {
+                    if let Ok(r) = Arc::try_unwrap(r) {
+                        objs.extend(r.fields.into_inner().unwrap());
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Continuation {
+    pub stack: Vec<Obj>,
+    pub pc: u32,
+    pub fp: u32,
+    pub winders: Vec<Arc<(Obj, Obj)>>,
+    pub level: u64,
+}
+
+#[derive(Debug)]
+pub struct Coroutine {
+    pub stack: Vec<Obj>,
+    pub sp: u32,
+    pub pc: u32,
+    pub fp: u32,
+    pub base: u32,
+    pub level: u64,
+    pub winders: Vec<Arc<(Obj, Obj)>>,
+    pub id: u64,
+    pub state: CoroutineState,
+    pub preemptive: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoroutineState {
+    Created,
+    Suspended,
+    Preempted,
+    Running,
+    Dead,
+}
+
+#[derive(Debug)]
+pub struct RecordType {
+    pub name: Id,
+    pub fields: Vec<Id>,
+}
+
+#[derive(Debug)]
+pub struct Record {
+    pub rtd: Arc<RecordType>,
+    pub fields: RwLock<Vec<Obj>>,
+}
+
 impl PartialEq for Obj {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -22,26 +122,41 @@ impl PartialEq for Obj {
             (Self::Number(l), Self::Number(r)) => l == r,
             (Self::String(l), Self::String(r)) => l == r,
             (Self::Id(l), Self::Id(r)) => l.0 == r.0,
+            (Self::Identifier(l, l_ctx), Self::Identifier(r, r_ctx)) => {
+                l.0 == r.0 && l_ctx == r_ctx
+            }
             (
                 Self::Closure {
                     addr: addr_l,
                     fp: fp_l,
+                    ..
                 },
                 Self::Closure {
                     addr: addr_r,
                     fp: fp_r,
+                    ..
                 },
             ) => addr_l == addr_r && fp_l == fp_r,
             (Self::Context { pc: pc_l, fp: fp_l }, Self::Context { pc: pc_r, fp: fp_r }) => {
                 pc_l == pc_r && fp_l == fp_r
             }
+            (Self::Environment(l), Self::Environment(r)) => l == r,
+            (Self::Continuation(l), Self::Continuation(r)) => Arc::ptr_eq(l, r),
+            (Self::Coroutine(l), Self::Coroutine(r)) => Arc::ptr_eq(l, r),
+            (Self::Values(l), Self::Values(r)) => l == r,
+            (Self::RecordType(l), Self::RecordType(r)) => Arc::ptr_eq(l, r),
+            (Self::Record(l), Self::Record(r)) => {
+                Arc::ptr_eq(&l.rtd, &r.rtd)
+                    && *l.fields.read().unwrap() == *r.fields.read().unwrap()
+            }
             (Self::Null, Self::Null) => true,
             (Self::Pair(l), Self::Pair(r)) => {
-                let l = l.borrow();
-                let r = r.borrow();
+                let l = l.read().unwrap();
+                let r = r.read().unwrap();
 
                 l.0 == r.0 && l.1 == r.1
             }
+            (Self::Vector(l), Self::Vector(r)) => *l.read().unwrap() == *r.read().unwrap(),
             _ => false,
         }
     }
@@ -49,34 +164,301 @@ impl PartialEq for Obj {
 
 impl Display for Obj {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Obj::Bool(v) => write!(f, "{}", v),
+        Printer::new(self, false, false).print(self, f)
+    }
+}
+
+pub struct Written<'a> {
+    obj: &'a Obj,
+    is_shared: bool,
+}
+
+impl Display for Written<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Printer::new(self.obj, true, self.is_shared).print(self.obj, f)
+    }
+}
+
+struct Printer {
+    is_write: bool,
+    labeled: HashSet<*const RwLock<Cons>>,
+    labels: HashMap<*const RwLock<Cons>, usize>,
+    records: HashSet<*const Record>,
+}
+
+impl Printer {
+    fn new(obj: &Obj, is_write: bool, is_shared: bool) -> Self {
+        let mut labeled = HashSet::new();
+
+        find_labeled(obj, is_shared, &mut HashSet::new(), &mut HashSet::new(), &mut labeled);
+
+        Self {
+            is_write,
+            labeled,
+            labels: Default::default(),
+            records: Default::default(),
+        }
+    }
+
+    fn print(&mut self, obj: &Obj, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match obj {
+            Obj::Bool(v) => write!(f, "{}", if *v { "#t" } else { "#f" }),
+            Obj::Number(Number::Float(v)) if self.is_write && v.fract() == 0.0 => {
+                write!(f, "{}.0", v)
+            }
             Obj::Number(v) => write!(f, "{}", v),
+            Obj::String(v) if self.is_write => write!(f, "\"{}\"", escape(v)),
             Obj::String(v) => write!(f, "{}", v),
-            Obj::Id(v) => write!(f, "{}", v.0),
+            Obj::Id(v) | Obj::Identifier(v, _) => write!(f, "{}", v.0),
             Obj::Pair(v) => {
-                let v = v.borrow();
-                write!(f, "({})", display_pair(&v))
+                let ptr = Arc::as_ptr(v);
+
+                if let Some(label) = self.labels.get(&ptr) {
+                    return write!(f, "#{}#", label);
+                }
+
+                if self.labeled.contains(&ptr) {
+                    let label = self.labels.len();
+                    self.labels.insert(ptr, label);
+                    write!(f, "#{}=", label)?;
+                }
+
+                let (car, mut cdr) = v.read().unwrap().cloned();
+
+                write!(f, "(")?;
+                self.print(&car, f)?;
+
+                loop {
+                    match cdr {
+                        Obj::Null => break,
+                        Obj::Pair(p) if !self.labeled.contains(&Arc::as_ptr(&p)) => {
+                            let (car, next) = p.read().unwrap().cloned();
+
+                            write!(f, " ")?;
+                            self.print(&car, f)?;
+
+                            cdr = next;
+                        }
+                        _ => {
+                            write!(f, " . ")?;
+                            self.print(&cdr, f)?;
+                            break;
+                        }
+                    }
+                }
+
+                write!(f, ")")
             }
-            Obj::Closure { addr, fp } => write!(f, "closure({}, {})", addr, fp),
+            Obj::Vector(v) => {
+                write!(f, "#(")?;
+
+                for (i, v) in v.read().unwrap().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+
+                    self.print(v, f)?;
+                }
+
+                write!(f, ")")
+            }
+            Obj::Closure {
+                name: Some(name), ..
+            } => write!(f, "#<procedure {}>", name),
+            Obj::Closure { name: None, .. } => write!(f, "#<procedure>"),
             Obj::Context { pc, fp } => write!(f, "context({}, {})", pc, fp),
-            Obj::Null => write!(f, "null"),
+            Obj::Environment(_) => write!(f, "#<environment>"),
+            Obj::Continuation(_) => write!(f, "#<continuation>"),
+            Obj::Coroutine(_) => write!(f, "#<coroutine>"),
+            Obj::RecordType(v) => write!(f, "#<record-type {}>", v.name.0),
+            Obj::Record(v) => {
+                let ptr = Arc::as_ptr(v);
+
+                if !self.records.insert(ptr) {
+                    return write!(f, "#<record {} ...>", v.rtd.name.0);
+                }
+
+                write!(f, "#<record {}", v.rtd.name.0)?;
+
+                for (id, v) in v.rtd.fields.iter().zip(v.fields.read().unwrap().iter()) {
+                    write!(f, " {}=", id.0)?;
+                    self.print(v, f)?;
+                }
+
+                self.records.remove(&ptr);
+
+                write!(f, ">")
+            }
+            Obj::Values(v) => {
+                for (i, v) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+
+                    self.print(v, f)?;
+                }
+
+                Ok(())
+            }
+            Obj::Null => write!(f, "()"),
         }
     }
 }
 
-fn display_pair(pair: &(Obj, Obj)) -> String {
-    if let Obj::Pair(v) = &pair.1 {
-        format!("{} {}", pair.0, display_pair(&v.borrow()))
-    } else {
-        match pair.1 {
-            Obj::Null => format!("{}", pair.0),
-            _ => format!("{} . {}", pair.0, pair.1),
+fn find_labeled(
+    obj: &Obj,
+    is_shared: bool,
+    visited: &mut HashSet<*const RwLock<Cons>>,
+    path: &mut HashSet<*const RwLock<Cons>>,
+    labeled: &mut HashSet<*const RwLock<Cons>>,
+) {
+    if let Obj::Vector(v) = obj {
+        for e in v.read().unwrap().iter() {
+            find_labeled(e, is_shared, visited, path, labeled);
         }
+
+        return;
     }
+
+    let mut entered = vec![];
+    let mut cur = obj.clone();
+
+    while let Obj::Pair(p) = cur {
+        let ptr = Arc::as_ptr(&p);
+
+        if path.contains(&ptr) || (is_shared && visited.contains(&ptr)) {
+            labeled.insert(ptr);
+        }
+
+        if !visited.insert(ptr) {
+            break;
+        }
+
+        path.insert(ptr);
+        entered.push(ptr);
+
+        let (car, cdr) = p.read().unwrap().cloned();
+        find_labeled(&car, is_shared, visited, path, labeled);
+
+        cur = cdr;
+    }
+
+    for ptr in entered {
+        path.remove(&ptr);
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut res = String::new();
+
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            _ => res.push(c),
+        }
+    }
+
+    res
 }
 
 impl Obj {
+    pub fn write(&self) -> Written<'_> {
+        Written {
+            obj: self,
+            is_shared: false,
+        }
+    }
+
+    pub fn write_shared(&self) -> Written<'_> {
+        Written {
+            obj: self,
+            is_shared: true,
+        }
+    }
+
+    pub fn pretty(&self, width: usize) -> String {
+        let mut res = String::new();
+        self.pretty_at(0, width, &mut res);
+
+        res
+    }
+
+    fn pretty_at(&self, col: usize, width: usize, res: &mut String) {
+        let flat = self.write().to_string();
+
+        let items = match self.clone().list_elems() {
+            Ok(items) if col + flat.len() > width && !items.is_empty() => items,
+            _ => return res.push_str(&flat),
+        };
+
+        res.push('(');
+
+        let mut items = items.iter();
+        let head = items.next().unwrap();
+
+        let col = if let Obj::Id(_) | Obj::Identifier(..) = head {
+            let head = head.write().to_string();
+            res.push_str(&head);
+
+            if let Some(first) = items.next() {
+                res.push(' ');
+                first.pretty_at(col + head.len() + 2, width, res);
+            }
+
+            col + 2
+        } else {
+            head.pretty_at(col + 1, width, res);
+
+            col + 1
+        };
+
+        for item in items {
+            res.push('\n');
+            res.push_str(&" ".repeat(col));
+            item.pretty_at(col, width, res);
+        }
+
+        res.push(')');
+    }
+
+    // Copies plain data without sharing anything with the original, so that the copy can be
+    // handed to another VM. Shared structure and cycles are preserved.
+    pub fn deep_copy(&self) -> Result<Obj> {
+        let mut copies = HashMap::new();
+        let mut pending = vec![];
+
+        let copy = shallow_copy(self, &mut copies, &mut pending)?;
+
+        while let Some((from, to)) = pending.pop() {
+            match (from, to) {
+                (Obj::Pair(from), Obj::Pair(to)) => {
+                    let (car, cdr) = from.read().unwrap().cloned();
+                    let car = shallow_copy(&car, &mut copies, &mut pending)?;
+                    let cdr = shallow_copy(&cdr, &mut copies, &mut pending)?;
+
+                    *to.write().unwrap() = Cons(car, cdr);
+                }
+                (Obj::Vector(from), Obj::Vector(to)) => {
+                    let items = from
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .map(|obj| shallow_copy(obj, &mut copies, &mut pending))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    *to.write().unwrap() = items;
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        Ok(copy)
+    }
+
     pub fn bool(self) -> Result<bool> {
         let Self::Bool(n) = self else {
             bail!("Not Bool")
@@ -102,25 +484,46 @@ impl Obj {
     }
 
     pub fn id(self) -> Result<Id> {
-        let Self::Id(n) = self else { bail!("Not Id") };
+        match self {
+            Self::Id(n) | Self::Identifier(n, _) => Ok(n),
+            _ => bail!("Not Id"),
+        }
+    }
 
-        Ok(n)
+    pub fn vector(self) -> Result<Arc<RwLock<Vec<Obj>>>> {
+        let Self::Vector(v) = self else {
+            bail!("Not Vector")
+        };
+
+        Ok(v)
     }
 
     pub fn list_elems(self) -> Result<Vec<Obj>> {
-        match self {
-            Obj::Null => Ok(vec![]),
-            Obj::Pair(p) => {
-                let p = p.borrow();
-
-                Ok(vec![vec![p.0.clone()], p.1.clone().list_elems()?]
-                    .into_iter()
-                    .flatten()
-                    .collect())
-            }
-            _ => bail!("Not List"),
+        let mut elems = vec![];
+        let mut list = self;
+
+        loop {
+            list = match list {
+                Obj::Null => return Ok(elems),
+                Obj::Pair(p) => {
+                    let (car, cdr) = p.read().unwrap().cloned();
+                    elems.push(car);
+                    cdr
+                }
+                _ => bail!("Not List"),
+            };
         }
     }
+
+    pub fn list(elems: Vec<Obj>) -> Obj {
+        let mut list = Obj::Null;
+
+        for e in elems.into_iter().rev() {
+            list = Obj::Pair(Arc::new(RwLock::new(Cons(e, list))));
+        }
+
+        list
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -179,3 +582,48 @@ impl Number {
 
 #[derive(Debug, Clone, PartialEq, Hash, Eq)]
 pub struct Id(pub String);
+
+impl Id {
+    // The name as written in the source, without the suffixes codegen adds to renamed bindings.
+    pub fn name(&self) -> &str {
+        let name = self.0.split_once(' ').map_or(self.0.as_str(), |(v, _)| v);
+
+        match name.rsplit_once('~') {
+            Some((v, n)) if !v.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => v,
+            _ => name,
+        }
+    }
+}
+
+// Copies atoms right away and leaves empty containers to be filled in by the caller.
+fn shallow_copy(
+    obj: &Obj,
+    copies: &mut HashMap<*const (), Obj>,
+    pending: &mut Vec<(Obj, Obj)>,
+) -> Result<Obj> {
+    let (key, copy) = match obj {
+        Obj::Bool(_)
+        | Obj::Number(_)
+        | Obj::String(_)
+        | Obj::Id(_)
+        | Obj::Identifier(..)
+        | Obj::Null => {
+            return Ok(obj.clone());
+        }
+        Obj::Pair(p) => (
+            Arc::as_ptr(p) as *const (),
+            Obj::Pair(Arc::new(RwLock::new(Cons(Obj::Null, Obj::Null)))),
+        ),
+        Obj::Vector(v) => (Arc::as_ptr(v) as *const (), Obj::Vector(Arc::new(RwLock::new(vec![])))),
+        _ => bail!("{} cannot be copied to another VM", obj.write()),
+    };
+
+    if let Some(copy) = copies.get(&key) {
+        return Ok(copy.clone());
+    }
+
+    copies.insert(key, copy.clone());
+    pending.push((obj.clone(), copy.clone()));
+
+    Ok(copy)
+}
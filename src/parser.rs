@@ -1,6 +1,10 @@
-use std::collections::HashMap;
-use crate::lexer::{Meta, Token, TokenKind};
+use crate::lexer::{get_tokens_from_obj, Meta, Token, TokenKind};
+use crate::obj::Obj;
 use crate::syntax::*;
+use crate::vm::Budget;
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
 use anyhow::{bail, Context as _, ensure, Result};
 use ctx::*;
 
@@ -15,7 +19,7 @@ macro_rules! ensure_paren_open {
 macro_rules! ensure_paren_close {
     ($ctx:expr) => {
         if $ctx.read()?.kind != TokenKind::ParenClose {
-            panic!("')' expected");
+            bail!("')' expected");
         }
     };
 }
@@ -28,6 +32,15 @@ macro_rules! ensure_symbol {
     };
 }
 
+// Code evaluated in a null or report environment must not reach the interaction environment
+// through these.
+const ENV_PROCS: [&str; 4] = [
+    "eval",
+    "interaction-environment",
+    "scheme-report-environment",
+    "null-environment",
+];
+
 pub struct Parser {
     ctx: Context,
 }
@@ -42,15 +55,116 @@ impl Parser {
     pub fn parse(&mut self, src: String, is_strict_syntax: bool) -> Result<AST> {
         let tokens =
             crate::lexer::get_tokens(src, is_strict_syntax).context("Failed to tokenize")?;
+
+        self.parse_tokens(tokens)
+    }
+
+    pub fn parse_file(&mut self, path: &Path) -> Result<AST> {
+        let src = read_to_string(path).context(format!("Failed to open {}", path.display()))?;
+
+        let prev = self.ctx.set_file(Some(path.to_path_buf()));
+        let ast = self.parse(src, true);
+        self.ctx.set_file(prev);
+
+        ast.context(format!("In {}", path.display()))
+    }
+
+    // Transformers run while parsing use up the budget of the execution that parses.
+    pub fn with_budget<T>(
+        &mut self,
+        budget: &mut Budget,
+        parse: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.ctx.swap_budget(budget);
+        let res = parse(self);
+        self.ctx.swap_budget(budget);
+
+        res
+    }
+
+    pub fn add_search_path(&mut self, dir: PathBuf) {
+        self.ctx.add_search_path(dir);
+    }
+
+    pub fn parse_tokens(&mut self, tokens: Vec<Token>) -> Result<AST> {
         self.ctx.add(tokens);
 
         let mut body = vec![];
 
         while self.ctx.has_token() {
-            body.push(Parse::parse(&mut self.ctx)?);
+            match Parse::parse(&mut self.ctx) {
+                Ok(t) => body.push(t),
+                Err(e) => {
+                    self.ctx.discard();
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(AST {
+            body,
+            id_ctxs: self.ctx.take_id_ctxs(),
+        })
+    }
+
+    // Parses code evaluated in a null or report environment, which must not reach the file
+    // system.
+    pub fn parse_tokens_sandboxed(&mut self, tokens: Vec<Token>) -> Result<AST> {
+        self.ctx.set_sandboxed(true);
+        let ast = self.parse_tokens(tokens);
+        self.ctx.set_sandboxed(false);
+
+        ast
+    }
+
+    pub fn expand(&mut self, src: String, show_renames: bool) -> Result<Vec<Obj>> {
+        self.ctx.start_trace();
+        let res = self.parse(src, true);
+        let trace = self.ctx.take_trace();
+
+        res?;
+
+        // Without --show-renames, a name only keeps its renames where it is used with more
+        // than one context, since the plain names would otherwise refer to the same binding.
+        // Macros can define globals, so this holds across the whole file.
+        let mut ctxs: HashMap<&str, HashSet<u32>> = HashMap::new();
+
+        for t in &trace {
+            if let TokenKind::Id(v) = &t.kind {
+                ctxs.entry(v).or_default().insert(t.meta.id_ctx);
+            }
+        }
+
+        let tokens: Vec<Token> = trace
+            .iter()
+            .map(|t| {
+                let mut t = t.clone();
+
+                if let TokenKind::Id(v) = &t.kind {
+                    if t.meta.id_ctx != 0 && (show_renames || ctxs[v.as_str()].len() > 1) {
+                        t.kind = TokenKind::Id(format!("{}#{}", v, t.meta.id_ctx));
+                    }
+                }
+
+                t
+            })
+            .collect();
+
+        TokenTree::parse_all(&tokens)?.iter().map(TokenTree::to_obj).collect()
+    }
+
+    pub fn macroexpand(&mut self, form: &Obj, is_once: bool) -> Result<Obj> {
+        let mut form = TokenTree::parse(&get_tokens_from_obj(form)?)?;
+
+        while let Some(id) = form.head().filter(|id| self.ctx.is_macro(id, 0)) {
+            form = self.ctx.expand_form(&id, 0, &form)?;
+
+            if is_once {
+                break;
+            }
         }
 
-        Ok(AST { body })
+        form.to_obj()
     }
 }
 
@@ -78,8 +192,23 @@ where
 
 impl Parse for Toplevel {
     fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.unreserve_keywords()?;
+
+        if ctx.expand_macro_use()? {
+            return Self::parse(ctx);
+        }
+
         match ctx.peek(0)?.kind {
             TokenKind::ParenOpen => match ctx.peek(1)?.kind {
+                TokenKind::Load
+                | TokenKind::Require
+                | TokenKind::DefineLibrary
+                | TokenKind::Import
+                | TokenKind::Include
+                    if ctx.is_sandboxed() =>
+                {
+                    bail!("Files cannot be loaded in this environment")
+                }
                 TokenKind::DefineSyntax => {
                     let syntax_def = DefineSyntax::parse(ctx)?;
 
@@ -87,8 +216,13 @@ impl Parse for Toplevel {
 
                     Ok(Self::DefineSyntax)
                 }
-                TokenKind::Define => Ok(Self::Define(Parse::parse(ctx)?)),
-                TokenKind::Load => Ok(Self::Load(Parse::parse(ctx)?)),
+                TokenKind::Define | TokenKind::DefineValues | TokenKind::DefineRecordType => {
+                    Ok(Self::Define(Parse::parse(ctx)?))
+                }
+                TokenKind::Load | TokenKind::Require => Ok(Self::Load(Parse::parse(ctx)?)),
+                TokenKind::DefineLibrary => Ok(Self::Library(Parse::parse(ctx)?)),
+                TokenKind::Import => Ok(Self::Import(Parse::parse(ctx)?)),
+                TokenKind::Include => Ok(Self::Include(Parse::parse(ctx)?)),
                 _ => Ok(Self::Exp(Parse::parse(ctx)?)),
             },
             _ => Ok(Self::Exp(Parse::parse(ctx)?)),
@@ -98,428 +232,1540 @@ impl Parse for Toplevel {
 
 impl Parse for DefineSyntax {
     fn parse(ctx: &mut Context) -> Result<Self> {
+        let trace = ctx.trace_len();
+
         ctx.start();
 
         ensure_paren_open!(ctx);
         ensure_symbol!(ctx, TokenKind::DefineSyntax, "define-syntax");
 
         let id = Parse::parse(ctx)?;
+        let def = Self::parse_transformer(ctx, id)?;
+
+        ensure_paren_close!(ctx);
+
+        ctx.untrace(trace);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            ..def
+        })
+    }
+}
+
+impl DefineSyntax {
+    fn parse_transformer(ctx: &mut Context, id: Id) -> Result<Self> {
+        ctx.start();
+
+        let transformer = match ctx.peek(1)?.kind {
+            TokenKind::ErMacroTransformer => {
+                // Transformers run in the compile-time VM, which is not sandboxed.
+                ensure!(
+                    !ctx.is_sandboxed(),
+                    "Procedural macros cannot be defined in this environment"
+                );
+
+                ensure_paren_open!(ctx);
+                ensure_symbol!(ctx, TokenKind::ErMacroTransformer, "er-macro-transformer");
+
+                let exp = ctx.read_next_chunk()?;
+
+                ensure_paren_close!(ctx);
+
+                Transformer::ErMacro(ctx.define_transformer(exp)?)
+            }
+            _ => Transformer::SyntaxRules(Parse::parse(ctx)?),
+        };
+
+        Ok(Self {
+            meta: ctx.meta(),
+            id,
+            transformer,
+        })
+    }
+}
+
+impl Parse for SyntaxRules {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::SyntaxRules, "syntax-rules");
+
+        let ellipsis = if ctx.peek(0)?.kind == TokenKind::ParenOpen {
+            "...".to_string()
+        } else {
+            ctx.read()?.kind.identifier().context("Ellipsis identifier expected")?.to_string()
+        };
+
+        ensure_paren_open!(ctx);
+
+        let mut literals = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            literals.push(
+                ctx.read()?.kind.identifier().context("Literal identifier expected")?.to_string(),
+            );
+        }
+
+        ensure_paren_close!(ctx);
+
+        let syntax_rules = Parse::parse(ctx)?;
+
+        ensure_paren_close!(ctx);
+
+        let rules = Self {
+            meta: ctx.meta(),
+            ellipsis,
+            literals,
+            syntax_rules,
+        };
+
+        for rule in &rules.syntax_rules {
+            rules.check_pattern(&rule.pattern)?;
+        }
+
+        Ok(rules)
+    }
+}
+
+impl Parse for LetSyntax {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+
+        if !matches!(ctx.read()?.kind, TokenKind::LetSyntax | TokenKind::LetRecSyntax) {
+            bail!("'let-syntax' expected");
+        }
+
+        let scope = ctx.enter_scope();
+        let body = Self::parse_body(ctx, scope);
+        ctx.exit_scope();
+
+        let body = body?;
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            body,
+        })
+    }
+}
+
+impl LetSyntax {
+    fn parse_body(ctx: &mut Context, scope: u32) -> Result<Body> {
+        ensure_paren_open!(ctx);
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            let trace = ctx.trace_len();
+
+            ctx.start();
+
+            ensure_paren_open!(ctx);
+
+            let id = Parse::parse(ctx)?;
+            let def = DefineSyntax::parse_transformer(ctx, id)?;
+
+            ensure_paren_close!(ctx);
+
+            ctx.untrace(trace);
+
+            let meta = ctx.meta();
+            ctx.add_syntax_def(DefineSyntax { meta, ..def });
+        }
+
+        ensure_paren_close!(ctx);
+
+        Body::parse_in_scope(ctx, scope)
+    }
+}
+
+impl Parse for SyntaxRule {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        let pattern = TokenTree::parse(&ctx.read_next_chunk()?)?;
+        let template = TokenTree::parse(&ctx.read_next_chunk()?)?;
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            pattern,
+            template,
+        })
+    }
+}
+
+impl Parse for Library {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        let id_ctx = ctx.new_library_ctx();
+        ctx.mark_next_chunk(id_ctx)?;
+
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::DefineLibrary, "define-library");
+
+        let name = Self::parse_name(ctx)?;
+
+        let mut body = vec![];
+        let mut exports = vec![];
+
+        ctx.enter_scope();
+        let res = Self::parse_declarations(ctx, &mut body, &mut exports);
+        let defs = ctx.exit_scope();
+
+        res?;
+
+        ensure_paren_close!(ctx);
+
+        let meta = ctx.meta();
+
+        let ids = defs
+            .iter()
+            .filter(|(_, defs)| {
+                defs.last().is_some_and(|(_, def)| matches!(def, ScopeBinding::Var))
+            })
+            .map(|(v, _)| Id {
+                meta: meta.clone(),
+                id_ctx,
+                v: v.clone(),
+            })
+            .collect();
+
+        ctx.add_library(name, id_ctx, defs, exports);
+
+        Ok(Self { meta, ids, body })
+    }
+}
+
+impl Library {
+    fn parse_name(ctx: &mut Context) -> Result<Vec<String>> {
+        ensure_paren_open!(ctx);
+
+        let mut name = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            name.push(match ctx.read()?.kind {
+                TokenKind::Num(n) => n.to_string(),
+                kind => kind.identifier().context("Invalid library name")?.to_string(),
+            });
+        }
+
+        ensure_paren_close!(ctx);
+
+        Ok(name)
+    }
+
+    fn parse_declarations(
+        ctx: &mut Context,
+        body: &mut Vec<Toplevel>,
+        exports: &mut Vec<(Id, String)>,
+    ) -> Result<()> {
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            match ctx.peek(1)?.kind {
+                TokenKind::Export => {
+                    ensure_paren_open!(ctx);
+                    ensure_symbol!(ctx, TokenKind::Export, "export");
+
+                    while ctx.peek(0)?.kind != TokenKind::ParenClose {
+                        if ctx.peek(0)?.kind != TokenKind::ParenOpen {
+                            let id: Id = Parse::parse(ctx)?;
+                            let name = id.v.clone();
+
+                            exports.push((id, name));
+                            continue;
+                        }
+
+                        ensure_paren_open!(ctx);
+
+                        if ctx.read()?.kind.identifier() != Some("rename") {
+                            bail!("'rename' expected");
+                        }
+
+                        let id = Parse::parse(ctx)?;
+                        let name: Id = Parse::parse(ctx)?;
+
+                        exports.push((id, name.v));
+
+                        ensure_paren_close!(ctx);
+                    }
+
+                    ensure_paren_close!(ctx);
+                }
+                TokenKind::Import => body.push(Toplevel::Import(Parse::parse(ctx)?)),
+                TokenKind::Include => body.push(Toplevel::Include(Parse::parse(ctx)?)),
+                TokenKind::Begin => {
+                    ensure_paren_open!(ctx);
+                    ensure_symbol!(ctx, TokenKind::Begin, "begin");
+
+                    while ctx.peek(0)?.kind != TokenKind::ParenClose {
+                        body.push(Parse::parse(ctx)?);
+                    }
+
+                    ensure_paren_close!(ctx);
+                }
+                _ => bail!("Invalid library declaration"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Parse for Import {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+
+        let import = ctx.read()?;
+
+        if import.kind != TokenKind::Import {
+            bail!("'import' expected");
+        }
+
+        let mut libraries = vec![];
+        let mut bindings = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            for (name, export) in Self::parse_set(ctx, &mut libraries)? {
+                match export {
+                    Export::Var(target) => {
+                        let id = Id {
+                            meta: import.meta.clone(),
+                            id_ctx: import.meta.id_ctx,
+                            v: name,
+                        };
+
+                        ctx.bind_import(&id);
+                        bindings.push(ImportBinding { id, target });
+                    }
+                    Export::Syntax(def) => {
+                        let id = Id {
+                            meta: import.meta.clone(),
+                            id_ctx: import.meta.id_ctx,
+                            v: name.clone(),
+                        };
+
+                        ctx.bind_syntax(
+                            &id,
+                            DefineSyntax {
+                                id: Id { v: name, ..def.id },
+                                ..def
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            libraries,
+            bindings,
+        })
+    }
+}
+
+impl Import {
+    fn parse_set(ctx: &mut Context, libraries: &mut Vec<Library>) -> Result<Vec<(String, Export)>> {
+        let modifier = match ctx.peek(1)?.kind.identifier() {
+            Some(m @ ("only" | "except" | "prefix" | "rename")) => m.to_string(),
+            _ => {
+                let name = Library::parse_name(ctx)?;
+                return Self::import_library(ctx, name, libraries);
+            }
+        };
+
+        ensure_paren_open!(ctx);
+        ctx.read()?;
+
+        let mut set = Self::parse_set(ctx, libraries)?;
+
+        match modifier.as_str() {
+            "only" | "except" => {
+                let mut ids = vec![];
+
+                while ctx.peek(0)?.kind != TokenKind::ParenClose {
+                    let id: Id = Parse::parse(ctx)?;
+
+                    ensure!(set.iter().any(|(name, _)| *name == id.v), "{} is not exported", id.v);
+
+                    ids.push(id.v);
+                }
+
+                set.retain(|(name, _)| ids.contains(name) == (modifier == "only"));
+            }
+            "prefix" => {
+                let prefix: Id = Parse::parse(ctx)?;
+
+                for (name, _) in &mut set {
+                    *name = format!("{}{}", prefix.v, name);
+                }
+            }
+            _ => {
+                while ctx.peek(0)?.kind != TokenKind::ParenClose {
+                    ensure_paren_open!(ctx);
+
+                    let from: Id = Parse::parse(ctx)?;
+                    let to: Id = Parse::parse(ctx)?;
+
+                    let (name, _) = set
+                        .iter_mut()
+                        .find(|(name, _)| *name == from.v)
+                        .context(format!("{} is not exported", from.v))?;
+
+                    *name = to.v;
+
+                    ensure_paren_close!(ctx);
+                }
+            }
+        }
+
+        ensure_paren_close!(ctx);
+
+        Ok(set)
+    }
+
+    fn import_library(
+        ctx: &mut Context,
+        name: Vec<String>,
+        libraries: &mut Vec<Library>,
+    ) -> Result<Vec<(String, Export)>> {
+        let key = format!("({})", name.join(" "));
+
+        if let Some(exports) = ctx.library_exports(&key) {
+            return Ok(exports);
+        }
+
+        let path = ctx.find_library(&name).context(format!("Library {} is not found", key))?;
+
+        let src = read_to_string(&path).context(format!("Failed to open {}", path.display()))?;
+        let tokens = crate::lexer::get_tokens(src, true).context("Failed to tokenize")?;
+
+        let trace = ctx.trace_len();
+
+        ctx.insert(tokens);
+        ctx.enter_library_file(&path, &key)?;
+        let library = Library::parse(ctx);
+        ctx.exit_library_file();
+
+        ctx.untrace(trace);
+
+        libraries.push(library?);
+
+        ctx.library_exports(&key).context(format!("{} does not define {}", path.display(), key))
+    }
+}
+
+impl Parse for Include {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        let trace = ctx.trace_len();
+
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+
+        let include = ctx.read()?;
+
+        if include.kind != TokenKind::Include {
+            bail!("'include' expected");
+        }
+
+        let mut tokens = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            let TokenKind::Str(file) = ctx.read()?.kind else {
+                bail!("File name expected")
+            };
+
+            tokens.extend(ctx.read_include(&file, include.meta.id_ctx)?);
+        }
+
+        ensure_paren_close!(ctx);
+
+        let meta = ctx.meta();
+
+        ctx.untrace(trace);
+
+        let rest = ctx.remaining();
+        ctx.insert(tokens);
+
+        let mut body = vec![];
+
+        while ctx.remaining() > rest {
+            body.push(Parse::parse(ctx)?);
+        }
+
+        Ok(Self { meta, body })
+    }
+}
+
+impl Parse for Load {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+
+        let is_once = match ctx.read()?.kind {
+            TokenKind::Load => false,
+            TokenKind::Require => true,
+            _ => bail!("'load' or 'require' expected"),
+        };
+
+        let mut src: Str = Parse::parse(ctx)?;
+
+        if let Some(path) = ctx.resolve_path(&src.v) {
+            src.v = path.to_string_lossy().into();
+        }
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            src,
+            is_once,
+        })
+    }
+}
+
+impl Parse for Define {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        if ctx.peek(1)?.kind == TokenKind::DefineValues {
+            Ok(Self::Values(Parse::parse(ctx)?))
+        } else if ctx.peek(1)?.kind == TokenKind::DefineRecordType {
+            Ok(Self::RecordType(Parse::parse(ctx)?))
+        } else if ctx.peek(2)?.kind == TokenKind::ParenOpen {
+            Ok(Self::Func(Parse::parse(ctx)?))
+        } else {
+            Ok(Self::Var(Parse::parse(ctx)?))
+        }
+    }
+}
+
+impl Parse for DefVar {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::Define, "define");
+
+        let id = Parse::parse(ctx)?;
+        ctx.bind_var(&id);
+
+        let exp = Parse::parse(ctx)?;
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            id,
+            exp,
+        })
+    }
+}
+
+impl Parse for DefFunc {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::Define, "define");
+        ensure_paren_open!(ctx);
+
+        let id = Parse::parse(ctx)?;
+        ctx.bind_var(&id);
+
+        let mut args = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::Period && ctx.peek(0)?.kind != TokenKind::ParenClose {
+            args.push(Parse::parse(ctx)?);
+        }
+
+        let varg = if ctx.peek(0)?.kind == TokenKind::Period {
+            ensure_symbol!(ctx, TokenKind::Period, ".");
+            Some(Parse::parse(ctx)?)
+        } else {
+            None
+        };
+
+        ensure_paren_close!(ctx);
+
+        let body = Body::parse_scoped(ctx, args.iter().chain(varg.iter()))?;
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            id,
+            args,
+            varg,
+            body,
+        })
+    }
+}
+
+impl Parse for DefValues {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::DefineValues, "define-values");
+
+        let formals: Arg = Parse::parse(ctx)?;
+        let exp = Parse::parse(ctx)?;
+
+        for id in formals.ids() {
+            ctx.bind_var(id);
+        }
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            formals,
+            exp,
+        })
+    }
+}
+
+impl Parse for DefRecordType {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::DefineRecordType, "define-record-type");
+
+        let id: Id = Parse::parse(ctx)?;
+
+        ctx.unreserve(0);
+
+        let constructor = match ctx.peek(0)?.kind {
+            TokenKind::Bool(false) => {
+                let _ = ctx.read()?;
+                None
+            }
+            TokenKind::Id(_) => Some((Parse::parse(ctx)?, None)),
+            TokenKind::ParenOpen => {
+                let c: RecordConstructor = Parse::parse(ctx)?;
+                Some((c.id, Some(c.args)))
+            }
+            _ => bail!("Invalid constructor spec in define-record-type {}", id.v),
+        };
+
+        let predicate = Parse::parse(ctx)?;
+
+        let mut fields: Vec<RecordField> = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            let field: RecordField = Parse::parse(ctx)
+                .with_context(|| format!("Invalid field spec in define-record-type {}", id.v))?;
+
+            if fields.iter().any(|f| f.id.v == field.id.v) {
+                bail!("Duplicate field {} in define-record-type {}", field.id.v, id.v);
+            }
+
+            fields.push(field);
+        }
+
+        ensure_paren_close!(ctx);
+
+        let constructor = match constructor {
+            Some((c, args)) => {
+                let args = args.unwrap_or_else(|| fields.iter().map(|f| f.id.clone()).collect());
+
+                for arg in &args {
+                    if !fields.iter().any(|f| f.id.v == arg.v) {
+                        bail!("{} is not a field of record type {}", arg.v, id.v);
+                    }
+                }
+
+                Some(RecordConstructor {
+                    meta: c.meta.clone(),
+                    id: c,
+                    args,
+                })
+            }
+            None => None,
+        };
+
+        ctx.bind_var(&id);
+        ctx.bind_var(&predicate);
+
+        if let Some(c) = &constructor {
+            ctx.bind_var(&c.id);
+        }
+
+        for f in &fields {
+            ctx.bind_var(&f.accessor);
+
+            if let Some(m) = &f.modifier {
+                ctx.bind_var(m);
+            }
+        }
+
+        Ok(Self {
+            meta: ctx.meta(),
+            id,
+            constructor,
+            predicate,
+            fields,
+        })
+    }
+}
+
+impl Parse for RecordConstructor {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+
+        let id = Parse::parse(ctx)?;
+        let args = Parse::parse(ctx)?;
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            id,
+            args,
+        })
+    }
+}
+
+impl Parse for RecordField {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+
+        let id = Parse::parse(ctx)?;
+        let accessor = Parse::parse(ctx)?;
+
+        let modifier = if ctx.peek(0)?.kind == TokenKind::ParenClose {
+            None
+        } else {
+            Some(Parse::parse(ctx)?)
+        };
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            id,
+            accessor,
+            modifier,
+        })
+    }
+}
+
+impl Parse for Exp {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.unreserve_keywords()?;
+
+        match ctx.peek(0)?.kind {
+            TokenKind::ParenOpen => match ctx.peek(1)?.kind {
+                TokenKind::ParenClose => Ok(Self::Const(Parse::parse(ctx)?)),
+                TokenKind::Lambda => Ok(Self::Lambda(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Quote => Ok(Self::Quote(Box::new(Parse::parse(ctx)?))),
+                TokenKind::QuasiQuote => Ok(Self::QuasiQuote(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Set => Ok(Self::Set(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Let => Ok(Self::Let(Box::new(Parse::parse(ctx)?))),
+                TokenKind::LetAster => Ok(Self::LetAster(Box::new(Parse::parse(ctx)?))),
+                TokenKind::LetRec | TokenKind::LetRecAster => {
+                    Ok(Self::LetRec(Box::new(Parse::parse(ctx)?)))
+                }
+                TokenKind::LetValues => Ok(Self::LetValues(Box::new(Parse::parse(ctx)?))),
+                TokenKind::LetAsterValues => Ok(Self::LetAsterValues(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Receive => Ok(Self::Receive(Box::new(Parse::parse(ctx)?))),
+                TokenKind::If => Ok(Self::If(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Cond => Ok(Self::Cond(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Case => Ok(Self::Case(Box::new(Parse::parse(ctx)?))),
+                TokenKind::When => Ok(Self::When(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Unless => Ok(Self::Unless(Box::new(Parse::parse(ctx)?))),
+                TokenKind::CaseLambda => Ok(Self::CaseLambda(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Assert => Ok(Self::Assert(Box::new(Parse::parse(ctx)?))),
+                TokenKind::And => Ok(Self::And(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Or => Ok(Self::Or(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Begin => Ok(Self::Begin(Box::new(Parse::parse(ctx)?))),
+                TokenKind::Do => Ok(Self::Do(Box::new(Parse::parse(ctx)?))),
+                TokenKind::LetSyntax | TokenKind::LetRecSyntax => {
+                    Ok(Self::LetSyntax(Box::new(Parse::parse(ctx)?)))
+                }
+                _ => {
+                    let id = ctx.peek(1)?;
+
+                    if let TokenKind::Id(v) = &id.kind {
+                        if ctx.is_macro(v, id.meta.id_ctx) {
+                            return Self::expand_macro(ctx);
+                        }
+                    }
+
+                    Ok(Self::Apply(Box::new(Parse::parse(ctx)?)))
+                }
+            },
+            TokenKind::SingleQuote => Ok(Self::Quote(Box::new(Parse::parse(ctx)?))),
+            TokenKind::Backquote => Ok(Self::QuasiQuote(Box::new(Parse::parse(ctx)?))),
+            TokenKind::VectorOpen => Ok(Self::Quote(Box::new(Parse::parse(ctx)?))),
+
+            TokenKind::Id(ref v)
+                if ctx.is_sandboxed() && ENV_PROCS.contains(&v.trim_start_matches('~')) =>
+            {
+                bail!("{} is not available in this environment", v)
+            }
+            TokenKind::Id(_) => Ok(Self::Id(Parse::parse(ctx)?)),
+
+            TokenKind::Num(_) | TokenKind::Bool(_) | TokenKind::Str(_) => {
+                Ok(Self::Const(Parse::parse(ctx)?))
+            }
+            _ => bail!("Not Exp"),
+        }
+    }
+}
+
+impl Parse for Lambda {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::Lambda, "lambda");
+
+        let arg: Arg = Parse::parse(ctx)?;
+        let body = Body::parse_scoped(ctx, arg.ids())?;
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            arg,
+            body,
+        })
+    }
+}
+
+impl Parse for Apply {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+
+        let func = Parse::parse(ctx)?;
+
+        let mut exps = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            exps.push(Parse::parse(ctx)?);
+        }
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            func,
+            exps,
+        })
+    }
+}
+
+impl Parse for Quote {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+        ctx.enter_quote();
+
+        let s_exp = if ctx.peek(0)?.kind == TokenKind::SingleQuote {
+            ensure_symbol!(ctx, TokenKind::SingleQuote, "'");
+
+            Parse::parse(ctx)?
+        } else if ctx.peek(0)?.kind == TokenKind::VectorOpen {
+            Parse::parse(ctx)?
+        } else {
+            ensure_paren_open!(ctx);
+            ensure_symbol!(ctx, TokenKind::Quote, "quote");
+
+            let s_exp = Parse::parse(ctx)?;
+
+            ensure_paren_close!(ctx);
+
+            s_exp
+        };
+
+        ctx.exit_quote();
+
+        Ok(Self {
+            meta: ctx.meta(),
+            s_exp,
+        })
+    }
+}
+
+impl Parse for QuasiQuote {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        let template = if ctx.peek(0)?.kind == TokenKind::Backquote {
+            ensure_symbol!(ctx, TokenKind::Backquote, "`");
+
+            QuasiSExp::parse_level(ctx, 1)?
+        } else {
+            ensure_paren_open!(ctx);
+            ensure_symbol!(ctx, TokenKind::QuasiQuote, "quasiquote");
+
+            let template = QuasiSExp::parse_level(ctx, 1)?;
+
+            ensure_paren_close!(ctx);
+
+            template
+        };
+
+        if let QuasiSExp::UnquoteSplicing(_) = template {
+            bail!("unquote-splicing is not allowed outside of a list");
+        }
+
+        Ok(Self {
+            meta: ctx.meta(),
+            template,
+        })
+    }
+}
+
+impl QuasiSExp {
+    fn parse_level(ctx: &mut Context, level: usize) -> Result<Self> {
+        let prefix = match &ctx.peek(0)?.kind {
+            TokenKind::ParenOpen => match &ctx.peek(1)?.kind {
+                kind @ (TokenKind::Quote
+                | TokenKind::QuasiQuote
+                | TokenKind::Unquote
+                | TokenKind::UnquoteSplicing) => Some((kind.clone(), true)),
+                _ => None,
+            },
+            kind => kind.abbreviation().map(|kind| (kind, false)),
+        };
+
+        let Some((kind, is_long)) = prefix else {
+            return match ctx.peek(0)?.kind {
+                TokenKind::ParenOpen if ctx.peek(1)?.kind != TokenKind::ParenClose => {
+                    Ok(QuasiPair::parse_level(ctx, level)?.fold())
+                }
+                TokenKind::VectorOpen => Ok(QuasiPair::parse_vector(ctx, level)?.fold_vector()),
+                _ => Ok(Self::SExp(Parse::parse(ctx)?)),
+            };
+        };
+
+        ctx.start();
+
+        let t = ctx.read()?;
+
+        if is_long {
+            let _ = ctx.read()?;
+        }
+
+        let level = match kind {
+            TokenKind::QuasiQuote => level + 1,
+            TokenKind::Unquote | TokenKind::UnquoteSplicing => level - 1,
+            _ => level,
+        };
+
+        let inner = if level == 0 {
+            let exp = Parse::parse(ctx)?;
+
+            if kind == TokenKind::Unquote {
+                Self::Unquote(exp)
+            } else {
+                Self::UnquoteSplicing(exp)
+            }
+        } else {
+            let name = SExp::Id(Id {
+                meta: t.meta,
+                id_ctx: 0,
+                v: crate::lexer::keyword_name(&kind).unwrap().into(),
+            });
+
+            let inner = Self::parse_level(ctx, level)?;
+
+            if let Self::UnquoteSplicing(_) = inner {
+                bail!("unquote-splicing is not allowed outside of a list");
+            }
+
+            Self::Pair(Box::new(QuasiPair {
+                meta: Default::default(),
+                exps: vec![Self::SExp(name), inner],
+                last: None,
+            }))
+        };
+
+        if is_long {
+            ensure_paren_close!(ctx);
+        }
+
+        let meta = ctx.meta();
+
+        Ok(match inner {
+            Self::Pair(mut pair) => {
+                pair.meta = meta;
+                pair.fold()
+            }
+            inner => inner,
+        })
+    }
+}
+
+impl QuasiPair {
+    fn parse_level(ctx: &mut Context, level: usize) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+
+        let mut exps = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::Period && ctx.peek(0)?.kind != TokenKind::ParenClose {
+            exps.push(QuasiSExp::parse_level(ctx, level)?);
+        }
+
+        let last = if ctx.peek(0)?.kind == TokenKind::Period {
+            if exps.is_empty() {
+                bail!("Invalid S-Exp")
+            }
+
+            let _ = ctx.read()?;
+
+            let last = QuasiSExp::parse_level(ctx, level)?;
+
+            if let QuasiSExp::UnquoteSplicing(_) = last {
+                bail!("unquote-splicing is not allowed after '.'");
+            }
+
+            Some(last)
+        } else {
+            None
+        };
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            exps,
+            last,
+        })
+    }
+
+    fn parse_vector(ctx: &mut Context, level: usize) -> Result<Self> {
+        ctx.start();
+
+        ensure_symbol!(ctx, TokenKind::VectorOpen, "#(");
+
+        let mut exps = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            exps.push(QuasiSExp::parse_level(ctx, level)?);
+        }
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            exps,
+            last: None,
+        })
+    }
+
+    fn fold_vector(self) -> QuasiSExp {
+        if !self.exps.iter().all(|t| matches!(t, QuasiSExp::SExp(_))) {
+            return QuasiSExp::Vector(Box::new(self));
+        }
+
+        let unwrap = |t: QuasiSExp| match t {
+            QuasiSExp::SExp(s) => s,
+            _ => unreachable!(),
+        };
+
+        QuasiSExp::SExp(SExp::Vector(Box::new(Vector {
+            meta: self.meta,
+            exps: self.exps.into_iter().map(unwrap).collect(),
+        })))
+    }
+
+    fn fold(self) -> QuasiSExp {
+        let is_const = |t: &QuasiSExp| matches!(t, QuasiSExp::SExp(_));
+
+        if !self.exps.iter().all(is_const) || !self.last.iter().all(is_const) {
+            return QuasiSExp::Pair(Box::new(self));
+        }
+
+        let unwrap = |t: QuasiSExp| match t {
+            QuasiSExp::SExp(s) => s,
+            _ => unreachable!(),
+        };
+
+        QuasiSExp::SExp(SExp::Pair(Box::new(Pair {
+            meta: self.meta,
+            exps: self.exps.into_iter().map(unwrap).collect(),
+            last: self.last.map(unwrap),
+        })))
+    }
+}
+
+impl Parse for Set {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::Set, "set!");
+
+        let id: Id = Parse::parse(ctx)?;
+        ensure!(!ctx.is_import(&id.v, id.id_ctx), "Imported {} cannot be set!", id.v);
+
+        let exp = Parse::parse(ctx)?;
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            id,
+            exp,
+        })
+    }
+}
+
+impl Parse for Let {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::SyntaxRules, "syntax-rules");
+        ensure_symbol!(ctx, TokenKind::Let, "let");
 
-        ensure_paren_open!(ctx);
-        let keywords = Parse::parse(ctx)?;
-        ensure_paren_close!(ctx);
+        ctx.unreserve(0);
 
-        let syntax_rules = Parse::parse(ctx)?;
+        let id = if let TokenKind::Id(_) = ctx.peek(0)?.kind {
+            Some(Parse::parse(ctx)?)
+        } else {
+            None
+        };
 
-        ensure_paren_close!(ctx);
+        let bindings: Bindings = Parse::parse(ctx)?;
+        let body = Body::parse_scoped(ctx, id.iter().chain(bindings.ids()))?;
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
             id,
-            keywords,
-            syntax_rules,
+            bindings,
+            body,
         })
     }
 }
 
-impl Parse for SyntaxRule {
+impl Parse for LetAster {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        let syntax = ctx.read_next_chunk()?;
-        let template = ctx.read_next_chunk()?;
+        ensure_symbol!(ctx, TokenKind::LetAster, "let*");
+
+        let bindings: Bindings = Parse::parse(ctx)?;
+        let body = Body::parse_scoped(ctx, bindings.ids())?;
+
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            syntax,
-            template,
+            bindings,
+            body,
         })
     }
 }
 
-impl Parse for Load {
+impl Parse for LetRec {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::Load, "load");
 
-        let src = Parse::parse(ctx)?;
+        if !matches!(ctx.read()?.kind, TokenKind::LetRec | TokenKind::LetRecAster) {
+            bail!("'letrec' expected");
+        }
+
+        let bindings: Bindings = Parse::parse(ctx)?;
+        let body = Body::parse_scoped(ctx, bindings.ids())?;
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            src,
+            bindings,
+            body,
         })
     }
 }
 
-impl Parse for Define {
-    fn parse(ctx: &mut Context) -> Result<Self> {
-        if ctx.peek(2)?.kind == TokenKind::ParenOpen {
-            Ok(Self::Func(Parse::parse(ctx)?))
-        } else {
-            Ok(Self::Var(Parse::parse(ctx)?))
-        }
-    }
-}
-
-impl Parse for DefVar {
+impl Parse for LetValues {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::Define, "define");
+        ensure_symbol!(ctx, TokenKind::LetValues, "let-values");
 
-        let id = Parse::parse(ctx)?;
-        let exp = Parse::parse(ctx)?;
+        ensure_paren_open!(ctx);
+        let bindings: Vec<ValuesBinding> = Parse::parse(ctx)?;
+        ensure_paren_close!(ctx);
+
+        let body = Body::parse_scoped(ctx, bindings.iter().flat_map(|b| b.formals.ids()))?;
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            id,
-            exp,
+            bindings,
+            body,
         })
     }
 }
 
-impl Parse for DefFunc {
+impl Parse for LetAsterValues {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::Define, "define");
-        ensure_paren_open!(ctx);
-
-        let id = Parse::parse(ctx)?;
-
-        let mut args = vec![];
-
-        while ctx.peek(0)?.kind != TokenKind::Period && ctx.peek(0)?.kind != TokenKind::ParenClose {
-            args.push(Parse::parse(ctx)?);
-        }
-
-        let varg = if ctx.peek(0)?.kind == TokenKind::Period {
-            ensure_symbol!(ctx, TokenKind::Period, ".");
-            Some(Parse::parse(ctx)?)
-        } else {
-            None
-        };
+        ensure_symbol!(ctx, TokenKind::LetAsterValues, "let*-values");
 
+        ensure_paren_open!(ctx);
+        let bindings: Vec<ValuesBinding> = Parse::parse(ctx)?;
         ensure_paren_close!(ctx);
 
-        let body = Parse::parse(ctx)?;
+        let body = Body::parse_scoped(ctx, bindings.iter().flat_map(|b| b.formals.ids()))?;
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            id,
-            args,
-            varg,
+            bindings,
             body,
         })
     }
 }
 
-impl Parse for Exp {
+impl Parse for ValuesBinding {
     fn parse(ctx: &mut Context) -> Result<Self> {
-        match ctx.peek(0)?.kind {
-            TokenKind::ParenOpen => match ctx.peek(1)?.kind {
-                TokenKind::ParenClose => Ok(Self::Const(Parse::parse(ctx)?)),
-                TokenKind::Lambda => Ok(Self::Lambda(Box::new(Parse::parse(ctx)?))),
-                TokenKind::Quote => Ok(Self::Quote(Box::new(Parse::parse(ctx)?))),
-                TokenKind::Set => Ok(Self::Set(Box::new(Parse::parse(ctx)?))),
-                TokenKind::Let => Ok(Self::Let(Box::new(Parse::parse(ctx)?))),
-                TokenKind::LetAster => Ok(Self::LetAster(Box::new(Parse::parse(ctx)?))),
-                TokenKind::LetRec => Ok(Self::LetRec(Box::new(Parse::parse(ctx)?))),
-                TokenKind::If => Ok(Self::If(Box::new(Parse::parse(ctx)?))),
-                TokenKind::Cond => Ok(Self::Cond(Box::new(Parse::parse(ctx)?))),
-                TokenKind::And => Ok(Self::And(Box::new(Parse::parse(ctx)?))),
-                TokenKind::Or => Ok(Self::Or(Box::new(Parse::parse(ctx)?))),
-                TokenKind::Begin => Ok(Self::Begin(Box::new(Parse::parse(ctx)?))),
-                TokenKind::Do => Ok(Self::Do(Box::new(Parse::parse(ctx)?))),
-                _ => {
-                    let id = ctx.peek(1)?;
+        ctx.start();
 
-                    if let TokenKind::Id(id) = &id.kind {
-                        if ctx.is_macro(id) {
-                            return Self::expand_macro(ctx);
-                        }
-                    }
+        ensure_paren_open!(ctx);
 
-                    Ok(Self::Apply(Box::new(Parse::parse(ctx)?)))
-                }
-            },
-            TokenKind::SingleQuote => Ok(Self::Quote(Box::new(Parse::parse(ctx)?))),
+        let formals = Parse::parse(ctx)?;
+        let exp = Parse::parse(ctx)?;
 
-            TokenKind::Id(_) => Ok(Self::Id(Parse::parse(ctx)?)),
+        ensure_paren_close!(ctx);
 
-            TokenKind::Num(_) | TokenKind::Bool(_) | TokenKind::Str(_) => {
-                Ok(Self::Const(Parse::parse(ctx)?))
-            }
-            _ => panic!("Not Exp"), //bail!("Not Exp"),
-        }
+        Ok(Self {
+            meta: ctx.meta(),
+            formals,
+            exp,
+        })
     }
 }
 
-impl Parse for Lambda {
+impl Parse for Receive {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::Lambda, "lambda");
+        ensure_symbol!(ctx, TokenKind::Receive, "receive");
 
-        let arg = Parse::parse(ctx)?;
-        let body = Parse::parse(ctx)?;
+        let formals: Arg = Parse::parse(ctx)?;
+        let exp = Parse::parse(ctx)?;
+        let body = Body::parse_scoped(ctx, formals.ids())?;
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            arg,
+            formals,
+            exp,
             body,
         })
     }
 }
 
-impl Parse for Apply {
+impl Parse for If {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::If, "if");
 
-        let func = Parse::parse(ctx)?;
-
-        let mut exps = vec![];
+        let cond = Parse::parse(ctx)?;
+        let then = Parse::parse(ctx)?;
 
-        while ctx.peek(0)?.kind != TokenKind::ParenClose {
-            exps.push(Parse::parse(ctx)?);
-        }
+        let el = if ctx.peek(0)?.kind == TokenKind::ParenClose {
+            None
+        } else {
+            Some(Parse::parse(ctx)?)
+        };
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            func,
-            exps,
+            cond,
+            then,
+            el,
         })
     }
 }
 
-impl Parse for Quote {
+impl Parse for Cond {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
-        ctx.enter_quote();
 
-        let s_exp = if ctx.peek(0)?.kind == TokenKind::SingleQuote {
-            ensure_symbol!(ctx, TokenKind::SingleQuote, "'");
+        ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::Cond, "cond");
 
-            Parse::parse(ctx)?
-        } else {
-            ensure_paren_open!(ctx);
-            ensure_symbol!(ctx, TokenKind::Quote, "quote");
+        let mut matches = vec![];
+        let mut el = None;
 
-            let s_exp = Parse::parse(ctx)?;
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            if ctx.peek(1)?.kind == TokenKind::Else {
+                ensure_paren_open!(ctx);
+                ensure_symbol!(ctx, TokenKind::Else, "else");
 
-            ensure_paren_close!(ctx);
+                let mut exps = NonEmptyVec::new(Parse::parse(ctx)?);
 
-            s_exp
-        };
+                while ctx.peek(0)?.kind != TokenKind::ParenClose {
+                    exps.push(Parse::parse(ctx)?);
+                }
 
-        ctx.exit_quote();
+                el = Some(exps);
+
+                ensure_paren_close!(ctx);
+
+                break;
+            }
+
+            matches.push(Parse::parse(ctx)?);
+        }
+
+        ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            s_exp,
+            matches,
+            el,
         })
     }
 }
 
-impl Parse for Set {
+impl Parse for Match {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::Set, "set!");
 
-        let id = Parse::parse(ctx)?;
-        let exp = Parse::parse(ctx)?;
+        let cond = Parse::parse(ctx)?;
+        let then = Parse::parse(ctx)?;
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            id,
-            exp,
+            cond,
+            then,
         })
     }
 }
 
-impl Parse for Let {
+impl Parse for ClauseBody {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        if ctx.peek(0)?.kind == TokenKind::Arrow && !ctx.is_bound(ctx.peek(0)?) {
+            ensure_symbol!(ctx, TokenKind::Arrow, "=>");
+            return Ok(Self::Arrow(Parse::parse(ctx)?));
+        }
+
+        let mut exps = NonEmptyVec::new(Parse::parse(ctx)?);
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            exps.push(Parse::parse(ctx)?);
+        }
+
+        Ok(Self::Exps(exps))
+    }
+}
+
+impl Parse for Case {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::Let, "let");
+        ensure_symbol!(ctx, TokenKind::Case, "case");
 
-        let id = if let TokenKind::Id(_) = ctx.peek(0)?.kind {
-            Some(Parse::parse(ctx)?)
-        } else {
-            None
-        };
+        let key = Parse::parse(ctx)?;
+
+        let mut clauses = vec![];
+        let mut el = None;
 
-        let bindings = Parse::parse(ctx)?;
-        let body = Parse::parse(ctx)?;
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            if ctx.peek(1)?.kind == TokenKind::Else {
+                ensure_paren_open!(ctx);
+                ensure_symbol!(ctx, TokenKind::Else, "else");
+
+                el = Some(Parse::parse(ctx)?);
+
+                ensure_paren_close!(ctx);
+
+                break;
+            }
+
+            clauses.push(Parse::parse(ctx)?);
+        }
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            id,
-            bindings,
-            body,
+            key,
+            clauses,
+            el,
         })
     }
 }
 
-impl Parse for LetAster {
+impl Parse for CaseClause {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::LetAster, "let*");
+        ensure_paren_open!(ctx);
+
+        let mut data = vec![];
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            data.push(Parse::parse(ctx)?);
+        }
+
+        ensure_paren_close!(ctx);
 
-        let bindings = Parse::parse(ctx)?;
-        let body = Parse::parse(ctx)?;
+        let then = Parse::parse(ctx)?;
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            bindings,
-            body,
+            data,
+            then,
         })
     }
 }
 
-impl Parse for LetRec {
+impl Parse for When {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::LetRec, "letrec");
+        ensure_symbol!(ctx, TokenKind::When, "when");
+
+        let cond = Parse::parse(ctx)?;
 
-        let bindings = Parse::parse(ctx)?;
-        let body = Parse::parse(ctx)?;
+        let mut body = NonEmptyVec::new(Parse::parse(ctx)?);
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            body.push(Parse::parse(ctx)?);
+        }
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            bindings,
+            cond,
             body,
         })
     }
 }
 
-impl Parse for If {
+impl Parse for Unless {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::If, "if");
+        ensure_symbol!(ctx, TokenKind::Unless, "unless");
 
         let cond = Parse::parse(ctx)?;
-        let then = Parse::parse(ctx)?;
 
-        let el = if ctx.peek(0)?.kind == TokenKind::ParenClose {
-            None
-        } else {
-            Some(Parse::parse(ctx)?)
-        };
+        let mut body = NonEmptyVec::new(Parse::parse(ctx)?);
+
+        while ctx.peek(0)?.kind != TokenKind::ParenClose {
+            body.push(Parse::parse(ctx)?);
+        }
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
             cond,
-            then,
-            el,
+            body,
         })
     }
 }
 
-impl Parse for Cond {
+impl Parse for CaseLambda {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
-        ensure_symbol!(ctx, TokenKind::Cond, "cond");
+        ensure_symbol!(ctx, TokenKind::CaseLambda, "case-lambda");
 
-        let mut matches = vec![];
-        let mut el = None;
+        let mut clauses = vec![];
 
         while ctx.peek(0)?.kind != TokenKind::ParenClose {
-            if ctx.peek(1)?.kind == TokenKind::Else {
-                ensure_paren_open!(ctx);
-                ensure_symbol!(ctx, TokenKind::Else, "else");
-
-                let mut exps = NonEmptyVec::new(Parse::parse(ctx)?);
-
-                while ctx.peek(0)?.kind != TokenKind::ParenClose {
-                    exps.push(Parse::parse(ctx)?);
-                }
+            ctx.start();
 
-                el = Some(exps);
+            ensure_paren_open!(ctx);
 
-                ensure_paren_close!(ctx);
+            let arg: Arg = Parse::parse(ctx)?;
+            let body = Body::parse_scoped(ctx, arg.ids())?;
 
-                break;
-            }
+            ensure_paren_close!(ctx);
 
-            matches.push(Parse::parse(ctx)?);
+            clauses.push(Lambda {
+                meta: ctx.meta(),
+                arg,
+                body,
+            });
         }
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            matches,
-            el,
+            clauses,
         })
     }
 }
 
-impl Parse for Match {
+impl Parse for Assert {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
 
         ensure_paren_open!(ctx);
+        ensure_symbol!(ctx, TokenKind::Assert, "assert");
 
-        let cond = Parse::parse(ctx)?;
+        let chunk = ctx.read_next_chunk()?;
 
-        let mut then = NonEmptyVec::new(Parse::parse(ctx)?);
+        ctx.insert(chunk.clone());
+        let s_exp = Parse::parse(ctx)?;
 
-        while ctx.peek(0)?.kind != TokenKind::ParenClose {
-            then.push(Parse::parse(ctx)?);
-        }
+        ctx.insert(chunk);
+        let exp = Parse::parse(ctx)?;
 
         ensure_paren_close!(ctx);
 
         Ok(Self {
             meta: ctx.meta(),
-            cond,
-            then,
+            exp,
+            s_exp,
         })
     }
 }
@@ -614,7 +1860,11 @@ impl Parse for Do {
         }
         ensure_paren_close!(ctx);
 
-        let body = Parse::parse(ctx)?;
+        let body = if ctx.peek(0)?.kind == TokenKind::ParenClose {
+            None
+        } else {
+            Some(Body::parse_scoped(ctx, bindings.iter().map(|b: &DoBinding| &b.id))?)
+        };
 
         ensure_paren_close!(ctx);
 
@@ -651,14 +1901,47 @@ impl Parse for DoBinding {
 
 impl Parse for Body {
     fn parse(ctx: &mut Context) -> Result<Self> {
+        Self::parse_scoped(ctx, [])
+    }
+}
+
+impl Body {
+    fn parse_scoped<'a>(ctx: &mut Context, ids: impl IntoIterator<Item = &'a Id>) -> Result<Self> {
+        let scope = ctx.enter_scope();
+
+        for id in ids {
+            ctx.bind_var(id);
+        }
+
+        let body = Self::parse_in_scope(ctx, scope);
+
+        ctx.exit_scope();
+
+        body
+    }
+
+    fn parse_in_scope(ctx: &mut Context, scope: u32) -> Result<Self> {
         ctx.start();
 
         let mut defs = vec![];
 
-        while ctx.peek(0).map_or(false, |t| t.kind == TokenKind::ParenOpen)
-            && ctx.peek(1).map_or(false, |t| t.kind == TokenKind::Define)
-        {
-            defs.push(Parse::parse(ctx)?);
+        while ctx.peek(0).map_or(false, |t| t.kind == TokenKind::ParenOpen) {
+            ctx.unreserve_keywords()?;
+
+            if ctx.expand_macro_use()? {
+                continue;
+            }
+
+            match ctx.peek(1)?.kind {
+                TokenKind::DefineSyntax => {
+                    let syntax_def = DefineSyntax::parse(ctx)?;
+                    ctx.add_syntax_def(syntax_def);
+                }
+                TokenKind::Define | TokenKind::DefineValues | TokenKind::DefineRecordType => {
+                    defs.push(Parse::parse(ctx)?)
+                }
+                _ => break,
+            }
         }
 
         let mut exps = NonEmptyVec::new(Parse::parse(ctx)?);
@@ -669,12 +1952,28 @@ impl Parse for Body {
 
         Ok(Self {
             meta: ctx.meta(),
+            scope,
             defs,
             exps,
         })
     }
 }
 
+impl Arg {
+    fn ids(&self) -> Vec<&Id> {
+        match self {
+            Arg::Args(args) => args.args.iter().chain(args.varg.iter()).collect(),
+            Arg::VArg(id) => vec![id],
+        }
+    }
+}
+
+impl Bindings {
+    fn ids(&self) -> impl Iterator<Item = &Id> {
+        self.bindings.iter().map(|b| &b.id)
+    }
+}
+
 impl Parse for Arg {
     fn parse(ctx: &mut Context) -> Result<Self> {
         if ctx.peek(0)?.kind == TokenKind::ParenOpen {
@@ -756,8 +2055,47 @@ impl Parse for Binding {
 
 impl Parse for SExp {
     fn parse(ctx: &mut Context) -> Result<Self> {
+        if let Some(name) = crate::lexer::keyword_name(&ctx.peek(0)?.kind) {
+            let t = ctx.read()?;
+
+            return Ok(Self::Id(Id {
+                meta: t.meta,
+                id_ctx: 0,
+                v: name.into(),
+            }));
+        }
+
         match ctx.peek(0)?.kind {
             TokenKind::Id(_) => Ok(Self::Id(Parse::parse(ctx)?)),
+            TokenKind::Ellipsis => {
+                let t = ctx.read()?;
+
+                Ok(Self::Id(Id {
+                    meta: t.meta,
+                    id_ctx: 0,
+                    v: "...".into(),
+                }))
+            }
+            TokenKind::SingleQuote
+            | TokenKind::Backquote
+            | TokenKind::Comma
+            | TokenKind::CommaAt => {
+                ctx.start();
+
+                let t = ctx.read()?;
+                let quote = SExp::Id(Id {
+                    v: crate::lexer::keyword_name(&t.kind.abbreviation().unwrap()).unwrap().into(),
+                    meta: t.meta,
+                    id_ctx: 0,
+                });
+                let s_exp = Parse::parse(ctx)?;
+
+                Ok(Self::Pair(Box::new(Pair {
+                    meta: ctx.meta(),
+                    exps: vec![quote, s_exp],
+                    last: None,
+                })))
+            }
             TokenKind::Num(_) | TokenKind::Bool(_) | TokenKind::Str(_) => {
                 Ok(Self::Const(Parse::parse(ctx)?))
             }
@@ -768,11 +2106,29 @@ impl Parse for SExp {
                     Ok(Self::Pair(Box::new(Parse::parse(ctx)?)))
                 }
             }
+            TokenKind::VectorOpen => Ok(Self::Vector(Box::new(Parse::parse(ctx)?))),
             _ => bail!("Not S-Exp"),
         }
     }
 }
 
+impl Parse for Vector {
+    fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.start();
+
+        ensure_symbol!(ctx, TokenKind::VectorOpen, "#(");
+
+        let exps = Parse::parse(ctx)?;
+
+        ensure_paren_close!(ctx);
+
+        Ok(Self {
+            meta: ctx.meta(),
+            exps,
+        })
+    }
+}
+
 impl Parse for Pair {
     fn parse(ctx: &mut Context) -> Result<Self> {
         ctx.start();
@@ -787,7 +2143,7 @@ impl Parse for Pair {
 
         let last = if ctx.peek(0)?.kind == TokenKind::Period {
             if exps.len() == 0 {
-                panic!("Invalid S-Exp")
+                bail!("Invalid S-Exp")
             }
 
             let _ = ctx.read()?;
@@ -867,11 +2223,12 @@ impl Parse for Null {
 
 impl Parse for Id {
     fn parse(ctx: &mut Context) -> Result<Self> {
+        ctx.unreserve(0);
+
         let t = ctx.read()?;
 
         let TokenKind::Id(id) = t.kind else {
-            panic!("Not Id")
-            //bail!("Not Id")
+            bail!("Not Id")
         };
 
         let id_ctx = if ctx.is_quoted() { 0 } else { t.meta.id_ctx };
@@ -886,15 +2243,7 @@ impl Parse for Id {
 
 impl Exp {
     fn expand_macro(ctx: &mut Context) -> Result<Self> {
-        let macro_id = {
-            let TokenKind::Id(macro_id) = &ctx.peek(1)?.kind else {
-                bail!("Not Macro")
-            };
-
-            macro_id.clone()
-        };
-
-        let expanded = ctx.expand_macro(macro_id)?;
+        let expanded = ctx.expand_macro()?;
 
         ctx.insert(expanded);
 
@@ -902,84 +2251,62 @@ impl Exp {
     }
 }
 
-impl DefineSyntax {
-    fn is_keyword(&self, id: &String) -> bool {
-        self.keywords.iter().find(|k| &k.v == id).is_some()
-    }
-}
-
-impl SyntaxRule {
-    fn try_match(
-        &self,
-        def: &DefineSyntax,
-        ctx: &mut Context,
-    ) -> Result<HashMap<String, Vec<Token>>> {
-        let mut reps = HashMap::<String, Vec<Token>>::new();
-
-        for t in &self.syntax {
-            match &t.kind {
-                TokenKind::ParenOpen | TokenKind::ParenClose | TokenKind::Period => {
-                    if ctx.read()?.kind != t.kind {
-                        bail!("Invalid syntax");
-                    }
-                }
-                TokenKind::Id(id) => {
-                    if id == "_" {
-                        ctx.read_next_chunk()?;
-                        continue;
-                    }
-
-                    if def.is_keyword(id) {
-                        if let TokenKind::Id(t) = ctx.read()?.kind {
-                            if id != &t {
-                                bail!("Invalid syntax");
-                            }
-                        } else {
-                            bail!("Invalid syntax");
-                        }
-
-                        continue;
-                    }
-
-                    reps.insert(id.clone(), ctx.read_next_chunk()?);
-                }
-                TokenKind::Ellipsis => {
-                    let mut rep = vec![];
-
-                    while ctx.peek(0)?.kind != TokenKind::ParenClose {
-                        rep.extend(ctx.read_next_chunk()?);
-                    }
-
-                    reps.insert("...".into(), rep);
-                }
-                _ => {
-                    bail!("Invalid syntax");
-                }
-            }
-        }
-
-        Ok(reps)
-    }
-}
-
 mod ctx {
     use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
     use anyhow::{Context as _, ensure, Result};
     use crate::lexer::Meta;
+    use crate::obj::Number;
+    use crate::vm::VM;
     use super::*;
 
-    #[derive(Clone)]
     pub struct Context {
         tokens: Vec<Token>,
         i: usize,
         parse_origins: Vec<usize>,
 
-        syntax_defs: HashMap<String, DefineSyntax>,
+        syntax_scopes: Vec<(u32, SyntaxScope)>,
+        scope_cnt: u32,
 
-        id_ctxs: Vec<u32>,
+        id_ctxs: Vec<IdCtx>,
         id_ctx_cnt: u32,
 
+        expander: Option<Box<VM>>,
+        transformer_cnt: u32,
+        budget: Budget,
+
+        trace: Option<Vec<Token>>,
+
+        libraries: HashMap<String, Vec<(String, Export)>>,
+        library_syntax: HashMap<u32, SyntaxScope>,
+        library_files: Vec<(PathBuf, String)>,
+        search_paths: Vec<PathBuf>,
+        file: Option<PathBuf>,
+        id_ctx_parents: HashMap<u32, (u32, u32)>,
+
         is_quoted: bool,
+        is_sandboxed: bool,
+    }
+
+    // Bindings of each name in a scope along with the context of the identifier they bind.
+    // Variables are recorded too because they shadow macros of the same name.
+    pub type SyntaxScope = HashMap<String, Vec<(u32, ScopeBinding)>>;
+
+    #[derive(Clone)]
+    pub enum ScopeBinding {
+        Var,
+        Import,
+        Syntax(DefineSyntax),
+    }
+
+    fn binding_in<'a>(defs: &'a SyntaxScope, id: &str, id_ctx: u32) -> Option<&'a ScopeBinding> {
+        defs.get(id)?.iter().rfind(|(ctx, _)| *ctx == id_ctx).map(|(_, def)| def)
+    }
+
+    #[derive(Clone)]
+    pub enum Export {
+        Var(Id),
+        Syntax(DefineSyntax),
     }
 
     impl Context {
@@ -989,12 +2316,27 @@ mod ctx {
                 i: 0,
                 parse_origins: vec![],
 
-                syntax_defs: Default::default(),
+                syntax_scopes: vec![(0, Default::default())],
+                scope_cnt: 0,
 
-                id_ctxs: vec![0],
+                id_ctxs: vec![],
                 id_ctx_cnt: 0,
 
+                expander: None,
+                transformer_cnt: 0,
+                budget: Budget::default(),
+
+                trace: None,
+
+                libraries: Default::default(),
+                library_syntax: Default::default(),
+                library_files: vec![],
+                search_paths: vec![],
+                file: None,
+                id_ctx_parents: Default::default(),
+
                 is_quoted: false,
+                is_sandboxed: false,
             }
         }
 
@@ -1003,17 +2345,23 @@ mod ctx {
         }
 
         pub fn read(&mut self) -> Result<Token> {
-            ensure!(self.i < self.tokens.len(), "");
+            ensure!(self.i < self.tokens.len(), "Unexpected end of input");
 
             self.i += 1;
 
-            Ok(self.tokens[self.i - 1].clone())
+            let t = self.tokens[self.i - 1].clone();
+
+            if let Some(trace) = &mut self.trace {
+                trace.push(t.clone());
+            }
+
+            Ok(t)
         }
 
         pub fn peek(&self, n: isize) -> Result<&Token> {
             ensure!(
                 0 <= self.i as isize + n && self.i as isize + n < self.tokens.len() as isize,
-                ""
+                "Unexpected end of input"
             );
 
             Ok(&self.tokens[(self.i as isize + n) as usize])
@@ -1021,8 +2369,11 @@ mod ctx {
 
         pub fn read_next_chunk(&mut self) -> Result<Vec<Token>> {
             match self.peek(0)?.kind {
-                TokenKind::ParenOpen => (),
-                TokenKind::SingleQuote => {
+                TokenKind::ParenOpen | TokenKind::VectorOpen => (),
+                TokenKind::SingleQuote
+                | TokenKind::Backquote
+                | TokenKind::Comma
+                | TokenKind::CommaAt => {
                     return Ok(vec![vec![self.read()?], self.read_next_chunk()?].concat())
                 }
                 _ => return Ok(vec![self.read()?]),
@@ -1036,7 +2387,7 @@ mod ctx {
                 let t = self.read()?;
 
                 match &t.kind {
-                    TokenKind::ParenOpen => paren_stack += 1,
+                    TokenKind::ParenOpen | TokenKind::VectorOpen => paren_stack += 1,
                     TokenKind::ParenClose => paren_stack -= 1,
                     _ => (),
                 };
@@ -1066,110 +2417,427 @@ mod ctx {
             self.i < self.tokens.len()
         }
 
+        pub fn discard(&mut self) {
+            self.i = self.tokens.len();
+            self.parse_origins.clear();
+            self.syntax_scopes.truncate(1);
+        }
+
         pub fn insert(&mut self, tokens: Vec<Token>) {
             self.tokens.splice(self.i..self.i, tokens);
         }
 
+        pub fn enter_scope(&mut self) -> u32 {
+            self.scope_cnt += 1;
+            self.syntax_scopes.push((self.scope_cnt, Default::default()));
+
+            self.scope_cnt
+        }
+
+        pub fn exit_scope(&mut self) -> SyntaxScope {
+            self.syntax_scopes.pop().map(|(_, defs)| defs).unwrap_or_default()
+        }
+
         pub fn add_syntax_def(&mut self, def: DefineSyntax) {
-            self.syntax_defs.insert(def.id.v.clone(), def);
+            self.bind_syntax(&def.id.clone(), def);
+        }
+
+        pub fn bind_syntax(&mut self, id: &Id, def: DefineSyntax) {
+            self.bind(id, ScopeBinding::Syntax(def));
+        }
+
+        pub fn bind_var(&mut self, id: &Id) {
+            self.bind(id, ScopeBinding::Var);
         }
 
-        pub fn is_macro(&self, id: &String) -> bool {
-            self.syntax_defs.iter().find(|i| i.0 == id).is_some()
+        pub fn bind_import(&mut self, id: &Id) {
+            self.bind(id, ScopeBinding::Import);
         }
 
-        pub fn expand_macro(&mut self, id: String) -> Result<Vec<Token>> {
-            let def = {
-                self.syntax_defs
+        fn bind(&mut self, id: &Id, binding: ScopeBinding) {
+            let (_, scope) = self.syntax_scopes.last_mut().unwrap();
+            let bindings = scope.entry(id.v.clone()).or_default();
+
+            bindings.retain(|(ctx, _)| *ctx != id.id_ctx);
+            bindings.push((id.id_ctx, binding));
+        }
+
+        // Resolves like a variable reference: an identifier introduced by a macro first looks
+        // for bindings from the same expansion, then for the ones visible where the macro was
+        // defined. Library bodies only see their own definitions and imports, and macros
+        // private to a library are only visible to identifiers from it.
+        fn find_binding(&self, id: &str, id_ctx: u32) -> Option<(u32, &ScopeBinding)> {
+            let mut id_ctx = id_ctx;
+            let mut visible = self.syntax_scopes.len();
+
+            loop {
+                let found = self.syntax_scopes[..visible]
                     .iter()
-                    .find(|i| i.0 == &id)
-                    .context(format!("Macro {} is not defined", id))?
-                    .1
-                    .clone()
+                    .rev()
+                    .find_map(|(scope, defs)| binding_in(defs, id, id_ctx).map(|def| (*scope, def)))
+                    .or_else(|| {
+                        Some((0, binding_in(self.library_syntax.get(&id_ctx)?, id, id_ctx)?))
+                    });
+
+                if found.is_some() {
+                    return found;
+                }
+
+                if id_ctx == 0 {
+                    return None;
+                }
+
+                let (parent, scope) = *self.id_ctx_parents.get(&id_ctx)?;
+
+                if parent == id_ctx {
+                    return None;
+                }
+
+                id_ctx = parent;
+
+                if let Some(i) = self.syntax_scopes.iter().position(|(s, _)| *s == scope) {
+                    visible = visible.min(i + 1);
+                }
+            }
+        }
+
+        fn find_macro(&self, id: &str, id_ctx: u32) -> Option<(u32, &DefineSyntax)> {
+            match self.find_binding(id, id_ctx)? {
+                (scope, ScopeBinding::Syntax(def)) => Some((scope, def)),
+                _ => None,
+            }
+        }
+
+        pub fn is_macro(&self, id: &str, id_ctx: u32) -> bool {
+            self.find_macro(id, id_ctx).is_some()
+        }
+
+        pub fn is_bound(&self, t: &Token) -> bool {
+            t.kind.identifier().is_some_and(|id| self.find_binding(id, t.meta.id_ctx).is_some())
+        }
+
+        // Contextual keywords only start a form in operator position, and not when a binding
+        // of the same name is in scope. Anywhere else they are identifiers.
+        pub fn unreserve_keywords(&mut self) -> Result<()> {
+            if self.peek(0)?.kind != TokenKind::ParenOpen {
+                self.unreserve(0);
+            } else if self.peek(1).is_ok_and(|t| self.is_bound(t)) {
+                self.unreserve(1);
+            }
+
+            Ok(())
+        }
+
+        pub fn unreserve(&mut self, n: usize) {
+            if let Some(t) = self.tokens.get_mut(self.i + n) {
+                if t.kind.is_contextual() {
+                    t.kind = TokenKind::Id(t.kind.identifier().unwrap().to_string());
+                }
+            }
+        }
+
+        pub fn is_import(&self, id: &str, id_ctx: u32) -> bool {
+            matches!(self.find_binding(id, id_ctx), Some((_, ScopeBinding::Import)))
+        }
+
+        pub fn expand_macro_use(&mut self) -> Result<bool> {
+            match (&self.peek(0)?.kind, self.peek(1)) {
+                (
+                    TokenKind::ParenOpen,
+                    Ok(Token {
+                        meta,
+                        kind: TokenKind::Id(id),
+                    }),
+                ) if self.is_macro(id, meta.id_ctx) => (),
+                _ => return Ok(false),
             };
 
-            self.enter_new_id_ctx();
+            let expanded = self.expand_macro()?;
+            self.insert(expanded);
 
-            for rule in &def.syntax_rules {
-                let mut ctx = self.clone();
+            Ok(true)
+        }
 
-                let Ok(reps) = rule.try_match(&def, &mut ctx) else {
-                    continue;
-                };
+        pub fn expand_macro(&mut self) -> Result<Vec<Token>> {
+            let Token {
+                meta,
+                kind: TokenKind::Id(id),
+            } = self.peek(1)?.clone()
+            else {
+                bail!("Not Macro")
+            };
 
-                *self = ctx;
+            let trace = self.trace_len();
+            let form = TokenTree::parse(&self.read_next_chunk()?)?;
+            self.untrace(trace);
 
-                let mut expanded = vec![];
+            let mut tokens = vec![];
+            self.expand_form(&id, meta.id_ctx, &form)?.flatten(&mut tokens);
 
-                let mut quote_paren_stack = 0;
-                let mut is_quote = false;
+            Ok(tokens)
+        }
 
-                for t in &rule.template {
-                    if t.kind == TokenKind::Quote {
-                        is_quote = true;
-                        quote_paren_stack = 1;
-                    }
+        pub fn expand_form(
+            &mut self,
+            id: &String,
+            id_ctx: u32,
+            form: &TokenTree,
+        ) -> Result<TokenTree> {
+            let (scope, def) = self
+                .find_macro(id, id_ctx)
+                .map(|(scope, def)| (scope, def.clone()))
+                .context(format!("Macro {} is not defined", id))?;
+
+            match &def.transformer {
+                Transformer::SyntaxRules(rules) => {
+                    let mut marks = HashMap::new();
+
+                    rules.expand(form, &mut |id_ctx| {
+                        *marks.entry(id_ctx).or_insert_with(|| self.new_id_ctx(id_ctx, scope))
+                    })
+                }
+                Transformer::ErMacro(transformer) => {
+                    let mark = self.new_id_ctx(def.id.id_ctx, scope);
 
-                    let is_single_quote = t.kind == TokenKind::SingleQuote;
+                    self.run_transformer(transformer, form, mark)
+                }
+            }
+            .with_context(|| format!("Failed to expand {}", id))
+        }
 
-                    if is_single_quote {
-                        is_quote = true;
-                        quote_paren_stack = 0;
-                    }
+        pub fn define_transformer(&mut self, exp: Vec<Token>) -> Result<String> {
+            self.transformer_cnt += 1;
 
-                    if is_quote {
-                        if t.kind == TokenKind::ParenOpen {
-                            quote_paren_stack += 1;
-                        } else if t.kind == TokenKind::ParenClose {
-                            quote_paren_stack -= 1;
-                        }
-                    }
+            let name = format!(" transformer{}", self.transformer_cnt);
 
-                    if !is_single_quote && quote_paren_stack == 0 {
-                        is_quote = false;
-                    }
+            let tokens = [
+                vec![
+                    token(TokenKind::ParenOpen),
+                    token(TokenKind::Define),
+                    token(TokenKind::Id(name.clone())),
+                ],
+                exp,
+                vec![token(TokenKind::ParenClose)],
+            ]
+            .concat();
 
-                    if !is_quote {
-                        if let TokenKind::Id(id) = &t.kind {
-                            if let Some(rep) = reps.get(id) {
-                                expanded.extend(rep.clone());
-                                continue;
-                            }
-                        }
+            self.run_expander(tokens).context("Failed to define a transformer")?;
 
-                        if &TokenKind::Ellipsis == &t.kind {
-                            if let Some(rep) = reps.get("...") {
-                                expanded.extend(rep.clone());
-                                continue;
-                            }
-                        }
-                    }
+            Ok(name)
+        }
+
+        fn run_transformer(
+            &mut self,
+            name: &str,
+            form: &TokenTree,
+            mark: u32,
+        ) -> Result<TokenTree> {
+            self.expander()?.define(" form", form.to_obj()?);
+
+            let res = self.run_expander(vec![
+                token(TokenKind::ParenOpen),
+                token(TokenKind::Id(" er-expand".to_string())),
+                token(TokenKind::Id(name.to_string())),
+                token(TokenKind::Id(" form".to_string())),
+                token(TokenKind::Num(Number::Int(mark as i64))),
+                token(TokenKind::ParenClose),
+            ])?;
+
+            self.expander()?.release(&res);
+
+            TokenTree::parse(&get_tokens_from_obj(&res)?)
+        }
+
+        fn run_expander(&mut self, tokens: Vec<Token>) -> Result<Obj> {
+            let mut budget = std::mem::take(&mut self.budget);
+            let res =
+                self.expander().and_then(|expander| expander.exec_tokens(tokens, &mut budget));
+            self.budget = budget;
+
+            res
+        }
+
+        fn expander(&mut self) -> Result<&mut VM> {
+            if self.expander.is_none() {
+                let mut vm = VM::new();
+                vm.load_prelude(crate::prelude())?;
+
+                // Calls a transformer with renaming and comparison procedures for its expansion.
+                let er_expand = crate::lexer::get_tokens(
+                    "(lambda (transformer form mark)
+                       (transformer
+                         form
+                         (lambda (id) (~rename id mark))
+                         (lambda (l r) (equal? (symbol->string l) (symbol->string r)))))"
+                        .to_string(),
+                    false,
+                )?;
+
+                let tokens = [
+                    vec![
+                        token(TokenKind::ParenOpen),
+                        token(TokenKind::Define),
+                        token(TokenKind::Id(" er-expand".to_string())),
+                    ],
+                    er_expand,
+                    vec![token(TokenKind::ParenClose)],
+                ]
+                .concat();
+
+                vm.exec_tokens(tokens, &mut Default::default())?;
+
+                self.expander = Some(Box::new(vm));
+            }
+
+            Ok(self.expander.as_mut().unwrap())
+        }
+
+        fn new_id_ctx(&mut self, parent: u32, scope: u32) -> u32 {
+            self.id_ctx_cnt += 1;
+            self.id_ctx_parents.insert(self.id_ctx_cnt, (parent, scope));
+            self.id_ctxs.push(IdCtx {
+                id: self.id_ctx_cnt,
+                parent,
+                scope,
+            });
+
+            self.id_ctx_cnt
+        }
 
-                    let mut t = t.clone();
-                    t.meta.id_ctx = self.get_id_ctx();
+        // A library context is its own parent, so that names in the library body resolve only
+        // against the library's definitions and imports.
+        pub fn new_library_ctx(&mut self) -> u32 {
+            self.new_id_ctx(self.id_ctx_cnt + 1, 0)
+        }
+
+        pub fn mark_next_chunk(&mut self, id_ctx: u32) -> Result<()> {
+            let trace = self.trace_len();
+            let mut tokens = self.read_next_chunk()?;
+            self.untrace(trace);
 
-                    expanded.push(t);
+            for t in &mut tokens {
+                if t.meta.id_ctx == 0 {
+                    t.meta.id_ctx = id_ctx;
                 }
+            }
+
+            self.insert(tokens);
+
+            Ok(())
+        }
+
+        pub fn add_library(
+            &mut self,
+            name: Vec<String>,
+            id_ctx: u32,
+            defs: SyntaxScope,
+            exports: Vec<(Id, String)>,
+        ) {
+            let exports = exports
+                .into_iter()
+                .map(|(id, name)| match defs.get(&id.v).and_then(|defs| defs.last()) {
+                    Some((_, ScopeBinding::Syntax(def))) => (name, Export::Syntax(def.clone())),
+                    _ => (name, Export::Var(id)),
+                })
+                .collect();
+
+            self.libraries.insert(format!("({})", name.join(" ")), exports);
+            self.library_syntax.insert(id_ctx, defs);
+        }
 
-                self.exit_cur_id_ctx();
+        pub fn library_exports(&self, key: &str) -> Option<Vec<(String, Export)>> {
+            self.libraries.get(key).cloned()
+        }
+
+        pub fn find_library(&self, name: &[String]) -> Option<PathBuf> {
+            self.resolve_path(&format!("{}.sld", name.join("/")))
+        }
+
+        pub fn resolve_path(&self, file: &str) -> Option<PathBuf> {
+            let file = Path::new(file);
 
-                return Ok(expanded);
+            if file.is_absolute() {
+                return Some(file.to_path_buf()).filter(|path| path.is_file());
             }
 
-            bail!("Invalid syntax")
+            std::iter::once(self.base_dir())
+                .chain(self.search_paths.iter().cloned())
+                .chain(std::iter::once(PathBuf::from(".")))
+                .map(|dir| dir.join(file))
+                .find(|path| path.is_file())
         }
 
-        pub fn get_id_ctx(&self) -> u32 {
-            *self.id_ctxs.last().unwrap()
+        fn base_dir(&self) -> PathBuf {
+            match (self.library_files.last(), &self.file) {
+                (Some((dir, _)), _) => dir.clone(),
+                (None, Some(file)) => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+                (None, None) => PathBuf::from("."),
+            }
         }
 
-        pub fn enter_new_id_ctx(&mut self) {
-            self.id_ctx_cnt += 1;
-            self.id_ctxs.push(self.id_ctx_cnt);
+        pub fn set_file(&mut self, file: Option<PathBuf>) -> Option<PathBuf> {
+            std::mem::replace(&mut self.file, file)
+        }
+
+        pub fn swap_budget(&mut self, budget: &mut Budget) {
+            std::mem::swap(&mut self.budget, budget);
         }
 
-        pub fn exit_cur_id_ctx(&mut self) {
-            let _ = self.id_ctxs.pop();
+        pub fn add_search_path(&mut self, dir: PathBuf) {
+            self.search_paths.push(dir);
+        }
+
+        pub fn enter_library_file(&mut self, path: &Path, key: &str) -> Result<()> {
+            ensure!(self.library_files.iter().all(|(_, k)| k != key), "Circular import of {}", key);
+
+            let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            self.library_files.push((dir, key.to_string()));
+
+            Ok(())
+        }
+
+        pub fn exit_library_file(&mut self) {
+            let _ = self.library_files.pop();
+        }
+
+        pub fn read_include(&self, file: &str, id_ctx: u32) -> Result<Vec<Token>> {
+            let path = self.base_dir().join(file);
+
+            let src = std::fs::read_to_string(&path)
+                .context(format!("Failed to open {}", path.display()))?;
+            let mut tokens = crate::lexer::get_tokens(src, true).context("Failed to tokenize")?;
+
+            for t in &mut tokens {
+                t.meta.id_ctx = id_ctx;
+            }
+
+            Ok(tokens)
+        }
+
+        pub fn remaining(&self) -> usize {
+            self.tokens.len() - self.i
+        }
+
+        pub fn take_id_ctxs(&mut self) -> Vec<IdCtx> {
+            std::mem::take(&mut self.id_ctxs)
+        }
+
+        pub fn start_trace(&mut self) {
+            self.trace = Some(vec![]);
+        }
+
+        pub fn take_trace(&mut self) -> Vec<Token> {
+            self.trace.take().unwrap_or_default()
+        }
+
+        pub fn trace_len(&self) -> usize {
+            self.trace.as_ref().map_or(0, Vec::len)
+        }
+
+        pub fn untrace(&mut self, len: usize) {
+            if let Some(trace) = &mut self.trace {
+                trace.truncate(len);
+            }
         }
 
         pub fn enter_quote(&mut self) {
@@ -1183,5 +2851,20 @@ mod ctx {
         pub fn is_quoted(&self) -> bool {
             self.is_quoted
         }
+
+        pub fn set_sandboxed(&mut self, is_sandboxed: bool) {
+            self.is_sandboxed = is_sandboxed;
+        }
+
+        pub fn is_sandboxed(&self) -> bool {
+            self.is_sandboxed
+        }
+    }
+
+    fn token(kind: TokenKind) -> Token {
+        Token {
+            meta: Default::default(),
+            kind,
+        }
     }
 }
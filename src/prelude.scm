@@ -1,104 +1,908 @@
-(define (list . l)
-  (if (null? l)
-    '()
-    (cons (car l)
-      (apply list (cdr l)))))
-
-(define (length l)
-  (if (null? l)
-    0
-    (+ 1 (length (cdr l)))))
-
-(define (memq a l)
-  (cond
-    ((null? l) #f)
-    ((eq? a (car l)) l)
-    (else (memq a (cdr l)))))
-
-(define (last l)
-  (if (pair? (cdr l))
-    (last (cdr l))
-    (car l)))
-
-(define (append . lists)
-  (if (null? lists)
-    '()
-    (if (null? (car lists))
-      (apply append (cdr lists))
-      (cons (car (car lists)) (apply append (cdr (car lists)) (cdr lists))))))
-
-(define (neq? l r) (not (eq? l r)))
-
-(define (newline) (display "\n"))
-
-(define (+ . a)
-  (if (null? a)
-    0
-    (~+ (car a) (apply + (cdr a)))))
-
-(define (- . a)
-  (cond
-    ((null? a) 0)
-    ((null? (cdr a)) (~- 0 (car a)))
-    (else (~- (car a) (apply + (cdr a))))))
-
-(define (* . a)
-  (if (null? a)
-    1
-    (~* (car a) (apply * (cdr a)))))
-
-(define (/ . a)
-  (cond
-    ((null? a) '())
-    ((null? (cdr a)) (~/ 1 (car a)))
-    (else (~/ (car a) (apply * (cdr a))))))
-
-(define (= . a)
-  (if (~> 2 (length a))
-    '()
-    (and
-      (~= (car a) (car (cdr a)))
-      (if (~= 1 (length (cdr a)))
-        #t
-        (apply = (cdr a))))))
-
-(define (< . a)
-  (if (~> 2 (length a))
-    '()
-    (and
-      (~< (car a) (car (cdr a)))
-      (if (~= 1 (length (cdr a)))
-        #t
-        (apply < (cdr a))))))
-
-(define (<= . a)
-  (if (~> 2 (length a))
-    '()
-    (and
-      (~<= (car a) (car (cdr a)))
-      (if (~= 1 (length (cdr a)))
-        #t
-        (apply <= (cdr a))))))
-
-(define (> . a)
-  (if (~> 2 (length a))
-    '()
-    (and
-      (~> (car a) (car (cdr a)))
-      (if (~= 1 (length (cdr a)))
-        #t
-        (apply > (cdr a))))))
-
-(define (>= . a)
-  (if (~> 2 (length a))
-    '()
-    (and
-      (~>= (car a) (car (cdr a)))
-      (if (~= 1 (length (cdr a)))
-        #t
-        (apply >= (cdr a))))))
-
-(define (string-append . a)
-  (if (null? a)
-    ""
-    (~string-append (car a) (apply string-append (cdr a)))))
+(define-library (scheme base)
+  (export not cons car cdr set-car! set-cdr! null? pair? number? boolean? string? proc? symbol?
+          vector? vector-length vector-ref vector-set! vector->list list->vector eq? equal?
+          symbol->string string->symbol string->number number->string
+          macroexpand macroexpand-1
+          list length reverse list-tail list-ref list-copy last append append!
+          memq memv member assq assv assoc iota map for-each fold-left fold-right
+          reduce filter partition delete delete-duplicates find any every
+          sort sort! list-sort vector-sort merge
+          delay delay-force force make-promise promise?
+          stream-null stream-cons stream? stream-null? stream-pair? stream-car stream-cdr
+          list->stream stream->list stream-from stream-ref stream-take stream-drop
+          stream-map stream-filter stream-for-each
+          eof-object eof-object? make-generator make-coroutine-generator generator
+          list->generator vector->generator make-iota-generator make-range-generator
+          gcons* gappend gmap gfilter gremove gtake gdrop gtake-while gdrop-while
+          generator->list generator->reverse-list generator->vector generator-fold
+          generator-for-each generator-find generator-count generator-any generator-every
+          make-accumulator count-accumulator list-accumulator reverse-list-accumulator
+          vector-accumulator sum-accumulator product-accumulator
+          spawn yield sleep thread-join thread? current-thread
+          make-channel channel? channel-send channel-receive
+          call-with-current-continuation call/cc dynamic-wind make-parameter parameterize
+          port? output-port? current-output-port open-output-string get-output-string
+          write-string display write write-shared newline
+          values call-with-values neq?
+          + - * / = < <= > >= string-append vector
+          eval interaction-environment scheme-report-environment null-environment)
+  (begin
+    ; Calls of these compile to instructions; the definitions make them procedures as well.
+    (define (not x) (not x))
+    (define (cons a d) (cons a d))
+    (define (car p) (car p))
+    (define (cdr p) (cdr p))
+    (define (set-car! p x) (set-car! p x))
+    (define (set-cdr! p x) (set-cdr! p x))
+    (define (null? x) (null? x))
+    (define (pair? x) (pair? x))
+    (define (number? x) (number? x))
+    (define (boolean? x) (boolean? x))
+    (define (string? x) (string? x))
+    (define (proc? x) (proc? x))
+    (define (symbol? x) (symbol? x))
+    (define (vector? x) (vector? x))
+    (define (vector-length v) (vector-length v))
+    (define (vector-ref v k) (vector-ref v k))
+    (define (vector-set! v k x) (vector-set! v k x))
+    (define (vector->list v) (vector->list v))
+    (define (list->vector l) (list->vector l))
+    (define (eq? a b) (eq? a b))
+    (define (equal? a b) (equal? a b))
+    (define (symbol->string s) (symbol->string s))
+    (define (string->symbol s) (string->symbol s))
+    (define (string->number s) (string->number s))
+    (define (number->string n) (number->string n))
+    (define (macroexpand form) (macroexpand form))
+    (define (macroexpand-1 form) (macroexpand-1 form))
+
+    (define (list . l) l)
+
+    (define (length l) (~length l))
+
+    (define (reverse l) (~reverse l))
+
+    (define (list-tail l k) (~list-tail l k))
+
+    (define (list-ref l k) (car (~list-tail l k)))
+
+    (define (list-copy l) (~list-copy l))
+
+    (define (last l)
+      (if (pair? (cdr l))
+        (last (cdr l))
+        (car l)))
+
+    (define (append . lists)
+      (if (null? lists)
+        '()
+        (let loop ((lists (cdr (~reverse lists))) (acc (car (~reverse lists))))
+          (if (pair? lists)
+            (loop (cdr lists) (~append (car lists) acc))
+            acc))))
+
+    (define (append! . lists) (~append! lists))
+
+    (define (memq x l) (~memq x l))
+
+    (define (memv x l) (~memv x l))
+
+    (define (member x l . compare)
+      (if (null? compare)
+        (~member x l)
+        (let loop ((l l))
+          (cond
+            ((null? l) #f)
+            (((car compare) x (car l)) l)
+            (else (loop (cdr l)))))))
+
+    (define (assq x l) (~assq x l))
+
+    (define (assv x l) (~assv x l))
+
+    (define (assoc x l . compare)
+      (if (null? compare)
+        (~assoc x l)
+        (find (lambda (entry) ((car compare) x (car entry))) l)))
+
+    (define (iota count . rest)
+      (if (null? rest)
+        (~iota count 0 1)
+        (~iota count (car rest) (if (null? (cdr rest)) 1 (car (cdr rest))))))
+
+    ; map and for-each stay in Scheme, unlike the list primitives, so that continuations
+    ; and coroutine yields can leave and reenter the procedures they call. Native calls
+    ; back into the VM cannot be resumed that way.
+    (define (map f l . ls)
+      (if (null? ls)
+        (let loop ((l l) (acc '()))
+          (if (pair? l)
+            (loop (cdr l) (cons (f (car l)) acc))
+            (~reverse acc)))
+        (let loop ((ls (cons l ls)) (acc '()))
+          (let ((args (~cars ls)))
+            (if args
+              (loop (~cdrs ls) (cons (apply f args) acc))
+              (~reverse acc))))))
+
+    (define (for-each f l . ls)
+      (if (null? ls)
+        (let loop ((l l))
+          (if (pair? l)
+            (begin
+              (f (car l))
+              (loop (cdr l)))))
+        (let loop ((ls (cons l ls)))
+          (let ((args (~cars ls)))
+            (if args
+              (begin
+                (apply f args)
+                (loop (~cdrs ls))))))))
+
+    (define (fold-left f init l . ls)
+      (let loop ((ls (cons l ls)) (acc init))
+        (let ((args (~cars ls)))
+          (if args
+            (loop (~cdrs ls) (apply f acc args))
+            acc))))
+
+    (define (fold-right f init l . ls)
+      (let loop ((rows (apply fold-left (lambda (rows . args) (cons args rows)) '() l ls)) (acc init))
+        (if (pair? rows)
+          (loop (cdr rows) (apply f (append (car rows) (list acc))))
+          acc)))
+
+    (define (reduce f ridentity l)
+      (if (null? l)
+        ridentity
+        (fold-left (lambda (acc x) (f x acc)) (car l) (cdr l))))
+
+    (define (filter pred l)
+      (let loop ((l l) (acc '()))
+        (cond
+          ((null? l) (~reverse acc))
+          ((pred (car l)) (loop (cdr l) (cons (car l) acc)))
+          (else (loop (cdr l) acc)))))
+
+    (define (partition pred l)
+      (let loop ((l l) (in '()) (out '()))
+        (cond
+          ((null? l) (values (~reverse in) (~reverse out)))
+          ((pred (car l)) (loop (cdr l) (cons (car l) in) out))
+          (else (loop (cdr l) in (cons (car l) out))))))
+
+    (define (delete x l . compare)
+      (if (null? compare)
+        (~delete x l)
+        (filter (lambda (y) (not ((car compare) x y))) l)))
+
+    (define (delete-duplicates l . compare)
+      (if (null? compare)
+        (~delete-duplicates l)
+        (let loop ((l l) (acc '()))
+          (cond
+            ((null? l) (~reverse acc))
+            ((any (lambda (y) ((car compare) y (car l))) acc) (loop (cdr l) acc))
+            (else (loop (cdr l) (cons (car l) acc)))))))
+
+    (define (find pred l)
+      (let loop ((l l))
+        (cond
+          ((null? l) #f)
+          ((pred (car l)) (car l))
+          (else (loop (cdr l))))))
+
+    (define (any pred l . ls)
+      (let loop ((ls (cons l ls)))
+        (let ((args (~cars ls)))
+          (if args
+            (let ((v (apply pred args)))
+              (if v v (loop (~cdrs ls))))
+            #f))))
+
+    (define (every pred l . ls)
+      (let loop ((ls (cons l ls)) (prev #t))
+        (let ((args (~cars ls)))
+          (if args
+            (let ((v (apply pred args)))
+              (if v (loop (~cdrs ls) v) #f))
+            prev))))
+
+    (define (sort seq less?) (~sort seq less?))
+
+    (define (sort! seq less?) (~sort! seq less?))
+
+    (define (list-sort less? l) (~sort l less?))
+
+    (define (vector-sort less? v) (~sort v less?))
+
+    (define (merge l r less?) (~merge l r less?))
+
+    (define-record-type promise
+      (promise-of box)
+      promise?
+      (box promise-box set-promise-box!))
+
+    (define (lazy-promise done? value) (promise-of (cons done? value)))
+
+    (define-syntax delay-force
+      (syntax-rules ()
+        ((_ expr) (lazy-promise #f (lambda () expr)))))
+
+    (define-syntax delay
+      (syntax-rules ()
+        ((_ expr) (delay-force (make-promise expr)))))
+
+    (define (make-promise obj)
+      (if (promise? obj)
+        obj
+        (lazy-promise #t obj)))
+
+    (define (force promise)
+      (if (promise? promise)
+        (let loop ()
+          (let ((box (promise-box promise)))
+            (if (car box)
+              (cdr box)
+              (let ((promise* ((cdr box))))
+                (unless (car (promise-box promise))
+                  (let ((box* (promise-box promise*)))
+                    (set-car! (promise-box promise) (car box*))
+                    (set-cdr! (promise-box promise) (cdr box*))
+                    (set-promise-box! promise* (promise-box promise))))
+                (loop)))))
+        promise))
+
+    (define stream-null (delay '()))
+
+    (define-syntax stream-cons
+      (syntax-rules ()
+        ((_ a b) (make-promise (cons (delay a) (delay-force b))))))
+
+    (define (stream? s) (promise? s))
+
+    (define (stream-null? s) (and (promise? s) (null? (force s))))
+
+    (define (stream-pair? s) (and (promise? s) (pair? (force s))))
+
+    (define (stream-car s) (force (car (force s))))
+
+    (define (stream-cdr s) (cdr (force s)))
+
+    (define (list->stream l)
+      (delay-force
+        (if (pair? l)
+          (stream-cons (car l) (list->stream (cdr l)))
+          stream-null)))
+
+    (define (stream->list s . n)
+      (stream-prefix s (if (null? n) -1 (car n)) '()))
+
+    (define (stream-prefix s n acc)
+      (if (and (not (~= n 0)) (stream-pair? s))
+        (stream-prefix (stream-cdr s) (~- n 1) (cons (stream-car s) acc))
+        (~reverse acc)))
+
+    (define (stream-from start . step)
+      (let ((step (if (null? step) 1 (car step))))
+        (let loop ((x start))
+          (stream-cons x (loop (~+ x step))))))
+
+    (define (stream-ref s k)
+      (if (~> k 0)
+        (stream-ref (stream-cdr s) (~- k 1))
+        (stream-car s)))
+
+    (define (stream-take n s)
+      (delay-force
+        (if (and (~> n 0) (stream-pair? s))
+          (stream-cons (stream-car s) (stream-take (~- n 1) (stream-cdr s)))
+          stream-null)))
+
+    (define (stream-drop n s)
+      (if (and (~> n 0) (stream-pair? s))
+        (stream-drop (~- n 1) (stream-cdr s))
+        s))
+
+    (define (stream-map f s . ss) (streams-map f (cons s ss)))
+
+    (define (streams-map f strms)
+      (delay-force
+        (if (every stream-pair? strms)
+          (stream-cons (apply f (map stream-car strms)) (streams-map f (map stream-cdr strms)))
+          stream-null)))
+
+    (define (stream-filter pred s)
+      (delay-force
+        (cond
+          ((not (stream-pair? s)) stream-null)
+          ((pred (stream-car s)) (stream-cons (stream-car s) (stream-filter pred (stream-cdr s))))
+          (else (stream-filter pred (stream-cdr s))))))
+
+    (define (stream-for-each f s)
+      (when (stream-pair? s)
+        (f (stream-car s))
+        (stream-for-each f (stream-cdr s))))
+
+    (define-record-type eof
+      (make-eof)
+      eof-object?)
+
+    (define the-eof (make-eof))
+
+    (define (eof-object) the-eof)
+
+    (define (make-coroutine-generator proc)
+      (let* ((done #f)
+             (co (~make-coroutine
+                   (lambda ()
+                     (proc (lambda (v) (~yield v)))
+                     (set! done #t)
+                     the-eof))))
+        (lambda ()
+          (if done
+            the-eof
+            (~resume co)))))
+
+    (define (make-generator proc) (make-coroutine-generator proc))
+
+    (define (generator . args) (list->generator args))
+
+    (define (list->generator l)
+      (lambda ()
+        (if (null? l)
+          the-eof
+          (let ((v (car l)))
+            (set! l (cdr l))
+            v))))
+
+    (define (vector->generator v . range)
+      (let ((i (if (pair? range) (car range) 0))
+            (end (if (and (pair? range) (pair? (cdr range)))
+                   (car (cdr range))
+                   (vector-length v))))
+        (lambda ()
+          (if (~>= i end)
+            the-eof
+            (let ((x (vector-ref v i)))
+              (set! i (~+ i 1))
+              x)))))
+
+    (define (make-iota-generator count . rest)
+      (let ((start (if (pair? rest) (car rest) 0))
+            (step (if (and (pair? rest) (pair? (cdr rest))) (car (cdr rest)) 1))
+            (i 0))
+        (lambda ()
+          (if (~>= i count)
+            the-eof
+            (let ((v (~+ start (~* i step))))
+              (set! i (~+ i 1))
+              v)))))
+
+    (define (make-range-generator start . rest)
+      (let ((end (if (pair? rest) (car rest) #f))
+            (step (if (and (pair? rest) (pair? (cdr rest))) (car (cdr rest)) 1)))
+        (lambda ()
+          (if (and end (~>= start end))
+            the-eof
+            (let ((v start))
+              (set! start (~+ start step))
+              v)))))
+
+    (define (gcons* . args)
+      (lambda ()
+        (if (null? (cdr args))
+          ((car args))
+          (let ((v (car args)))
+            (set! args (cdr args))
+            v))))
+
+    (define (gappend . gens)
+      (lambda ()
+        (let loop ()
+          (if (null? gens)
+            the-eof
+            (let ((v ((car gens))))
+              (if (eof-object? v)
+                (begin
+                  (set! gens (cdr gens))
+                  (loop))
+                v))))))
+
+    (define (gmap proc gen . gens)
+      (if (null? gens)
+        (lambda ()
+          (let ((v (gen)))
+            (if (eof-object? v) v (proc v))))
+        (let ((gens (cons gen gens)))
+          (lambda ()
+            (let ((vs (map (lambda (g) (g)) gens)))
+              (if (any eof-object? vs) the-eof (apply proc vs)))))))
+
+    (define (gfilter pred gen)
+      (lambda ()
+        (let loop ()
+          (let ((v (gen)))
+            (if (or (eof-object? v) (pred v)) v (loop))))))
+
+    (define (gremove pred gen) (gfilter (lambda (v) (not (pred v))) gen))
+
+    (define (gtake gen k . padding)
+      (lambda ()
+        (if (~> k 0)
+          (let ((v (gen)))
+            (set! k (~- k 1))
+            (if (and (eof-object? v) (pair? padding)) (car padding) v))
+          the-eof)))
+
+    (define (gdrop gen k)
+      (lambda ()
+        (let loop ()
+          (when (~> k 0)
+            (set! k (~- k 1))
+            (gen)
+            (loop)))
+        (gen)))
+
+    (define (gtake-while pred gen)
+      (let ((done #f))
+        (lambda ()
+          (if done
+            the-eof
+            (let ((v (gen)))
+              (if (or (eof-object? v) (not (pred v)))
+                (begin
+                  (set! done #t)
+                  the-eof)
+                v))))))
+
+    (define (gdrop-while pred gen)
+      (let ((dropping #t))
+        (lambda ()
+          (if dropping
+            (let loop ()
+              (let ((v (gen)))
+                (if (and (not (eof-object? v)) (pred v))
+                  (loop)
+                  (begin
+                    (set! dropping #f)
+                    v))))
+            (gen)))))
+
+    (define (generator->reverse-list gen . n)
+      (let loop ((n (if (pair? n) (car n) -1)) (acc '()))
+        (if (~= n 0)
+          acc
+          (let ((v (gen)))
+            (if (eof-object? v)
+              acc
+              (loop (~- n 1) (cons v acc)))))))
+
+    (define (generator->list gen . n)
+      (~reverse (apply generator->reverse-list gen n)))
+
+    (define (generator->vector gen . n)
+      (list->vector (apply generator->list gen n)))
+
+    (define (generator-fold proc seed gen . gens)
+      (let ((gen (apply gmap list gen gens)))
+        (let loop ((acc seed))
+          (let ((vs (gen)))
+            (if (eof-object? vs)
+              acc
+              (loop (apply proc (~append vs (list acc)))))))))
+
+    (define (generator-for-each proc gen . gens)
+      (let ((gen (apply gmap list gen gens)))
+        (let loop ()
+          (let ((vs (gen)))
+            (unless (eof-object? vs)
+              (apply proc vs)
+              (loop))))))
+
+    (define (generator-find pred gen)
+      (let loop ()
+        (let ((v (gen)))
+          (cond
+            ((eof-object? v) #f)
+            ((pred v) v)
+            (else (loop))))))
+
+    (define (generator-count pred gen)
+      (generator-fold (lambda (v n) (if (pred v) (~+ n 1) n)) 0 gen))
+
+    (define (generator-any pred gen)
+      (let loop ()
+        (let ((v (gen)))
+          (if (eof-object? v)
+            #f
+            (let ((r (pred v)))
+              (if r r (loop)))))))
+
+    (define (generator-every pred gen)
+      (let loop ((last #t))
+        (let ((v (gen)))
+          (if (eof-object? v)
+            last
+            (let ((r (pred v)))
+              (if r (loop r) #f))))))
+
+    (define (make-accumulator kons knil finalizer)
+      (let ((state knil))
+        (lambda (obj)
+          (if (eof-object? obj)
+            (finalizer state)
+            (set! state (kons obj state))))))
+
+    (define (count-accumulator)
+      (make-accumulator (lambda (obj n) (~+ n 1)) 0 (lambda (n) n)))
+
+    (define (list-accumulator)
+      (make-accumulator (lambda (obj l) (cons obj l)) '() (lambda (l) (~reverse l))))
+
+    (define (reverse-list-accumulator)
+      (make-accumulator (lambda (obj l) (cons obj l)) '() (lambda (l) l)))
+
+    (define (vector-accumulator)
+      (make-accumulator (lambda (obj l) (cons obj l)) '() (lambda (l) (list->vector (~reverse l)))))
+
+    (define (sum-accumulator)
+      (make-accumulator (lambda (obj n) (~+ obj n)) 0 (lambda (n) n)))
+
+    (define (product-accumulator)
+      (make-accumulator (lambda (obj n) (~* obj n)) 1 (lambda (n) n)))
+
+    (define-record-type thread
+      (make-thread coroutine state value waiters)
+      thread?
+      (coroutine thread-coroutine)
+      (state thread-state set-thread-state!)
+      (value thread-value set-thread-value!)
+      (waiters thread-waiters set-thread-waiters!))
+
+    (define (make-queue) (cons '() '()))
+
+    (define (queue-empty? q) (and (null? (car q)) (null? (cdr q))))
+
+    (define (enqueue! q obj) (set-cdr! q (cons obj (cdr q))))
+
+    (define (dequeue! q)
+      (when (null? (car q))
+        (set-car! q (reverse (cdr q)))
+        (set-cdr! q '()))
+      (let ((obj (car (car q))))
+        (set-car! q (cdr (car q)))
+        obj))
+
+    (define main-thread (make-thread #f 'running '() '()))
+
+    (define running-thread main-thread)
+
+    (define ready-threads (make-queue))
+
+    (define sleeping-threads '())
+
+    (define thread-failure (list 'thread-failure))
+
+    (define (current-thread)
+      (if (~in-thread) running-thread main-thread))
+
+    ; Threads only ask the scheduler to act on their behalf, so the shared queues are never
+    ; touched by a thread that may be preempted halfway through. The main program has no
+    ; coroutine of its own and drives the scheduler itself until its request is served. It is
+    ; preempted by the VM yielding on its behalf, which never happens inside the prelude.
+    (define (thread-request op . args)
+      (if (~in-thread)
+        (let ((self running-thread))
+          (~yield (cons op args))
+          (thread-value self))
+        (let ((self (make-thread #f 'running '() '())))
+          (apply op self args)
+          (dynamic-wind
+            (lambda () #f)
+            (lambda ()
+              (let loop ()
+                (unless (eq? (thread-state self) 'running)
+                  (schedule!)
+                  (loop))))
+            (lambda ()
+              (unless (eq? (thread-state self) 'running)
+                (set-thread-state! self 'dead))))
+          (thread-value self))))
+
+    (define (schedule!)
+      (wake-sleepers!)
+      (cond
+        ((not (queue-empty? ready-threads)) (run-thread! (dequeue! ready-threads)))
+        ((pair? sleeping-threads)
+         (~sleep (~- (car (car sleeping-threads)) (~clock))))
+        (else (~error "Deadlock: every thread is blocked"))))
+
+    (define (wake-sleepers!)
+      (when (and (pair? sleeping-threads) (~<= (car (car sleeping-threads)) (~clock)))
+        (let ((t (cdr (car sleeping-threads))))
+          (set! sleeping-threads (cdr sleeping-threads))
+          (wake-thread! t '()))
+        (wake-sleepers!)))
+
+    (define (wake-thread! t v)
+      (when (eq? (thread-state t) 'blocked)
+        (set-thread-value! t v)
+        (set-thread-state! t 'ready)
+        (enqueue! ready-threads t)))
+
+    (define (run-thread! t)
+      (set-thread-state! t 'running)
+      (when (thread-coroutine t)
+        (set! running-thread t)
+        (dynamic-wind
+          (lambda () #f)
+          (lambda ()
+            (let ((request (~resume (thread-coroutine t))))
+              (set-thread-value! t '())
+              (cond
+                ((null? request) (yield-op t))
+                ((string? request) (fail-op t request))
+                (else (apply (car request) t (cdr request))))
+              (when (eq? (thread-state t) 'running)
+                (yield-op t))))
+          (lambda ()
+            (set! running-thread main-thread)
+            (when (eq? (thread-state t) 'running)
+              (fail-op t "Interrupted"))))))
+
+    (define (spawn-op self thunk)
+      (let ((t (make-thread (~make-thread (lambda () (list finish-op (thunk)))) 'ready '() '())))
+        (enqueue! ready-threads t)
+        (set-thread-value! self t)))
+
+    (define (finish-op self v)
+      (set-thread-state! self 'done)
+      (set-thread-value! self v)
+      (for-each (lambda (t) (wake-thread! t v)) (thread-waiters self))
+      (set-thread-waiters! self '()))
+
+    (define (fail-op self msg)
+      (finish-op self (cons thread-failure msg))
+      (set-thread-state! self 'failed))
+
+    (define (yield-op self)
+      (set-thread-state! self 'ready)
+      (enqueue! ready-threads self))
+
+    (define (sleep-op self secs)
+      (define (insert sleepers wake)
+        (if (or (null? sleepers) (~< wake (car (car sleepers))))
+          (cons (cons wake self) sleepers)
+          (cons (car sleepers) (insert (cdr sleepers) wake))))
+      (set-thread-state! self 'blocked)
+      (set! sleeping-threads (insert sleeping-threads (~+ (~clock) secs))))
+
+    (define (join-op self t)
+      (if (memq (thread-state t) '(done failed))
+        (set-thread-value! self (thread-value t))
+        (begin
+          (set-thread-state! self 'blocked)
+          (set-thread-waiters! t (cons self (thread-waiters t))))))
+
+    (define (spawn thunk) (thread-request spawn-op thunk))
+
+    (define (yield) (thread-request yield-op))
+
+    (~on-preempt
+      (lambda ()
+        (wake-sleepers!)
+        (unless (queue-empty? ready-threads)
+          (yield))))
+
+    (define (sleep secs) (thread-request sleep-op secs))
+
+    (define (thread-join t)
+      (let ((v (thread-request join-op t)))
+        (if (and (pair? v) (eq? (car v) thread-failure))
+          (~error (~string-append "Joined thread exited with an error: " (cdr v)))
+          v)))
+
+    (define-record-type channel
+      (channel-of items receivers)
+      channel?
+      (items channel-items)
+      (receivers channel-receivers))
+
+    (define (make-channel) (channel-of (make-queue) (make-queue)))
+
+    (define (send-op self ch v)
+      (if (queue-empty? (channel-receivers ch))
+        (enqueue! (channel-items ch) v)
+        (let ((t (dequeue! (channel-receivers ch))))
+          (if (eq? (thread-state t) 'blocked)
+            (wake-thread! t v)
+            (send-op self ch v)))))
+
+    (define (receive-op self ch)
+      (if (queue-empty? (channel-items ch))
+        (begin
+          (set-thread-state! self 'blocked)
+          (enqueue! (channel-receivers ch) self))
+        (set-thread-value! self (dequeue! (channel-items ch)))))
+
+    (define (channel-send ch v) (thread-request send-op ch v))
+
+    (define (channel-receive ch) (thread-request receive-op ch))
+
+    (define (values . vals) (~list->values vals))
+
+    (define (call-with-values producer consumer)
+      (apply consumer (~values->list (producer))))
+
+    (define (neq? l r) (not (eq? l r)))
+
+    (define (call-with-current-continuation f) (~call/cc f))
+
+    (define (call/cc f) (~call/cc f))
+
+    (define (dynamic-wind before thunk after)
+      (before)
+      (~wind before after)
+      (let ((result (thunk)))
+        (~unwind)
+        (after)
+        result))
+
+    (define param-key (list 'parameter))
+
+    (define (make-parameter value . converter)
+      (let* ((converter (if (null? converter) (lambda (x) x) (car converter)))
+             (cell (cons (converter value) converter)))
+        (lambda args
+          (cond
+            ((null? args) (car cell))
+            ((eq? (car args) param-key) cell)
+            (else (set-car! cell (converter (car args))))))))
+
+    (define-syntax parameterize
+      (syntax-rules ()
+        ((_ ((param value) ...) body ...)
+         (dynamic-bind (list param ...) (list value ...) (lambda () body ...)))))
+
+    (define (dynamic-bind params vals body)
+      (let* ((cells (map (lambda (p) (p param-key)) params))
+             (vals (map (lambda (cell v) ((cdr cell) v)) cells vals)))
+        (define (swap!)
+          (set! vals (map (lambda (cell v)
+                            (let ((old (car cell)))
+                              (set-car! cell v)
+                              old))
+                          cells
+                          vals)))
+        (dynamic-wind swap! body swap!)))
+
+    (define-record-type port
+      (make-port sink)
+      port?
+      (sink port-sink set-port-sink!))
+
+    (define (output-port? obj) (port? obj))
+
+    (define current-output-port (make-parameter (make-port 'stdout)))
+
+    (define (open-output-string) (make-port '()))
+
+    (define (get-output-string port)
+      (let loop ((chunks (port-sink port)) (s ""))
+        (if (null? chunks)
+          (begin
+            (set-port-sink! port (list s))
+            s)
+          (loop (cdr chunks) (~string-append (car chunks) s)))))
+
+    (define (port-of rest)
+      (if (null? rest) (current-output-port) (car rest)))
+
+    (define (write-string s . port)
+      (let ((port (port-of port)))
+        (if (eq? (port-sink port) 'stdout)
+          (~display s)
+          (set-port-sink! port (cons s (port-sink port))))))
+
+    (define (display obj . port)
+      (let ((port (port-of port)))
+        (if (eq? (port-sink port) 'stdout)
+          (~display obj)
+          (write-string (~display->string obj) port))))
+
+    (define (write obj . port)
+      (let ((port (port-of port)))
+        (if (eq? (port-sink port) 'stdout)
+          (~write obj)
+          (write-string (~write->string obj) port))))
+
+    (define (write-shared obj . port)
+      (let ((port (port-of port)))
+        (if (eq? (port-sink port) 'stdout)
+          (~write-shared obj)
+          (write-string (~write-shared->string obj) port))))
+
+    (define (newline . port) (write-string "\n" (port-of port)))
+
+    (define (+ . a)
+      (if (null? a)
+        0
+        (~+ (car a) (apply + (cdr a)))))
+
+    (define (- . a)
+      (cond
+        ((null? a) 0)
+        ((null? (cdr a)) (~- 0 (car a)))
+        (else (~- (car a) (apply + (cdr a))))))
+
+    (define (* . a)
+      (if (null? a)
+        1
+        (~* (car a) (apply * (cdr a)))))
+
+    (define (/ . a)
+      (cond
+        ((null? a) '())
+        ((null? (cdr a)) (~/ 1 (car a)))
+        (else (~/ (car a) (apply * (cdr a))))))
+
+    (define (= . a)
+      (if (~> 2 (length a))
+        '()
+        (and
+          (~= (car a) (car (cdr a)))
+          (if (~= 1 (length (cdr a)))
+            #t
+            (apply = (cdr a))))))
+
+    (define (< . a)
+      (if (~> 2 (length a))
+        '()
+        (and
+          (~< (car a) (car (cdr a)))
+          (if (~= 1 (length (cdr a)))
+            #t
+            (apply < (cdr a))))))
+
+    (define (<= . a)
+      (if (~> 2 (length a))
+        '()
+        (and
+          (~<= (car a) (car (cdr a)))
+          (if (~= 1 (length (cdr a)))
+            #t
+            (apply <= (cdr a))))))
+
+    (define (> . a)
+      (if (~> 2 (length a))
+        '()
+        (and
+          (~> (car a) (car (cdr a)))
+          (if (~= 1 (length (cdr a)))
+            #t
+            (apply > (cdr a))))))
+
+    (define (>= . a)
+      (if (~> 2 (length a))
+        '()
+        (and
+          (~>= (car a) (car (cdr a)))
+          (if (~= 1 (length (cdr a)))
+            #t
+            (apply >= (cdr a))))))
+
+    (define (string-append . a)
+      (if (null? a)
+        ""
+        (~string-append (car a) (apply string-append (cdr a)))))
+
+    (define (vector . l) (list->vector l))
+
+    (define (eval exp env) (~eval exp env))
+
+    (define (interaction-environment) (~interaction-environment))
+
+    (define (scheme-report-environment version) (~scheme-report-environment version))
+
+    (define (null-environment version) (~null-environment version))))
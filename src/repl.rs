@@ -1,18 +1,25 @@
 use std::io::{BufRead, Write};
+use std::path::PathBuf;
 use std::process::exit;
 use std::sync::mpsc::channel;
 
-pub fn run() {
+pub fn run(search_paths: &[PathBuf], limits: crate::vm::Limits) {
     let stdin = std::io::stdin();
     let mut stdout = std::io::stdout();
 
     let mut vm = crate::vm::VM::new();
-    let _ = vm.exec(crate::prelude(), None, None, false);
+
+    for dir in search_paths {
+        vm.add_search_path(dir.clone());
+    }
+
+    let _ = vm.load_prelude(crate::prelude());
 
     let mut var_cnt = 0;
 
     let (tx, rx) = channel();
     ctrlc::set_handler(move || tx.send(()).unwrap()).expect("Failed to set Ctrl-C handler");
+    vm.set_stopper(rx);
 
     loop {
         print!("> ");
@@ -34,7 +41,7 @@ pub fn run() {
 
         let ret = vm.exec(
             input.into(),
-            Some(&rx),
+            limits,
             Some(vec![
                 Inst::Def(var.clone()),
                 Inst::Set(var.clone()),
@@ -45,8 +52,11 @@ pub fn run() {
         );
 
         match ret {
-            Ok(ret) => println!("{} = {}", var.0, ret),
-            Err(e) => println!("Error: {}", e),
+            Ok(ret) => {
+                println!("{} = {}", var.0, ret.write());
+                vm.release(&ret);
+            }
+            Err(e) => println!("Error: {:#}", e),
         }
     }
 }
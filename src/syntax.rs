@@ -4,21 +4,49 @@ use crate::obj::Number;
 #[derive(Debug, Clone)]
 pub struct AST {
     pub body: Vec<Toplevel>,
+    pub id_ctxs: Vec<IdCtx>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IdCtx {
+    pub id: u32,
+    pub parent: u32,
+    pub scope: u32,
 }
 
 #[derive(Debug, Clone)]
 pub struct DefineSyntax {
     pub meta: Meta,
     pub id: Id,
-    pub keywords: Vec<Id>,
+    pub transformer: Transformer,
+}
+
+#[derive(Debug, Clone)]
+pub enum Transformer {
+    SyntaxRules(SyntaxRules),
+    ErMacro(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntaxRules {
+    pub meta: Meta,
+    pub ellipsis: String,
+    pub literals: Vec<String>,
     pub syntax_rules: Vec<SyntaxRule>,
 }
 
 #[derive(Debug, Clone)]
 pub struct SyntaxRule {
     pub meta: Meta,
-    pub syntax: Vec<Token>,
-    pub template: Vec<Token>,
+    pub pattern: TokenTree,
+    pub template: TokenTree,
+}
+
+#[derive(Debug, Clone)]
+pub enum TokenTree {
+    Token(Token),
+    List(Vec<TokenTree>, Option<Box<TokenTree>>),
+    Vector(Vec<TokenTree>),
 }
 
 #[derive(Debug, Clone)]
@@ -27,18 +55,50 @@ pub enum Toplevel {
     Exp(Exp),
     Define(Define),
     Load(Load),
+    Library(Library),
+    Import(Import),
+    Include(Include),
+}
+
+#[derive(Debug, Clone)]
+pub struct Library {
+    pub meta: Meta,
+    pub ids: Vec<Id>,
+    pub body: Vec<Toplevel>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub meta: Meta,
+    pub libraries: Vec<Library>,
+    pub bindings: Vec<ImportBinding>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportBinding {
+    pub id: Id,
+    pub target: Id,
+}
+
+#[derive(Debug, Clone)]
+pub struct Include {
+    pub meta: Meta,
+    pub body: Vec<Toplevel>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Load {
     pub meta: Meta,
     pub src: Str,
+    pub is_once: bool,
 }
 
 #[derive(Debug, Clone)]
 pub enum Define {
     Var(DefVar),
     Func(DefFunc),
+    Values(DefValues),
+    RecordType(DefRecordType),
 }
 
 #[derive(Debug, Clone)]
@@ -57,6 +117,37 @@ pub struct DefFunc {
     pub body: Body,
 }
 
+#[derive(Debug, Clone)]
+pub struct DefValues {
+    pub meta: Meta,
+    pub formals: Arg,
+    pub exp: Exp,
+}
+
+#[derive(Debug, Clone)]
+pub struct DefRecordType {
+    pub meta: Meta,
+    pub id: Id,
+    pub constructor: Option<RecordConstructor>,
+    pub predicate: Id,
+    pub fields: Vec<RecordField>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordConstructor {
+    pub meta: Meta,
+    pub id: Id,
+    pub args: Vec<Id>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordField {
+    pub meta: Meta,
+    pub id: Id,
+    pub accessor: Id,
+    pub modifier: Option<Id>,
+}
+
 #[derive(Debug, Clone)]
 pub enum Exp {
     Const(Const),
@@ -64,16 +155,32 @@ pub enum Exp {
     Lambda(Box<Lambda>),
     Apply(Box<Apply>),
     Quote(Box<Quote>),
+    QuasiQuote(Box<QuasiQuote>),
     Set(Box<Set>),
     Let(Box<Let>),
     LetAster(Box<LetAster>),
     LetRec(Box<LetRec>),
+    LetValues(Box<LetValues>),
+    LetAsterValues(Box<LetAsterValues>),
+    Receive(Box<Receive>),
     If(Box<If>),
     Cond(Box<Cond>),
+    Case(Box<Case>),
+    When(Box<When>),
+    Unless(Box<Unless>),
+    CaseLambda(Box<CaseLambda>),
+    Assert(Box<Assert>),
     And(Box<And>),
     Or(Box<Or>),
     Begin(Box<Begin>),
     Do(Box<Do>),
+    LetSyntax(Box<LetSyntax>),
+}
+
+#[derive(Debug, Clone)]
+pub struct LetSyntax {
+    pub meta: Meta,
+    pub body: Body,
 }
 
 #[derive(Debug, Clone)]
@@ -96,6 +203,29 @@ pub struct Quote {
     pub s_exp: SExp,
 }
 
+#[derive(Debug, Clone)]
+pub struct QuasiQuote {
+    pub meta: Meta,
+    pub template: QuasiSExp,
+}
+
+#[derive(Debug, Clone)]
+pub enum QuasiSExp {
+    SExp(SExp),
+    Unquote(Exp),
+    UnquoteSplicing(Exp),
+    Pair(Box<QuasiPair>),
+    // The elements of a vector template, which are built as a list first.
+    Vector(Box<QuasiPair>),
+}
+
+#[derive(Debug, Clone)]
+pub struct QuasiPair {
+    pub meta: Meta,
+    pub exps: Vec<QuasiSExp>,
+    pub last: Option<QuasiSExp>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Set {
     pub meta: Meta,
@@ -125,6 +255,35 @@ pub struct LetRec {
     pub body: Body,
 }
 
+#[derive(Debug, Clone)]
+pub struct LetValues {
+    pub meta: Meta,
+    pub bindings: Vec<ValuesBinding>,
+    pub body: Body,
+}
+
+#[derive(Debug, Clone)]
+pub struct LetAsterValues {
+    pub meta: Meta,
+    pub bindings: Vec<ValuesBinding>,
+    pub body: Body,
+}
+
+#[derive(Debug, Clone)]
+pub struct ValuesBinding {
+    pub meta: Meta,
+    pub formals: Arg,
+    pub exp: Exp,
+}
+
+#[derive(Debug, Clone)]
+pub struct Receive {
+    pub meta: Meta,
+    pub formals: Arg,
+    pub exp: Exp,
+    pub body: Body,
+}
+
 #[derive(Debug, Clone)]
 pub struct If {
     pub meta: Meta,
@@ -144,7 +303,55 @@ pub struct Cond {
 pub struct Match {
     pub meta: Meta,
     pub cond: Exp,
-    pub then: NonEmptyVec<Exp>,
+    pub then: ClauseBody,
+}
+
+#[derive(Debug, Clone)]
+pub enum ClauseBody {
+    Exps(NonEmptyVec<Exp>),
+    Arrow(Exp),
+}
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub meta: Meta,
+    pub key: Exp,
+    pub clauses: Vec<CaseClause>,
+    pub el: Option<ClauseBody>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseClause {
+    pub meta: Meta,
+    pub data: Vec<SExp>,
+    pub then: ClauseBody,
+}
+
+#[derive(Debug, Clone)]
+pub struct When {
+    pub meta: Meta,
+    pub cond: Exp,
+    pub body: NonEmptyVec<Exp>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Unless {
+    pub meta: Meta,
+    pub cond: Exp,
+    pub body: NonEmptyVec<Exp>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseLambda {
+    pub meta: Meta,
+    pub clauses: Vec<Lambda>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Assert {
+    pub meta: Meta,
+    pub exp: Exp,
+    pub s_exp: SExp,
 }
 
 #[derive(Debug, Clone)]
@@ -171,7 +378,7 @@ pub struct Do {
     pub bindings: Vec<DoBinding>,
     pub cond: Exp,
     pub value: Vec<Exp>,
-    pub body: Body,
+    pub body: Option<Body>,
 }
 
 #[derive(Debug, Clone)]
@@ -185,6 +392,7 @@ pub struct DoBinding {
 #[derive(Debug, Clone)]
 pub struct Body {
     pub meta: Meta,
+    pub scope: u32,
     pub defs: Vec<Define>,
     pub exps: NonEmptyVec<Exp>,
 }
@@ -220,6 +428,7 @@ pub enum SExp {
     Const(Const),
     Id(Id),
     Pair(Box<Pair>),
+    Vector(Box<Vector>),
 }
 
 #[derive(Debug, Clone)]
@@ -229,6 +438,12 @@ pub struct Pair {
     pub last: Option<SExp>,
 }
 
+#[derive(Debug, Clone)]
+pub struct Vector {
+    pub meta: Meta,
+    pub exps: Vec<SExp>,
+}
+
 #[derive(Debug, Clone)]
 pub enum Const {
     Num(Num),
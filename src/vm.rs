@@ -1,13 +1,16 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::fs::read_to_string;
-use std::rc::Rc;
-use anyhow::{bail, Context as _, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{bail, ensure, Context as _, Result};
 use crate::codegen::CodeGen;
 
 use crate::obj::*;
+use crate::lexer::Token;
 use crate::parser::Parser;
+use crate::syntax::AST;
 
 #[derive(Debug, Clone)]
 pub struct Frame {
@@ -23,6 +26,8 @@ pub enum Inst {
     Dup,
     Set(Id),
     Get(Id),
+    SetGlobal(Id),
+    GetGlobal(Id),
     Def(Id),
     CollectVArg(Id),
     Jump(u32),
@@ -31,11 +36,42 @@ pub enum Inst {
     OptCall,
     Ret,
     PushReturnContext(u32),
-    CreateClosure(u32),
+    CreateClosure(u32, Option<Arc<str>>),
     Load,
+    Require,
+    EndLoad,
+    Eval,
+    EvalRet,
     Exit,
+    Return,
+    Error(String),
+    Raise,
+    MatchArity(usize, bool),
+
+    InteractionEnv,
+    ReportEnv,
+    NullEnv,
+
+    CallCC,
+    Wind,
+    Unwind,
+    MakeCoroutine,
+    Resume,
+    Yield,
+    EndCoroutine,
+    MakeThread,
+    InThread,
+    OnPreempt,
+    EndPreempt,
+    Clock,
+    Sleep,
 
     Display,
+    Write,
+    WriteShared,
+    DisplayString,
+    WriteString,
+    WriteSharedString,
 
     Add, // +
     Sub, // -
@@ -56,7 +92,40 @@ pub enum Inst {
     Cdr,
     SetCar,
     SetCdr,
+    Append,
+    AppendDestructive,
     ExpandList,
+    Length,
+    Reverse,
+    ListTail,
+    ListCopy,
+    Sort,
+    SortDestructive,
+    Merge,
+    Memq,
+    Member,
+    Assq,
+    Assoc,
+    Delete,
+    DeleteDuplicates,
+    Iota,
+    Cars,
+    Cdrs,
+    ListToValues,
+    ValuesToList,
+
+    Vector(usize),
+    VectorLength,
+    VectorRef,
+    VectorSet,
+    VectorToList,
+    ListToVector,
+
+    MakeRecordType(Id, Vec<Id>),
+    MakeRecord,
+    RecordRef,
+    RecordSet,
+    IsRecord,
 
     IsNull,
     IsPair,
@@ -65,6 +134,7 @@ pub enum Inst {
     IsString,
     IsProc,
     IsSymbol,
+    IsVector,
 
     IsEq,
     IsEqual,
@@ -74,8 +144,78 @@ pub enum Inst {
     StrToNum,
     NumToStr,
     StringAppend,
+    Rename,
+    MacroExpand,
+    MacroExpand1,
 }
 
+const RETURN_ADDR: u32 = 0;
+const CALL_ADDR: u32 = 1;
+const END_COROUTINE_ADDR: u32 = 2;
+const END_PREEMPT_ADDR: u32 = 3;
+
+const COROUTINE_STACK_SIZE: usize = 64;
+
+// Number of instructions a thread runs before it is preempted.
+const THREAD_QUANTUM: u32 = 1000;
+
+// Number of instructions between checks of the stopper and the limits.
+const CHECK_INTERVAL: u64 = 1024;
+
+// Instructions left to dynamic-wind after thunks when an execution with limits fails.
+const UNWIND_FUEL: u64 = 100_000;
+
+// Bounds for a single execution. The heap limit applies to memory allocated on top of what
+// the VM already holds when the execution starts.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    pub fuel: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub max_heap: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum LimitExceeded {
+    Fuel(u64),
+    Deadline(Duration),
+    Heap(usize),
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fuel(fuel) => write!(f, "Fuel exhausted after {} instructions", fuel),
+            Self::Deadline(timeout) => write!(f, "Deadline exceeded after {:?}", timeout),
+            Self::Heap(max_heap) => write!(f, "Heap limit of {} bytes exceeded", max_heap),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+// The limits of the running execution along with what it has used of them, and the stopper
+// that interrupts it. Macro transformers that run while the execution parses share it.
+#[derive(Debug, Default)]
+pub struct Budget {
+    limits: Limits,
+    deadline: Option<Instant>,
+    heap_base: isize,
+    executed: u64,
+    next_check: u64,
+    stopper: Option<Receiver<()>>,
+}
+
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
 pub struct VM {
     parser: Parser,
     codegen: CodeGen,
@@ -88,6 +228,29 @@ pub struct VM {
 
     fp: u32,
     frame_stack: Vec<Option<Frame>>,
+    next_frame: usize,
+
+    report_env: HashMap<Id, Obj>,
+
+    files: Vec<(u32, u32, PathBuf)>,
+    loaded: HashMap<PathBuf, (Option<SystemTime>, u32)>,
+
+    roots: Vec<Obj>,
+    held: Vec<Obj>,
+
+    winders: Vec<Arc<(Obj, Obj)>>,
+    base: u32,
+    level: u64,
+    levels: u64,
+
+    coroutines: Vec<Arc<RwLock<Coroutine>>>,
+    ticks: u32,
+    epoch: Instant,
+    has_threads: bool,
+    preempt_handler: Option<Obj>,
+    prelude_end: u32,
+
+    budget: Budget,
 }
 
 impl VM {
@@ -103,25 +266,131 @@ impl VM {
             parser: Parser::new(),
             codegen: CodeGen::new(),
 
-            insts: vec![],
+            insts: vec![
+                Inst::Return,
+                Inst::Call,
+                Inst::EndCoroutine,
+                Inst::EndPreempt,
+            ],
             pc: 0,
             sp: 0,
             stack: vec![Obj::Null; 1000],
             fp: 0,
             frame_stack,
+            next_frame: 1,
+
+            report_env: Default::default(),
+
+            files: vec![],
+            loaded: Default::default(),
+
+            roots: vec![],
+            held: vec![],
+
+            winders: vec![],
+            base: 0,
+            level: 0,
+            levels: 0,
+
+            coroutines: vec![],
+            ticks: 0,
+            epoch: Instant::now(),
+            has_threads: false,
+            preempt_handler: None,
+            prelude_end: 0,
+
+            budget: Budget {
+                next_check: CHECK_INTERVAL,
+                ..Budget::default()
+            },
         }
     }
 
+    pub fn load_prelude(&mut self, src: String) -> Result<()> {
+        for src in [src, "(import (scheme base))".into()] {
+            let ret = self.exec(src, Limits::default(), None, false)?;
+            self.release(&ret);
+        }
+
+        self.report_env = self.frame_stack[0].as_ref().unwrap().table.clone();
+        self.prelude_end = self.insts.len() as u32;
+
+        Ok(())
+    }
+
+    // The results of exec, exec_file and call are held when they are not atoms, so that the
+    // frames they refer to survive collection. Callers must pass them to release once done.
     pub fn exec(
         &mut self,
         src: String,
-        stopper: Option<&std::sync::mpsc::Receiver<()>>,
+        limits: Limits,
         extra_insts: Option<Vec<Inst>>,
         is_strict_syntax: bool,
     ) -> Result<Obj> {
-        self.pc = self.insts.len() as u32;
+        self.set_limits(limits);
+
+        let ret = self.exec_ast(
+            |parser| parser.parse(src, is_strict_syntax).context("Invalid syntax"),
+            extra_insts,
+            None,
+        );
+
+        self.set_limits(Limits::default());
+
+        ret
+    }
+
+    pub fn exec_file(&mut self, path: &Path, limits: Limits) -> Result<Obj> {
+        self.set_limits(limits);
+
+        let ret = self.exec_ast(|parser| parser.parse_file(path), None, Some(path.to_path_buf()));
+
+        self.set_limits(Limits::default());
+
+        ret
+    }
+
+    // Runs under the budget of the caller, which gets back what is left of it.
+    pub fn exec_tokens(&mut self, tokens: Vec<Token>, budget: &mut Budget) -> Result<Obj> {
+        std::mem::swap(&mut self.budget, budget);
+
+        let ret = self.exec_ast(
+            |parser| parser.parse_tokens(tokens).context("Invalid syntax"),
+            None,
+            None,
+        );
 
-        let ast = self.parser.parse(src, is_strict_syntax).context("Invalid syntax")?;
+        std::mem::swap(&mut self.budget, budget);
+
+        ret
+    }
+
+    // Executions check the stopper along with the limits and end once something is sent on it.
+    pub fn set_stopper(&mut self, stopper: Receiver<()>) {
+        self.budget.stopper = Some(stopper);
+    }
+
+    pub fn add_search_path(&mut self, dir: PathBuf) {
+        self.parser.add_search_path(dir);
+    }
+
+    pub fn define(&mut self, id: &str, obj: Obj) {
+        update_ref_cnt(&obj, &mut self.frame_stack, true);
+
+        let table = &mut self.frame_stack[0].as_mut().unwrap().table;
+
+        if let Some(prev) = table.insert(Id(id.to_string()), obj) {
+            update_ref_cnt(&prev, &mut self.frame_stack, false);
+        }
+    }
+
+    fn exec_ast(
+        &mut self,
+        parse: impl FnOnce(&mut Parser) -> Result<AST>,
+        extra_insts: Option<Vec<Inst>>,
+        file: Option<PathBuf>,
+    ) -> Result<Obj> {
+        let ast = self.parse(parse)?;
         let mut insts = self.codegen.generate(&ast, true);
 
         if let Some(extra) = extra_insts {
@@ -129,403 +398,1732 @@ impl VM {
             insts.extend(extra)
         };
 
-        self.insts = crate::codegen::join(self.insts.clone(), insts);
+        self.pc = self.append(insts, file.clone());
 
-        macro_rules! pop {
-            () => {{
-                let v = std::mem::replace(&mut self.stack[self.sp as usize], Obj::Null);
-                update_ref_cnt(&v, &mut self.frame_stack, false);
-                self.sp -= 1;
-                v
-            }};
+        let (fp, depth, active) = (self.fp, self.winders.len(), self.coroutines.len());
+        let outer = self.enter_level();
+
+        let ret = self.run();
+
+        if ret.is_err() {
+            let limits = self.budget.limits;
+
+            // After thunks still run, but they must not be able to escape the limits.
+            if limits.fuel.is_some() || limits.timeout.is_some() || limits.max_heap.is_some() {
+                self.set_limits(Limits {
+                    fuel: Some(UNWIND_FUEL),
+                    ..Limits::default()
+                });
+            }
+
+            self.unwind(fp, depth, active);
         }
 
-        macro_rules! push {
-            ($obj:expr) => {{
-                let v = $obj;
-                update_ref_cnt(&v, &mut self.frame_stack, true);
-                self.sp += 1;
-                self.stack[self.sp as usize] = v;
-            }};
+        self.leave_level(outer);
+
+        if let Ok(obj) = &ret {
+            self.hold(obj);
         }
 
-        macro_rules! pop_retaining_ref {
-            () => {{
-                let v = std::mem::replace(&mut self.stack[self.sp as usize], Obj::Null);
-                self.sp -= 1;
-                v
-            }};
+        // Errors raised while the prelude runs are reported in the file that called it.
+        ret.map_err(|e| match self.file_at(self.pc).or(file.as_ref()) {
+            Some(path) => e.context(format!("In {}", path.display())),
+            None => e,
+        })
+    }
+
+    // Macro transformers run while parsing, so they use up the budget of the execution.
+    fn parse<T>(&mut self, parse: impl FnOnce(&mut Parser) -> Result<T>) -> Result<T> {
+        self.parser.with_budget(&mut self.budget, parse)
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        let budget = &mut self.budget;
+
+        budget.limits = limits;
+        budget.deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+        budget.heap_base = crate::heap::allocated();
+        budget.executed = 0;
+        budget.next_check = CHECK_INTERVAL.min(limits.fuel.map_or(u64::MAX, |fuel| fuel + 1));
+    }
+
+    fn check_limits(&mut self) -> Result<()> {
+        if self.budget.stopper.as_ref().is_some_and(|stopper| stopper.try_recv().is_ok()) {
+            bail!(Interrupted);
         }
 
-        macro_rules! push_retaining_ref {
-            ($obj:expr) => {{
-                self.sp += 1;
-                self.stack[self.sp as usize] = $obj;
-            }};
+        let Budget {
+            limits,
+            deadline,
+            heap_base,
+            executed,
+            ..
+        } = self.budget;
+
+        if let Some(fuel) = limits.fuel {
+            if executed > fuel {
+                bail!(LimitExceeded::Fuel(fuel));
+            }
         }
 
-        loop {
-            if let Some(stopper) = stopper {
-                if let Ok(_) = stopper.try_recv() {
-                    return Ok(Obj::Null);
-                }
+        if let (Some(deadline), Some(timeout)) = (deadline, limits.timeout) {
+            if Instant::now() >= deadline {
+                bail!(LimitExceeded::Deadline(timeout));
             }
+        }
 
-            let inst = self.insts[self.pc as usize].clone();
+        if let Some(max_heap) = limits.max_heap {
+            if crate::heap::allocated() - heap_base > max_heap as isize {
+                self.collect_garbage(None);
 
-            match &inst {
-                Inst::Push(obj) => {
-                    push!(obj.clone());
-                }
-                Inst::Pop => {
-                    pop!();
-                }
-                Inst::Dup => {
-                    let v = pop_retaining_ref!();
-                    push!(v.clone());
-                    push_retaining_ref!(v);
+                if crate::heap::allocated() - heap_base > max_heap as isize {
+                    bail!(LimitExceeded::Heap(max_heap));
                 }
-                Inst::Set(id) => {
-                    let v = pop_retaining_ref!();
+            }
+        }
 
-                    let prev = find_var(&id, &self.fp, &mut self.frame_stack, |obj| {
-                        std::mem::replace(obj, v)
-                    })
-                    .context(format!("{} is not defined", id.0))?;
+        self.budget.next_check =
+            (executed + CHECK_INTERVAL).min(limits.fuel.map_or(u64::MAX, |fuel| fuel + 1));
 
-                    update_ref_cnt(&prev, &mut self.frame_stack, false);
-                }
-                Inst::CollectVArg(id) => {
-                    let mut args = vec![];
+        Ok(())
+    }
 
-                    loop {
-                        let v = pop_retaining_ref!();
+    // Each run of the main loop gets its own level, and continuations can only be resumed
+    // on the level that captured them.
+    fn enter_level(&mut self) -> (u32, u64) {
+        self.levels += 1;
 
-                        if let Obj::Context { pc: _, fp: _ } = v {
-                            push_retaining_ref!(v);
-                            break;
-                        }
+        let outer = (self.base, self.level);
 
-                        args.push(v);
-                    }
+        self.base = self.sp;
+        self.level = self.levels;
 
-                    let mut list = Obj::Null;
+        outer
+    }
 
-                    for arg in args.into_iter().rev() {
-                        list = Obj::Pair(Rc::new(RefCell::new((arg, list))))
-                    }
+    fn leave_level(&mut self, (base, level): (u32, u64)) {
+        self.base = base;
+        self.level = level;
+    }
 
-                    push_retaining_ref!(list);
-                }
-                Inst::Get(id) => {
-                    let v = find_var(&id, &self.fp, &mut self.frame_stack, |obj| obj.clone())
-                        .context(format!("{} is not defined", id.0))?;
+    // Puts the VM back into the state it had before a failed execution so that it stays usable.
+    fn unwind(&mut self, fp: u32, depth: usize, active: usize) {
+        self.unwind_coroutines(active);
 
-                    push!(v);
-                }
-                Inst::Def(id) => {
-                    let frame = self.frame_stack[self.fp as usize].as_mut().unwrap();
-                    frame.table.insert(id.clone(), Obj::Null);
-                }
-                Inst::Jump(pc_next) => {
-                    self.pc = *pc_next;
-                    continue;
-                }
-                Inst::JumpIf(pc_next) => {
-                    if pop!() != Obj::Bool(false) {
-                        self.pc = *pc_next;
-                        continue;
-                    };
-                }
-                Inst::Call => {
-                    let Obj::Closure {
-                        addr,
-                        fp: fp_parent,
-                    } = pop_retaining_ref!()
-                    else {
-                        bail!("Not closure")
-                    };
+        if self.coroutines.is_empty() {
+            self.ticks = self.main_quantum();
+        }
 
-                    let new_frame = Frame {
-                        parent: Some(fp_parent),
-                        table: Default::default(),
-                        ref_cnt: 1,
-                    };
+        while self.winders.len() > depth {
+            let winder = self.winders.pop().unwrap();
+            let _ = self.call(&winder.1, &[]);
+        }
 
-                    while self.frame_stack[self.fp as usize].is_some() {
-                        self.fp += 1;
-                    }
+        while self.sp > self.base {
+            let v = std::mem::replace(&mut self.stack[self.sp as usize], Obj::Null);
+            update_ref_cnt(&v, &mut self.frame_stack, false);
+            self.sp -= 1;
+        }
 
-                    self.frame_stack[self.fp as usize] = Some(new_frame);
+        self.fp = fp;
+    }
 
-                    self.pc = addr;
+    // Ends the coroutines resumed after the first `active` ones, running their after thunks.
+    fn unwind_coroutines(&mut self, active: usize) {
+        while self.coroutines.len() > active {
+            while let Some(winder) = self.winders.pop() {
+                let _ = self.call(&winder.1, &[]);
+            }
 
-                    continue;
-                }
-                Inst::OptCall => {
-                    let Obj::Closure {
-                        addr,
-                        fp: fp_parent,
-                    } = pop_retaining_ref!()
-                    else {
-                        panic!("Not closure")
-                    };
+            let co = self.coroutines.pop().unwrap();
+            self.switch(&co);
 
-                    let local_ref_cnt = self
-                        .frame_stack
-                        .get(self.fp as usize)
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .table
-                        .iter()
-                        .fold(0, |c, (_, obj)| {
-                            if let Obj::Closure { addr, fp } = obj {
-                                c + if *fp == self.fp { 1 } else { 0 }
-                            } else {
-                                c
-                            }
-                        });
+            let mut co = co.write().unwrap();
+            co.state = CoroutineState::Dead;
+            co.stack = vec![];
+        }
+    }
 
-                    if self.frame_stack[self.fp as usize].as_ref().unwrap().ref_cnt - local_ref_cnt
-                        > 1
-                    {
-                        let new_frame = Frame {
-                            parent: Some(fp_parent),
-                            table: Default::default(),
-                            ref_cnt: 1,
-                        };
+    fn push_raw(&mut self, obj: Obj) {
+        self.sp += 1;
 
-                        while self.frame_stack[self.fp as usize].is_some() {
-                            self.fp += 1;
-                        }
+        if self.sp as usize == self.stack.len() {
+            self.stack.resize(self.stack.len() * 2, Obj::Null);
+        }
 
-                        self.frame_stack[self.fp as usize] = Some(new_frame);
-                    } else {
-                        for (_, obj) in self
-                            .frame_stack
-                            .get(self.fp as usize)
-                            .unwrap()
-                            .as_ref()
-                            .unwrap()
-                            .table
-                            .clone()
-                        {
-                            update_ref_cnt(&obj, &mut self.frame_stack, false);
-                        }
+        self.stack[self.sp as usize] = obj;
+    }
 
-                        self.frame_stack[self.fp as usize] = Some(Frame {
-                            parent: Some(fp_parent),
-                            table: Default::default(),
-                            ref_cnt: 1,
-                        });
-                    }
+    // Exchanges the running state with the one saved in the coroutine, so the same call
+    // switches into a coroutine and back out of it.
+    fn switch(&mut self, co: &Arc<RwLock<Coroutine>>) {
+        let co = &mut *co.write().unwrap();
+
+        std::mem::swap(&mut self.stack, &mut co.stack);
+        std::mem::swap(&mut self.sp, &mut co.sp);
+        std::mem::swap(&mut self.pc, &mut co.pc);
+        std::mem::swap(&mut self.fp, &mut co.fp);
+        std::mem::swap(&mut self.base, &mut co.base);
+        std::mem::swap(&mut self.level, &mut co.level);
+        std::mem::swap(&mut self.winders, &mut co.winders);
+    }
 
-                    self.pc = addr;
+    // Suspends the running thread in the middle of its code and hands control back to the
+    // scheduler, unless something other than the thread itself is running right now.
+    fn preempt(&mut self) -> bool {
+        let Some(co) = self.coroutines.last().cloned() else {
+            return self.preempt_main();
+        };
 
-                    continue;
-                }
-                Inst::Ret => {
-                    for (_, obj) in self
-                        .frame_stack
-                        .get(self.fp as usize)
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .table
-                        .clone()
-                    {
-                        update_ref_cnt(&obj, &mut self.frame_stack, false);
-                    }
+        if !co.read().unwrap().preemptive || co.read().unwrap().id != self.level {
+            return false;
+        }
 
-                    let v = pop_retaining_ref!();
+        co.write().unwrap().state = CoroutineState::Preempted;
 
-                    loop {
-                        let v = pop!();
+        self.coroutines.pop();
+        self.switch(&co);
+        self.push_raw(Obj::Null);
 
-                        let Obj::Context {
-                            pc: pc_prev,
-                            fp: fp_prev,
-                        } = v
-                        else {
-                            continue;
-                        };
+        self.ticks = self.main_quantum();
 
-                        update_ref_cnt(
-                            &Obj::Closure {
-                                addr: 0,
+        true
+    }
+
+    // The main program is preempted by calling the handler the prelude registered, which lets
+    // the threads run. The scheduler itself is part of the prelude and is never interrupted.
+    fn preempt_main(&mut self) -> bool {
+        let Some(handler) = self.preempt_handler.clone() else {
+            return false;
+        };
+
+        if self.pc < self.prelude_end {
+            return false;
+        }
+
+        for obj in [
+            Obj::Context {
+                pc: self.pc,
+                fp: self.fp,
+            },
+            Obj::Context {
+                pc: END_PREEMPT_ADDR,
+                fp: self.fp,
+            },
+            handler,
+        ] {
+            update_ref_cnt(&obj, &mut self.frame_stack, true);
+            self.push_raw(obj);
+        }
+
+        self.pc = CALL_ADDR;
+
+        true
+    }
+
+    fn main_quantum(&self) -> u32 {
+        if self.has_threads && self.preempt_handler.is_some() && self.coroutines.is_empty() {
+            THREAD_QUANTUM
+        } else {
+            0
+        }
+    }
+
+    // An error raised by a thread resumed within this run ends that thread alone. The
+    // scheduler gets the message in place of the thread's next request.
+    fn fail_thread(&mut self, e: &anyhow::Error, active: usize) -> bool {
+        if e.is::<LimitExceeded>() || e.is::<Interrupted>() {
+            return false;
+        }
+
+        let Some(i) = self.coroutines.iter().rposition(|co| co.read().unwrap().preemptive) else {
+            return false;
+        };
+
+        if i < active {
+            return false;
+        }
+
+        self.unwind_coroutines(i);
+        self.push_raw(Obj::String(format!("{:#}", e)));
+        self.ticks = self.main_quantum();
+
+        true
+    }
+
+    fn capture(&mut self) -> Obj {
+        let stack = self.stack[self.base as usize + 1..=self.sp as usize].to_vec();
+
+        retain_all(&stack, &mut self.frame_stack);
+        retain_contexts(&stack, self.fp, &mut self.frame_stack);
+
+        Obj::Continuation(Arc::new(Continuation {
+            stack,
+            pc: self.pc + 1,
+            fp: self.fp,
+            winders: self.winders.clone(),
+            level: self.level,
+        }))
+    }
+
+    fn resume(&mut self, k: &Continuation) -> Result<()> {
+        ensure!(
+            k.level == self.level,
+            "Continuation resumed outside of the execution that captured it"
+        );
+
+        let mut vals = vec![];
+
+        while !matches!(self.stack[self.sp as usize], Obj::Context { .. }) {
+            vals.push(std::mem::replace(&mut self.stack[self.sp as usize], Obj::Null));
+            self.sp -= 1;
+        }
+
+        let v = if vals.len() == 1 {
+            vals.pop().unwrap()
+        } else {
+            Obj::Values(vals.into())
+        };
+
+        self.rewind(&k.winders)?;
+
+        while self.sp > self.base {
+            let v = std::mem::replace(&mut self.stack[self.sp as usize], Obj::Null);
+            update_ref_cnt(&v, &mut self.frame_stack, false);
+            self.sp -= 1;
+        }
+
+        retain_all(&k.stack, &mut self.frame_stack);
+        retain_contexts(&k.stack, k.fp, &mut self.frame_stack);
+
+        for obj in k.stack.iter().cloned().chain([v]) {
+            self.push_raw(obj);
+        }
+
+        self.pc = k.pc;
+        self.fp = k.fp;
+
+        Ok(())
+    }
+
+    // Leaves the dynamic extents that the target is not in and enters the ones it is in.
+    fn rewind(&mut self, winders: &[Arc<(Obj, Obj)>]) -> Result<()> {
+        let common =
+            self.winders.iter().zip(winders).take_while(|(l, r)| Arc::ptr_eq(l, r)).count();
+
+        while self.winders.len() > common {
+            let winder = self.winders.pop().unwrap();
+            self.call(&winder.1, &[])?;
+        }
+
+        for winder in &winders[common..] {
+            self.call(&winder.0, &[])?;
+            self.winders.push(winder.clone());
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, insts: Vec<Inst>, file: Option<PathBuf>) -> u32 {
+        let start = self.insts.len() as u32;
+
+        self.insts = crate::codegen::join(self.insts.clone(), insts);
+
+        if let Some(path) = file {
+            self.files.push((start, self.insts.len() as u32, path));
+        }
+
+        start
+    }
+
+    pub fn call(&mut self, f: &Obj, args: &[Obj]) -> Result<Obj> {
+        let (pc, fp, depth, active) = (self.pc, self.fp, self.winders.len(), self.coroutines.len());
+        let outer = self.enter_level();
+
+        let context = Obj::Context {
+            pc: RETURN_ADDR,
+            fp: self.fp,
+        };
+
+        for obj in std::iter::once(context).chain(args.iter().rev().cloned()).chain([f.clone()]) {
+            update_ref_cnt(&obj, &mut self.frame_stack, true);
+            self.push_raw(obj);
+        }
+
+        self.pc = CALL_ADDR;
+
+        let ret = self.run();
+
+        self.pc = pc;
+
+        if ret.is_err() {
+            self.unwind(fp, depth, active);
+        }
+
+        self.leave_level(outer);
+
+        let ret = ret?;
+
+        self.hold(&ret);
+
+        Ok(ret)
+    }
+
+    // Results handed to the host keep their frames alive until the host releases them.
+    fn hold(&mut self, obj: &Obj) {
+        let is_atom =
+            matches!(obj, Obj::Bool(_) | Obj::Number(_) | Obj::String(_) | Obj::Id(_) | Obj::Null);
+
+        if self.level == 0 && !is_atom {
+            self.held.push(obj.clone());
+        }
+    }
+
+    pub fn release(&mut self, obj: &Obj) {
+        if let Some(i) = self.held.iter().rposition(|held| is_eq(held, obj)) {
+            let obj = self.held.swap_remove(i);
+            update_ref_cnt(&obj, &mut self.frame_stack, false);
+        }
+    }
+
+    fn is_less(&mut self, less: &Obj, l: &Obj, r: &Obj) -> Result<bool> {
+        Ok(self.call(less, &[l.clone(), r.clone()])? != Obj::Bool(false))
+    }
+
+    fn merge(&mut self, l: Vec<Obj>, r: Vec<Obj>, less: &Obj) -> Result<Vec<Obj>> {
+        let mut merged = Vec::with_capacity(l.len() + r.len());
+
+        let mut l = l.into_iter().peekable();
+        let mut r = r.into_iter().peekable();
+
+        while let (Some(a), Some(b)) = (l.peek(), r.peek()) {
+            if self.is_less(less, b, a)? {
+                merged.push(r.next().unwrap());
+            } else {
+                merged.push(l.next().unwrap());
+            }
+        }
+
+        merged.extend(l);
+        merged.extend(r);
+
+        Ok(merged)
+    }
+
+    fn sort(&mut self, mut v: Vec<Obj>, less: &Obj) -> Result<Vec<Obj>> {
+        if v.len() < 2 {
+            return Ok(v);
+        }
+
+        let r = v.split_off(v.len() / 2);
+
+        let l = self.sort(v, less)?;
+        let r = self.sort(r, less)?;
+
+        self.merge(l, r, less)
+    }
+
+    fn alloc_frame(&mut self, frame: Frame) -> Result<u32> {
+        if let Some(fp) = self.free_frame() {
+            self.frame_stack[fp] = Some(frame);
+            self.next_frame = fp + 1;
+            return Ok(fp as u32);
+        }
+
+        let traced = self.collect_garbage(frame.parent);
+
+        let len = self.frame_stack.len();
+        let free = self.frame_stack.iter().filter(|f| f.is_none()).count();
+
+        // Collection walks all live data, so keep enough free slots to pay for the next one.
+        if free < len / 4 || free < traced / 4 {
+            self.frame_stack.resize_with((len * 2).max(len + traced), || None);
+        }
+
+        let Some(fp) = self.free_frame() else {
+            bail!("Frame stack overflow")
+        };
+
+        self.frame_stack[fp] = Some(frame);
+        self.next_frame = fp + 1;
+
+        Ok(fp as u32)
+    }
+
+    fn free_frame(&self) -> Option<usize> {
+        let len = self.frame_stack.len();
+
+        (0..len).map(|i| (self.next_frame + i) % len).find(|&i| self.frame_stack[i].is_none())
+    }
+
+    // Reference counts only cover the stack and frame tables, so frames kept by pairs,
+    // vectors and records are reclaimed here once the frame stack runs out.
+    fn collect_garbage(&mut self, extra_root: Option<u32>) -> usize {
+        let mut marked = vec![false; self.frame_stack.len()];
+
+        let mut fps = vec![0, self.fp];
+        fps.extend(extra_root);
+
+        let mut objs: Vec<Obj> = self.stack[1..=self.sp as usize].to_vec();
+        objs.extend(self.roots.iter().cloned());
+        objs.extend(self.held.iter().cloned());
+        objs.extend(self.preempt_handler.clone());
+
+        for winder in &self.winders {
+            objs.extend([winder.0.clone(), winder.1.clone()]);
+        }
+
+        objs.extend(self.coroutines.iter().cloned().map(Obj::Coroutine));
+
+        let mut visited = HashSet::new();
+        let mut traced = 0;
+
+        loop {
+            traced += 1;
+
+            if let Some(fp) = fps.pop() {
+                if std::mem::replace(&mut marked[fp as usize], true) {
+                    continue;
+                }
+
+                if let Some(frame) = &self.frame_stack[fp as usize] {
+                    objs.extend(frame.table.values().cloned());
+                    fps.extend(frame.parent);
+                }
+            } else if let Some(obj) = objs.pop() {
+                match &obj {
+                    Obj::Context { fp, .. } => fps.push(*fp),
+                    _ => fps.extend(frame_of(&obj)),
+                }
+
+                push_children(&obj, &mut objs, &mut visited);
+            } else {
+                break;
+            }
+        }
+
+        for (fp, marked) in marked.into_iter().enumerate() {
+            if !marked {
+                self.frame_stack[fp] = None;
+            }
+        }
+
+        traced
+    }
+
+    fn file_at(&self, pc: u32) -> Option<&PathBuf> {
+        self.files
+            .iter()
+            .rev()
+            .find(|(start, end, _)| (*start..*end).contains(&pc))
+            .map(|(_, _, path)| path)
+    }
+
+    fn run(&mut self) -> Result<Obj> {
+        let active = self.coroutines.len();
+
+        loop {
+            match self.run_insts() {
+                Err(e) if self.fail_thread(&e, active) => continue,
+                ret => return ret,
+            }
+        }
+    }
+
+    fn run_insts(&mut self) -> Result<Obj> {
+        macro_rules! pop {
+            () => {{
+                let v = std::mem::replace(&mut self.stack[self.sp as usize], Obj::Null);
+                update_ref_cnt(&v, &mut self.frame_stack, false);
+                self.sp -= 1;
+                v
+            }};
+        }
+
+        macro_rules! push {
+            ($obj:expr) => {{
+                let v = $obj;
+                update_ref_cnt(&v, &mut self.frame_stack, true);
+                self.push_raw(v);
+            }};
+        }
+
+        macro_rules! pop_retaining_ref {
+            () => {{
+                let v = std::mem::replace(&mut self.stack[self.sp as usize], Obj::Null);
+                self.sp -= 1;
+                v
+            }};
+        }
+
+        macro_rules! push_retaining_ref {
+            ($obj:expr) => {{
+                self.push_raw($obj);
+            }};
+        }
+
+        loop {
+            self.budget.executed += 1;
+
+            if self.budget.executed == self.budget.next_check {
+                self.check_limits()?;
+            }
+
+            if self.ticks > 0 {
+                self.ticks -= 1;
+
+                if self.ticks == 0 && !self.preempt() {
+                    self.ticks = 1;
+                }
+            }
+
+            let inst = self.insts[self.pc as usize].clone();
+
+            match &inst {
+                Inst::Push(obj) => {
+                    push!(obj.clone());
+                }
+                Inst::Pop => {
+                    pop!();
+                }
+                Inst::Dup => {
+                    let v = pop_retaining_ref!();
+                    push!(v.clone());
+                    push_retaining_ref!(v);
+                }
+                Inst::Set(id) => {
+                    let v = pop_retaining_ref!();
+
+                    let prev = find_var(&id, &self.fp, &mut self.frame_stack, |obj| {
+                        std::mem::replace(obj, v)
+                    })
+                    .context(format!("{} is not defined", id.name()))?;
+
+                    update_ref_cnt(&prev, &mut self.frame_stack, false);
+                }
+                Inst::CollectVArg(id) => {
+                    let mut args = vec![];
+
+                    loop {
+                        let v = pop_retaining_ref!();
+
+                        if let Obj::Context { pc: _, fp: _ } = v {
+                            push_retaining_ref!(v);
+                            break;
+                        }
+
+                        args.push(v);
+                    }
+
+                    let mut list = Obj::Null;
+
+                    for arg in args.into_iter().rev() {
+                        list = Obj::Pair(Arc::new(RwLock::new(Cons(arg, list))))
+                    }
+
+                    push_retaining_ref!(list);
+                }
+                Inst::Get(id) => {
+                    let v = find_var(&id, &self.fp, &mut self.frame_stack, |obj| obj.clone())
+                        .context(format!("{} is not defined", id.name()))?;
+
+                    push!(v);
+                }
+                Inst::SetGlobal(id) => {
+                    let v = pop_retaining_ref!();
+                    let fp = root_fp(self.fp, &self.frame_stack);
+
+                    let prev =
+                        find_var(id, &fp, &mut self.frame_stack, |obj| std::mem::replace(obj, v))
+                            .context(format!("{} is not defined", id.name()))?;
+
+                    update_ref_cnt(&prev, &mut self.frame_stack, false);
+                }
+                Inst::GetGlobal(id) => {
+                    let fp = root_fp(self.fp, &self.frame_stack);
+                    let v = find_var(id, &fp, &mut self.frame_stack, |obj| obj.clone())
+                        .context(format!("{} is not defined", id.name()))?;
+
+                    push!(v);
+                }
+                Inst::Def(id) => {
+                    let frame = self.frame_stack[self.fp as usize].as_mut().unwrap();
+
+                    if let Some(prev) = frame.table.insert(id.clone(), Obj::Null) {
+                        update_ref_cnt(&prev, &mut self.frame_stack, false);
+                    }
+                }
+                Inst::Jump(pc_next) => {
+                    self.pc = *pc_next;
+                    continue;
+                }
+                Inst::JumpIf(pc_next) => {
+                    if pop!() != Obj::Bool(false) {
+                        self.pc = *pc_next;
+                        continue;
+                    };
+                }
+                Inst::Call => {
+                    let f = pop_retaining_ref!();
+
+                    if let Obj::Continuation(k) = &f {
+                        update_ref_cnt(&f, &mut self.frame_stack, false);
+                        self.resume(k)?;
+                        continue;
+                    }
+
+                    let Obj::Closure {
+                        addr,
+                        fp: fp_parent,
+                        ..
+                    } = f
+                    else {
+                        bail!("Not closure")
+                    };
+
+                    let new_frame = Frame {
+                        parent: Some(fp_parent),
+                        table: Default::default(),
+                        ref_cnt: 1,
+                    };
+
+                    self.fp = self.alloc_frame(new_frame)?;
+
+                    self.pc = addr;
+
+                    continue;
+                }
+                Inst::OptCall => {
+                    let f = pop_retaining_ref!();
+
+                    if let Obj::Continuation(k) = &f {
+                        update_ref_cnt(&f, &mut self.frame_stack, false);
+                        self.resume(k)?;
+                        continue;
+                    }
+
+                    let Obj::Closure {
+                        addr,
+                        fp: fp_parent,
+                        ..
+                    } = f
+                    else {
+                        bail!("Not closure")
+                    };
+
+                    let local_ref_cnt = self
+                        .frame_stack
+                        .get(self.fp as usize)
+                        .unwrap()
+                        .as_ref()
+                        .unwrap()
+                        .table
+                        .iter()
+                        .fold(0, |c, (_, obj)| {
+                            if let Obj::Closure { fp, .. } = obj {
+                                c + if *fp == self.fp { 1 } else { 0 }
+                            } else {
+                                c
+                            }
+                        });
+
+                    let is_shared = self.frame_stack[self.fp as usize].as_ref().unwrap().ref_cnt
+                        - local_ref_cnt
+                        > 1;
+
+                    if is_shared {
+                        release_self_refs(self.fp, &mut self.frame_stack);
+
+                        let new_frame = Frame {
+                            parent: Some(fp_parent),
+                            table: Default::default(),
+                            ref_cnt: 1,
+                        };
+
+                        let fp_prev = self.fp;
+
+                        self.fp = self.alloc_frame(new_frame)?;
+
+                        update_ref_cnt(
+                            &Obj::Closure {
+                                addr: 0,
+                                fp: fp_prev,
+                                name: None,
+                            },
+                            &mut self.frame_stack,
+                            false,
+                        );
+                    } else {
+                        for (_, obj) in self
+                            .frame_stack
+                            .get(self.fp as usize)
+                            .unwrap()
+                            .as_ref()
+                            .unwrap()
+                            .table
+                            .clone()
+                        {
+                            update_ref_cnt(&obj, &mut self.frame_stack, false);
+                        }
+
+                        if let Some(parent) =
+                            self.frame_stack[self.fp as usize].as_ref().unwrap().parent
+                        {
+                            update_ref_cnt(
+                                &Obj::Closure {
+                                    addr: 0,
+                                    fp: parent,
+                                    name: None,
+                                },
+                                &mut self.frame_stack,
+                                false,
+                            );
+                        }
+
+                        self.frame_stack[self.fp as usize] = Some(Frame {
+                            parent: Some(fp_parent),
+                            table: Default::default(),
+                            ref_cnt: 1,
+                        });
+                    }
+
+                    self.pc = addr;
+
+                    continue;
+                }
+                Inst::Ret => {
+                    release_self_refs(self.fp, &mut self.frame_stack);
+
+                    let v = pop_retaining_ref!();
+
+                    loop {
+                        let v = pop!();
+
+                        let Obj::Context {
+                            pc: pc_prev,
+                            fp: fp_prev,
+                        } = v
+                        else {
+                            continue;
+                        };
+
+                        update_ref_cnt(
+                            &Obj::Closure {
+                                addr: 0,
                                 fp: self.fp,
+                                name: None,
                             },
                             &mut self.frame_stack,
                             false,
                         );
 
-                        self.pc = pc_prev;
-                        self.fp = fp_prev;
+                        self.pc = pc_prev;
+                        self.fp = fp_prev;
+
+                        break;
+                    }
+
+                    push_retaining_ref!(v);
+
+                    continue;
+                }
+                Inst::Load | Inst::Require => {
+                    let src = pop!().string()?;
+                    let path =
+                        std::fs::canonicalize(&src).context(format!("Failed to open {}", src))?;
+                    let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+                    let start = match self.loaded.get(&path) {
+                        Some(_) if matches!(inst, Inst::Require) => {
+                            push!(Obj::Null);
+                            self.pc += 1;
+
+                            continue;
+                        }
+                        Some(&(prev, start)) if prev == modified => start,
+                        _ => {
+                            let ast = self.parse(|parser| parser.parse_file(&path))?;
+                            let mut insts = self.codegen.generate(&ast, false);
+                            insts.push(Inst::EndLoad);
+
+                            let start = self.append(insts, Some(path.clone()));
+                            self.loaded.insert(path, (modified, start));
+
+                            start
+                        }
+                    };
+
+                    push!(Obj::Context {
+                        pc: self.pc + 1,
+                        fp: self.fp
+                    });
+
+                    self.pc = start;
+
+                    continue;
+                }
+                Inst::EndLoad => {
+                    let mut ret = None;
+
+                    loop {
+                        match pop_retaining_ref!() {
+                            Obj::Context { pc, fp } => {
+                                self.pc = pc;
+                                self.fp = fp;
+
+                                break;
+                            }
+                            v if ret.is_none() => ret = Some(v),
+                            v => update_ref_cnt(&v, &mut self.frame_stack, false),
+                        }
+                    }
+
+                    push_retaining_ref!(ret.unwrap_or(Obj::Null));
+
+                    continue;
+                }
+                Inst::Eval => {
+                    let exp = pop!();
+                    let env = pop_retaining_ref!();
+
+                    let Obj::Environment(env_fp) = env else {
+                        bail!("Not Environment")
+                    };
+
+                    let tokens = crate::lexer::get_tokens_from_obj(&exp)?;
+
+                    let next_pc = self.insts.len() as u32;
+
+                    let ast = self
+                        .parse(|parser| {
+                            if env_fp == 0 {
+                                parser.parse_tokens(tokens)
+                            } else {
+                                parser.parse_tokens_sandboxed(tokens)
+                            }
+                        })
+                        .context("Invalid syntax")?;
+                    let mut insts = self.codegen.generate(&ast, false);
+                    insts.push(Inst::EvalRet);
+
+                    self.insts = crate::codegen::join(self.insts.clone(), insts);
+
+                    push!(Obj::Context {
+                        pc: self.pc + 1,
+                        fp: self.fp
+                    });
+                    push_retaining_ref!(env);
+
+                    self.pc = next_pc;
+                    self.fp = env_fp;
+
+                    continue;
+                }
+                Inst::EvalRet => {
+                    let v = pop_retaining_ref!();
+
+                    loop {
+                        let Obj::Context {
+                            pc: pc_prev,
+                            fp: fp_prev,
+                        } = pop!()
+                        else {
+                            continue;
+                        };
+
+                        self.pc = pc_prev;
+                        self.fp = fp_prev;
+
+                        break;
+                    }
+
+                    push_retaining_ref!(v);
+
+                    continue;
+                }
+                Inst::Exit | Inst::Return => {
+                    // The caller holds on to the result, so its frames stay alive.
+                    return Ok(pop_retaining_ref!());
+                }
+                Inst::InteractionEnv => {
+                    push!(Obj::Environment(0));
+                }
+                Inst::ReportEnv | Inst::NullEnv => {
+                    let version = pop!().number()?;
+
+                    if version != Number::Int(5) {
+                        bail!("Unsupported version {}", version);
+                    }
+
+                    let table = if let Inst::ReportEnv = &inst {
+                        self.report_env.clone()
+                    } else {
+                        Default::default()
+                    };
+
+                    for obj in table.values() {
+                        update_ref_cnt(obj, &mut self.frame_stack, true);
+                    }
+
+                    let env_fp = self.alloc_frame(Frame {
+                        parent: None,
+                        table,
+                        ref_cnt: 0,
+                    })?;
+
+                    push!(Obj::Environment(env_fp));
+                }
+                Inst::PushReturnContext(pc) => {
+                    push!(Obj::Context {
+                        pc: *pc,
+                        fp: self.fp
+                    });
+                }
+                Inst::CreateClosure(pc, name) => {
+                    let v = Obj::Closure {
+                        addr: *pc,
+                        fp: self.fp,
+                        name: name.clone(),
+                    };
+                    push!(v);
+                }
+                Inst::Display => {
+                    let v = pop!();
+
+                    print!("{}", v);
+                    push!(Obj::Null);
+                }
+                Inst::Write => {
+                    let v = pop!();
+
+                    print!("{}", v.write());
+                    push!(Obj::Null);
+                }
+                Inst::WriteShared => {
+                    let v = pop!();
+
+                    print!("{}", v.write_shared());
+                    push!(Obj::Null);
+                }
+                Inst::DisplayString | Inst::WriteString | Inst::WriteSharedString => {
+                    let v = pop!();
 
-                        break;
+                    let s = match inst {
+                        Inst::DisplayString => v.to_string(),
+                        Inst::WriteString => v.write().to_string(),
+                        _ => v.write_shared().to_string(),
+                    };
+
+                    push!(Obj::String(s));
+                }
+                Inst::CallCC => {
+                    let f = pop_retaining_ref!();
+                    let k = self.capture();
+
+                    push!(Obj::Context {
+                        pc: self.pc + 1,
+                        fp: self.fp
+                    });
+                    push!(k);
+                    push_retaining_ref!(f);
+
+                    self.pc = CALL_ADDR;
+
+                    continue;
+                }
+                Inst::Wind => {
+                    let before = pop_retaining_ref!();
+                    let after = pop_retaining_ref!();
+
+                    self.winders.push(Arc::new((before, after)));
+                    push!(Obj::Null);
+                }
+                Inst::Unwind => {
+                    self.winders.pop();
+                    push!(Obj::Null);
+                }
+                Inst::MakeCoroutine | Inst::MakeThread => {
+                    let thunk = pop_retaining_ref!();
+
+                    if matches!(inst, Inst::MakeThread) && !self.has_threads {
+                        self.has_threads = true;
+
+                        if self.ticks == 0 {
+                            self.ticks = self.main_quantum();
+                        }
+                    }
+
+                    let mut stack = vec![Obj::Null; COROUTINE_STACK_SIZE];
+                    stack[1] = Obj::Context {
+                        pc: END_COROUTINE_ADDR,
+                        fp: 0,
+                    };
+                    stack[2] = thunk;
+
+                    self.levels += 1;
+
+                    push!(Obj::Coroutine(Arc::new(RwLock::new(Coroutine {
+                        stack,
+                        sp: 2,
+                        pc: CALL_ADDR,
+                        fp: 0,
+                        base: 0,
+                        level: self.levels,
+                        winders: vec![],
+                        id: self.levels,
+                        state: CoroutineState::Created,
+                        preemptive: matches!(inst, Inst::MakeThread),
+                    }))));
+                }
+                Inst::Resume => {
+                    let Obj::Coroutine(co) = pop!() else {
+                        bail!("Not Coroutine")
+                    };
+
+                    let state = co.read().unwrap().state;
+
+                    ensure!(
+                        matches!(
+                            state,
+                            CoroutineState::Created
+                                | CoroutineState::Suspended
+                                | CoroutineState::Preempted
+                        ),
+                        "Coroutine is {}",
+                        if state == CoroutineState::Running {
+                            "already running"
+                        } else {
+                            "dead"
+                        }
+                    );
+
+                    co.write().unwrap().state = CoroutineState::Running;
+
+                    if co.read().unwrap().preemptive {
+                        self.ticks = THREAD_QUANTUM;
+                    }
+
+                    self.pc += 1;
+                    self.switch(&co);
+                    self.coroutines.push(co);
+
+                    if state == CoroutineState::Suspended {
+                        push!(Obj::Null);
+                    }
+
+                    continue;
+                }
+                Inst::Yield => {
+                    let Some(co) = self.coroutines.last().cloned() else {
+                        bail!("Yield outside of a coroutine")
+                    };
+
+                    ensure!(self.level == co.read().unwrap().id, "Yield across a native call");
+
+                    let v = pop_retaining_ref!();
+
+                    co.write().unwrap().state = CoroutineState::Suspended;
+
+                    self.pc += 1;
+                    self.coroutines.pop();
+                    self.switch(&co);
+
+                    if co.read().unwrap().preemptive {
+                        self.ticks = self.main_quantum();
                     }
 
                     push_retaining_ref!(v);
 
                     continue;
                 }
-                Inst::Load => {
-                    let src = pop!().string()?;
-                    let src = read_to_string(&src).context(format!("Failed to open {}", src))?;
+                Inst::EndCoroutine => {
+                    let v = pop_retaining_ref!();
 
-                    let next_pc = self.insts.len() as u32;
+                    let co = self.coroutines.pop().unwrap();
+                    self.switch(&co);
 
-                    let ast = self.parser.parse(src, true).context("Invalid syntax")?;
-                    let insts = self.codegen.generate(&ast, false);
+                    let mut co = co.write().unwrap();
+                    co.state = CoroutineState::Dead;
+                    co.stack = vec![];
 
-                    self.insts = crate::codegen::join(self.insts.clone(), insts);
+                    if co.preemptive {
+                        self.ticks = self.main_quantum();
+                    }
+
+                    push_retaining_ref!(v);
 
-                    self.insts.push(Inst::Jump(self.pc + 1));
+                    continue;
+                }
+                Inst::InThread => {
+                    let in_thread = match self
+                        .coroutines
+                        .iter()
+                        .rposition(|co| co.read().unwrap().preemptive)
+                    {
+                        Some(i) if i + 1 == self.coroutines.len() => true,
+                        Some(_) => {
+                            bail!("Thread operation inside a generator running in a thread")
+                        }
+                        None => false,
+                    };
 
-                    self.pc = next_pc;
+                    push!(Obj::Bool(in_thread));
+                }
+                Inst::OnPreempt => {
+                    let handler = pop_retaining_ref!();
+
+                    if let Some(prev) = self.preempt_handler.replace(handler) {
+                        update_ref_cnt(&prev, &mut self.frame_stack, false);
+                    }
+
+                    push!(Obj::Null);
+                }
+                Inst::EndPreempt => {
+                    pop!();
+
+                    let Obj::Context { pc, fp } = pop!() else {
+                        bail!("Not Context")
+                    };
+
+                    self.pc = pc;
+                    self.fp = fp;
+                    self.ticks = self.main_quantum();
 
                     continue;
                 }
-                Inst::Exit => {
-                    return Ok(pop!());
+                Inst::Clock => {
+                    push!(Obj::Number(Number::Float(self.epoch.elapsed().as_secs_f64())));
                 }
-                Inst::PushReturnContext(pc) => {
-                    push!(Obj::Context {
-                        pc: *pc,
-                        fp: self.fp
-                    });
+                Inst::Sleep => {
+                    let secs = pop!().number()?.float();
+                    let mut duration = Duration::from_secs_f64(secs.max(0.0));
+
+                    if let Some(deadline) = self.budget.deadline {
+                        duration = duration.min(deadline.saturating_duration_since(Instant::now()));
+                    }
+
+                    match &self.budget.stopper {
+                        Some(stopper) => {
+                            if stopper.recv_timeout(duration).is_ok() {
+                                bail!(Interrupted);
+                            }
+                        }
+                        None => std::thread::sleep(duration),
+                    }
+
+                    push!(Obj::Null);
                 }
-                Inst::CreateClosure(pc) => {
-                    let v = Obj::Closure {
-                        addr: *pc,
-                        fp: self.fp,
+                Inst::Add
+                | Inst::Sub
+                | Inst::Mul
+                | Inst::Div
+                | Inst::Eq
+                | Inst::Lt
+                | Inst::Le
+                | Inst::Gt
+                | Inst::Ge => {
+                    let l = pop!().number()?;
+                    let r = pop!().number()?;
+
+                    let obj = if let (Number::Int(l), Number::Int(r)) = (l, r) {
+                        match &inst {
+                            Inst::Add => Obj::Number(Number::Int(
+                                l.checked_add(r).context("Integer overflow")?,
+                            )),
+                            Inst::Sub => Obj::Number(Number::Int(
+                                l.checked_sub(r).context("Integer overflow")?,
+                            )),
+                            Inst::Mul => Obj::Number(Number::Int(
+                                l.checked_mul(r).context("Integer overflow")?,
+                            )),
+                            Inst::Div => {
+                                ensure!(r != 0, "Division by zero");
+                                Obj::Number(Number::Int(
+                                    l.checked_div(r).context("Integer overflow")?,
+                                ))
+                            }
+                            Inst::Eq => Obj::Bool(l == r),
+                            Inst::Lt => Obj::Bool(l < r),
+                            Inst::Le => Obj::Bool(l <= r),
+                            Inst::Gt => Obj::Bool(l > r),
+                            Inst::Ge => Obj::Bool(l >= r),
+                            _ => unreachable!(),
+                        }
+                    } else {
+                        let l = l.float();
+                        let r = r.float();
+
+                        match &inst {
+                            Inst::Add => Obj::Number(Number::Float(l + r)),
+                            Inst::Sub => Obj::Number(Number::Float(l - r)),
+                            Inst::Mul => Obj::Number(Number::Float(l * r)),
+                            Inst::Div => Obj::Number(Number::Float(l / r)),
+                            Inst::Eq => Obj::Bool(l == r),
+                            Inst::Lt => Obj::Bool(l < r),
+                            Inst::Le => Obj::Bool(l <= r),
+                            Inst::Gt => Obj::Bool(l > r),
+                            Inst::Ge => Obj::Bool(l >= r),
+                            _ => unreachable!(),
+                        }
                     };
+
+                    push!(obj);
+                }
+                Inst::And => {
+                    push!(Obj::Bool(pop!().bool()? && pop!().bool()?));
+                }
+                Inst::Or => {
+                    push!(Obj::Bool(pop!().bool()? || pop!().bool()?));
+                }
+                Inst::Not => {
+                    push!(Obj::Bool(pop!() == Obj::Bool(false)));
+                }
+                Inst::Cons => {
+                    let l = pop_retaining_ref!();
+                    let r = pop_retaining_ref!();
+
+                    let v = Obj::Pair(Arc::new(RwLock::new(Cons(l, r))));
+                    push_retaining_ref!(v);
+                }
+                Inst::Car => {
+                    let Obj::Pair(v) = pop_retaining_ref!() else {
+                        bail!("Not Pair")
+                    };
+
+                    let v = v.read().unwrap().0.clone();
+                    push!(v);
+                }
+                Inst::Cdr => {
+                    let Obj::Pair(v) = pop_retaining_ref!() else {
+                        bail!("Not Pair")
+                    };
+
+                    let v = v.read().unwrap().1.clone();
                     push!(v);
                 }
-                Inst::Display => {
+                Inst::SetCar => {
+                    let v = pop_retaining_ref!();
+                    let l = pop_retaining_ref!();
+
+                    let Obj::Pair(v) = v else { bail!("Not Pair") };
+
+                    let mut v = v.write().unwrap();
+
+                    let l = std::mem::replace(&mut v.0, l);
+
+                    update_ref_cnt(&l, &mut self.frame_stack, false);
+                    push!(Obj::Null);
+                }
+                Inst::SetCdr => {
+                    let v = pop_retaining_ref!();
+                    let r = pop_retaining_ref!();
+
+                    let Obj::Pair(v) = v else { bail!("Not Pair") };
+
+                    let mut v = v.write().unwrap();
+
+                    let r = std::mem::replace(&mut v.1, r);
+
+                    update_ref_cnt(&r, &mut self.frame_stack, false);
+                    push!(Obj::Null);
+                }
+                Inst::Error(msg) => {
+                    let v = pop!();
+                    bail!("{}: {}", msg, v.write());
+                }
+                Inst::Raise => {
+                    bail!("{}", pop!().string()?);
+                }
+                Inst::MatchArity(n, has_rest) => {
+                    let len = pop!().list_elems()?.len();
+                    push!(Obj::Bool(if *has_rest { len >= *n } else { len == *n }));
+                }
+                Inst::Append => {
+                    let l = pop_retaining_ref!();
+                    let r = pop_retaining_ref!();
+
+                    if r == Obj::Null {
+                        let _ = l.clone().list_elems()?;
+                        push_retaining_ref!(l);
+                    } else {
+                        let mut list = r;
+                        let elems = l.list_elems()?;
+
+                        retain_all(&elems, &mut self.frame_stack);
+
+                        for v in elems.into_iter().rev() {
+                            list = Obj::Pair(Arc::new(RwLock::new(Cons(v, list))));
+                        }
+
+                        push_retaining_ref!(list);
+                    }
+                }
+                Inst::AppendDestructive => {
+                    let lists: Vec<_> =
+                        pop!().list_elems()?.into_iter().filter(|l| *l != Obj::Null).collect();
+
+                    for (l, r) in lists.iter().zip(lists.iter().skip(1)) {
+                        let mut pair = l.clone();
+
+                        loop {
+                            let Obj::Pair(p) = pair else {
+                                bail!("Not Pair")
+                            };
+
+                            let next = p.read().unwrap().1.clone();
+
+                            if let Obj::Pair(_) = next {
+                                pair = next;
+                            } else {
+                                p.write().unwrap().1 = r.clone();
+                                break;
+                            }
+                        }
+                    }
+
+                    push!(lists.into_iter().next().unwrap_or(Obj::Null));
+                }
+                Inst::ExpandList => {
+                    let v = pop_retaining_ref!();
+
+                    for e in v.list_elems()?.into_iter().rev() {
+                        push!(e);
+                    }
+                }
+                Inst::Length => {
+                    let len = pop!().list_elems()?.len();
+                    push!(Obj::Number(Number::from(len as i64)));
+                }
+                Inst::Reverse => {
+                    let elems = pop!().list_elems()?;
+
+                    retain_all(&elems, &mut self.frame_stack);
+
+                    let mut list = Obj::Null;
+
+                    for v in elems {
+                        list = Obj::Pair(Arc::new(RwLock::new(Cons(v, list))));
+                    }
+
+                    push!(list);
+                }
+                Inst::ListTail => {
+                    let mut list = pop!();
+                    let k = pop!().number()?.int();
+
+                    for _ in 0..k {
+                        let Obj::Pair(p) = list else {
+                            bail!("List index out of range: {}", k)
+                        };
+
+                        list = p.read().unwrap().1.clone();
+                    }
+
+                    push!(list);
+                }
+                Inst::ListCopy => {
+                    let v = pop!().list_elems()?;
+
+                    retain_all(&v, &mut self.frame_stack);
+                    push!(Obj::list(v));
+                }
+                Inst::Sort | Inst::SortDestructive => {
+                    let seq = pop!();
+                    let less = pop_retaining_ref!();
+
+                    self.roots.push(seq.clone());
+
+                    let sorted = match &seq {
+                        Obj::Vector(v) => self.sort(v.read().unwrap().clone(), &less),
+                        _ => seq.clone().list_elems().and_then(|elems| self.sort(elems, &less)),
+                    };
+
+                    self.roots.pop();
+
+                    update_ref_cnt(&less, &mut self.frame_stack, false);
+
+                    let sorted = sorted?;
+
+                    let sorted = match &seq {
+                        Obj::Vector(v) => {
+                            if let Inst::Sort = inst {
+                                retain_all(&sorted, &mut self.frame_stack);
+                                Obj::Vector(Arc::new(RwLock::new(sorted)))
+                            } else {
+                                *v.write().unwrap() = sorted;
+                                seq
+                            }
+                        }
+                        _ => {
+                            if let Inst::Sort = inst {
+                                retain_all(&sorted, &mut self.frame_stack);
+                                Obj::list(sorted)
+                            } else {
+                                let mut pair = seq.clone();
+
+                                for v in sorted {
+                                    let Obj::Pair(p) = pair else { unreachable!() };
+                                    p.write().unwrap().0 = v;
+                                    pair = p.read().unwrap().1.clone();
+                                }
+
+                                seq
+                            }
+                        }
+                    };
+
+                    push!(sorted);
+                }
+                Inst::Merge => {
+                    let l = pop!();
+                    let r = pop!();
+                    let less = pop_retaining_ref!();
+
+                    self.roots.extend([l.clone(), r.clone()]);
+
+                    let merged = l
+                        .list_elems()
+                        .and_then(|l| Ok((l, r.list_elems()?)))
+                        .and_then(|(l, r)| self.merge(l, r, &less));
+
+                    self.roots.truncate(self.roots.len() - 2);
+
+                    update_ref_cnt(&less, &mut self.frame_stack, false);
+
+                    let merged = merged?;
+
+                    retain_all(&merged, &mut self.frame_stack);
+                    push!(Obj::list(merged));
+                }
+                Inst::Memq | Inst::Member | Inst::Assq | Inst::Assoc => {
                     let v = pop!();
+                    let mut list = pop!();
 
-                    print!("{}", v);
-                    push!(Obj::Null);
-                }
-                Inst::Add
-                | Inst::Sub
-                | Inst::Mul
-                | Inst::Div
-                | Inst::Eq
-                | Inst::Lt
-                | Inst::Le
-                | Inst::Gt
-                | Inst::Ge => {
-                    let l = pop!().number()?;
-                    let r = pop!().number()?;
+                    let is_equal = matches!(inst, Inst::Member | Inst::Assoc);
+                    let is_assoc = matches!(inst, Inst::Assq | Inst::Assoc);
 
-                    let obj = if let (Number::Int(l), Number::Int(r)) = (l, r) {
-                        match &inst {
-                            Inst::Add => Obj::Number(Number::Int(l + r)),
-                            Inst::Sub => Obj::Number(Number::Int(l - r)),
-                            Inst::Mul => Obj::Number(Number::Int(l * r)),
-                            Inst::Div => Obj::Number(Number::Int(l / r)),
-                            Inst::Eq => Obj::Bool(l == r),
-                            Inst::Lt => Obj::Bool(l < r),
-                            Inst::Le => Obj::Bool(l <= r),
-                            Inst::Gt => Obj::Bool(l > r),
-                            Inst::Ge => Obj::Bool(l >= r),
-                            _ => unreachable!(),
-                        }
-                    } else {
-                        let l = l.float();
-                        let r = r.float();
+                    let found = loop {
+                        let Obj::Pair(p) = list else {
+                            break Obj::Bool(false);
+                        };
 
-                        match &inst {
-                            Inst::Add => Obj::Number(Number::Float(l + r)),
-                            Inst::Sub => Obj::Number(Number::Float(l - r)),
-                            Inst::Mul => Obj::Number(Number::Float(l * r)),
-                            Inst::Div => Obj::Number(Number::Float(l / r)),
-                            Inst::Eq => Obj::Bool(l == r),
-                            Inst::Lt => Obj::Bool(l < r),
-                            Inst::Le => Obj::Bool(l <= r),
-                            Inst::Gt => Obj::Bool(l > r),
-                            Inst::Ge => Obj::Bool(l >= r),
-                            _ => unreachable!(),
+                        let (elem, next) = p.read().unwrap().cloned();
+
+                        let key = if is_assoc {
+                            let Obj::Pair(entry) = &elem else {
+                                bail!("Not Pair")
+                            };
+
+                            entry.read().unwrap().0.clone()
+                        } else {
+                            elem.clone()
+                        };
+
+                        if if is_equal { key == v } else { is_eq(&key, &v) } {
+                            break if is_assoc { elem } else { Obj::Pair(p) };
                         }
+
+                        list = next;
                     };
 
-                    push!(obj);
+                    push!(found);
                 }
-                Inst::And => {
-                    push!(Obj::Bool(pop!().bool()? && pop!().bool()?));
+                Inst::Delete => {
+                    let v = pop!();
+                    let list: Vec<_> =
+                        pop!().list_elems()?.into_iter().filter(|e| *e != v).collect();
+
+                    retain_all(&list, &mut self.frame_stack);
+                    push!(Obj::list(list));
                 }
-                Inst::Or => {
-                    push!(Obj::Bool(pop!().bool()? || pop!().bool()?));
+                Inst::DeleteDuplicates => {
+                    let mut elems: Vec<Obj> = vec![];
+
+                    for e in pop!().list_elems()? {
+                        if !elems.contains(&e) {
+                            elems.push(e);
+                        }
+                    }
+
+                    retain_all(&elems, &mut self.frame_stack);
+                    push!(Obj::list(elems));
                 }
-                Inst::Not => {
-                    push!(Obj::Bool(pop!() == Obj::Bool(false)));
+                Inst::Iota => {
+                    let count = pop!().number()?.int();
+                    let start = pop!().number()?;
+                    let step = pop!().number()?;
+
+                    ensure!(count >= 0, "Negative count: {}", count);
+
+                    let elems = (0..count)
+                        .map(|i| match (start, step) {
+                            (Number::Int(start), Number::Int(step)) => {
+                                Number::Int(start + i * step)
+                            }
+                            _ => Number::Float(start.float() + i as f64 * step.float()),
+                        })
+                        .map(Obj::Number)
+                        .collect();
+
+                    push!(Obj::list(elems));
                 }
-                Inst::Cons => {
-                    let l = pop_retaining_ref!();
-                    let r = pop_retaining_ref!();
+                Inst::Cars | Inst::Cdrs => {
+                    let lists = pop!().list_elems()?;
+                    let mut elems = vec![];
 
-                    let v = Obj::Pair(Rc::new(RefCell::new((l, r))));
-                    push_retaining_ref!(v);
+                    for list in &lists {
+                        let Obj::Pair(p) = list else {
+                            break;
+                        };
+
+                        let p = p.read().unwrap();
+                        elems.push(if let Inst::Cars = inst {
+                            p.0.clone()
+                        } else {
+                            p.1.clone()
+                        });
+                    }
+
+                    if lists.is_empty() || elems.len() < lists.len() {
+                        push!(Obj::Bool(false));
+                    } else {
+                        retain_all(&elems, &mut self.frame_stack);
+                        push!(Obj::list(elems));
+                    }
                 }
-                Inst::Car => {
-                    let Obj::Pair(v) = pop_retaining_ref!() else {
-                        bail!("Not Pair")
+                Inst::ListToValues => {
+                    let mut v = pop_retaining_ref!().list_elems()?;
+
+                    retain_all(&v, &mut self.frame_stack);
+
+                    if v.len() == 1 {
+                        push_retaining_ref!(v.pop().unwrap());
+                    } else {
+                        push_retaining_ref!(Obj::Values(v.into()));
+                    }
+                }
+                Inst::ValuesToList => {
+                    let v = match pop_retaining_ref!() {
+                        Obj::Values(v) => {
+                            retain_all(&v, &mut self.frame_stack);
+                            v.to_vec()
+                        }
+                        v => vec![v],
                     };
 
-                    let v = v.borrow();
+                    let mut list = Obj::Null;
 
-                    let l = &v.0;
-                    let r = &v.1;
+                    for v in v.into_iter().rev() {
+                        list = Obj::Pair(Arc::new(RwLock::new(Cons(v, list))))
+                    }
 
-                    update_ref_cnt(&r, &mut self.frame_stack, false);
-                    push_retaining_ref!(l.clone());
+                    push_retaining_ref!(list);
                 }
-                Inst::Cdr => {
-                    let Obj::Pair(v) = pop_retaining_ref!() else {
-                        bail!("Not Pair")
+                Inst::MakeRecordType(name, fields) => {
+                    push!(Obj::RecordType(Arc::new(RecordType {
+                        name: name.clone(),
+                        fields: fields.clone(),
+                    })));
+                }
+                Inst::MakeRecord => {
+                    let Obj::RecordType(rtd) = pop!() else {
+                        bail!("Not RecordType")
                     };
+                    let fields = pop_retaining_ref!().list_elems()?;
 
-                    let v = v.borrow();
+                    retain_all(&fields, &mut self.frame_stack);
 
-                    let l = &v.0;
-                    let r = &v.1;
-
-                    update_ref_cnt(&l, &mut self.frame_stack, false);
-                    push_retaining_ref!(r.clone());
+                    push_retaining_ref!(Obj::Record(Arc::new(Record {
+                        rtd,
+                        fields: RwLock::new(fields),
+                    })));
                 }
-                Inst::SetCar => {
-                    let v = pop_retaining_ref!();
-                    let l = pop_retaining_ref!();
+                Inst::RecordRef | Inst::RecordSet => {
+                    let Obj::RecordType(rtd) = pop!() else {
+                        bail!("Not RecordType")
+                    };
 
-                    let Obj::Pair(v) = v else { bail!("Not Pair") };
+                    let record = match pop!() {
+                        Obj::Record(r) if Arc::ptr_eq(&r.rtd, &rtd) => r,
+                        v => bail!("{} is not a {} record", v, rtd.name.0),
+                    };
 
-                    let mut v = v.borrow_mut();
+                    let idx = pop!().number()?.int() as usize;
 
-                    let l = std::mem::replace(&mut v.0, l);
+                    if let Inst::RecordRef = &inst {
+                        let v = record.fields.read().unwrap()[idx].clone();
+                        push!(v);
+                    } else {
+                        let v = pop_retaining_ref!();
+                        let prev = std::mem::replace(&mut record.fields.write().unwrap()[idx], v);
 
-                    update_ref_cnt(&l, &mut self.frame_stack, false);
+                        update_ref_cnt(&prev, &mut self.frame_stack, false);
+                        push!(Obj::Null);
+                    }
                 }
-                Inst::SetCdr => {
-                    let v = pop_retaining_ref!();
-                    let r = pop_retaining_ref!();
-
-                    let Obj::Pair(v) = v else { bail!("Not Pair") };
+                Inst::IsRecord => {
+                    let Obj::RecordType(rtd) = pop!() else {
+                        bail!("Not RecordType")
+                    };
 
-                    let mut v = v.borrow_mut();
+                    push!(Obj::Bool(match pop!() {
+                        Obj::Record(r) => Arc::ptr_eq(&r.rtd, &rtd),
+                        _ => false,
+                    }));
+                }
+                Inst::Vector(n) => {
+                    let mut v = vec![];
 
-                    let r = std::mem::replace(&mut v.1, r);
+                    for _ in 0..*n {
+                        v.push(pop_retaining_ref!());
+                    }
 
-                    update_ref_cnt(&r, &mut self.frame_stack, false);
+                    push_retaining_ref!(Obj::Vector(Arc::new(RwLock::new(v))));
                 }
-                Inst::ExpandList => {
-                    let v = pop_retaining_ref!();
+                Inst::VectorLength => {
+                    let v = pop!().vector()?;
+                    push!(Obj::Number(Number::from(v.read().unwrap().len() as i64)));
+                }
+                Inst::VectorRef | Inst::VectorSet => {
+                    let v = pop!().vector()?;
+                    let k = pop!().number()?.int();
+
+                    ensure!(
+                        0 <= k && (k as usize) < v.read().unwrap().len(),
+                        "Vector index out of range: {}",
+                        k
+                    );
+
+                    if let Inst::VectorRef = inst {
+                        push!(v.read().unwrap()[k as usize].clone());
+                    } else {
+                        let obj = pop_retaining_ref!();
+                        let old = std::mem::replace(&mut v.write().unwrap()[k as usize], obj);
+                        update_ref_cnt(&old, &mut self.frame_stack, false);
+                        push!(Obj::Null);
+                    }
+                }
+                Inst::VectorToList => {
+                    let v = pop!().vector()?;
 
-                    for e in v.list_elems()?.into_iter().rev() {
-                        push!(e);
+                    retain_all(&v.read().unwrap(), &mut self.frame_stack);
+
+                    let mut list = Obj::Null;
+
+                    for e in v.read().unwrap().iter().rev() {
+                        list = Obj::Pair(Arc::new(RwLock::new(Cons(e.clone(), list))));
                     }
+
+                    push!(list);
+                }
+                Inst::ListToVector => {
+                    let l = pop_retaining_ref!().list_elems()?;
+
+                    retain_all(&l, &mut self.frame_stack);
+                    push_retaining_ref!(Obj::Vector(Arc::new(RwLock::new(l))));
+                }
+                Inst::IsVector => {
+                    push!(Obj::Bool(matches!(pop!(), Obj::Vector(_))));
                 }
                 Inst::IsNull => {
                     push!(Obj::Bool(match pop!() {
@@ -559,13 +2157,13 @@ impl VM {
                 }
                 Inst::IsProc => {
                     push!(Obj::Bool(match pop!() {
-                        Obj::Closure { addr: _, fp: _ } => true,
+                        Obj::Closure { .. } | Obj::Continuation(_) => true,
                         _ => false,
                     }));
                 }
                 Inst::IsSymbol => {
                     push!(Obj::Bool(match pop!() {
-                        Obj::Id(_) => true,
+                        Obj::Id(_) | Obj::Identifier(..) => true,
                         _ => false,
                     }));
                 }
@@ -573,12 +2171,7 @@ impl VM {
                     let l = pop!();
                     let r = pop!();
 
-                    push!(Obj::Bool(match (&l, &r) {
-                        (Obj::Pair(l), Obj::Pair(r)) => {
-                            Rc::ptr_eq(l, r)
-                        }
-                        _ => l == r,
-                    }));
+                    push!(Obj::Bool(is_eq(&l, &r)));
                 }
                 Inst::IsEqual => {
                     push!(Obj::Bool(pop!() == pop!()))
@@ -591,6 +2184,16 @@ impl VM {
                     let v = pop!().string()?;
                     push!(Obj::Id(Id(v)));
                 }
+                Inst::MacroExpand | Inst::MacroExpand1 => {
+                    let form = pop!();
+                    let is_once = matches!(inst, Inst::MacroExpand1);
+                    push!(self.parse(|parser| parser.macroexpand(&form, is_once))?);
+                }
+                Inst::Rename => {
+                    let v = pop!().id()?;
+                    let id_ctx = pop!().number()?.int();
+                    push!(Obj::Identifier(v, id_ctx as u32));
+                }
                 Inst::StrToNum => {
                     let v = pop!().string()?;
                     if let Ok(n) = v.parse::<i64>() {
@@ -618,6 +2221,66 @@ impl VM {
     }
 }
 
+fn retain_contexts(stack: &[Obj], fp: u32, frame_stack: &mut Vec<Option<Frame>>) {
+    let fps = stack.iter().filter_map(|obj| match obj {
+        Obj::Context { fp, .. } => Some(*fp),
+        _ => None,
+    });
+
+    for fp in fps.chain([fp]) {
+        update_ref_cnt(
+            &Obj::Closure {
+                addr: 0,
+                fp,
+                name: None,
+            },
+            frame_stack,
+            true,
+        );
+    }
+}
+
+fn push_children(obj: &Obj, objs: &mut Vec<Obj>, visited: &mut HashSet<*const ()>) {
+    match obj {
+        Obj::Pair(p) if visited.insert(Arc::as_ptr(p) as *const ()) => {
+            let (car, cdr) = p.read().unwrap().cloned();
+            objs.push(car);
+            objs.push(cdr);
+        }
+        Obj::Vector(v) if visited.insert(Arc::as_ptr(v) as *const ()) => {
+            objs.extend(v.read().unwrap().iter().cloned());
+        }
+        Obj::Record(r) if visited.insert(Arc::as_ptr(r) as *const ()) => {
+            objs.extend(r.fields.read().unwrap().iter().cloned());
+        }
+        Obj::Values(v) => objs.extend(v.iter().cloned()),
+        Obj::Continuation(k) if visited.insert(Arc::as_ptr(k) as *const ()) => {
+            objs.extend(k.stack.iter().cloned());
+
+            for winder in &k.winders {
+                objs.extend([winder.0.clone(), winder.1.clone()]);
+            }
+        }
+        Obj::Coroutine(co) if visited.insert(Arc::as_ptr(co) as *const ()) => {
+            let co = co.read().unwrap();
+
+            if let Some(stack) = co.stack.get(1..=co.sp as usize) {
+                objs.extend(stack.iter().cloned());
+            }
+
+            objs.push(Obj::Context {
+                pc: co.pc,
+                fp: co.fp,
+            });
+
+            for winder in &co.winders {
+                objs.extend([winder.0.clone(), winder.1.clone()]);
+            }
+        }
+        _ => (),
+    }
+}
+
 fn find_var<T, F>(
     id: &Id,
     fp: &u32,
@@ -641,31 +2304,409 @@ where
     }
 }
 
+fn root_fp(fp: u32, frame_stack: &[Option<Frame>]) -> u32 {
+    let mut fp = fp;
+
+    while let Some(parent) = frame_stack[fp as usize].as_ref().unwrap().parent {
+        fp = parent;
+    }
+
+    fp
+}
+
+fn is_eq(l: &Obj, r: &Obj) -> bool {
+    match (l, r) {
+        (Obj::Pair(l), Obj::Pair(r)) => Arc::ptr_eq(l, r),
+        (Obj::Record(l), Obj::Record(r)) => Arc::ptr_eq(l, r),
+        (Obj::Vector(l), Obj::Vector(r)) => Arc::ptr_eq(l, r),
+        _ => l == r,
+    }
+}
+
+fn frame_of(obj: &Obj) -> Option<u32> {
+    match obj {
+        Obj::Closure { fp, .. } => Some(*fp),
+        Obj::Environment(fp) => Some(*fp),
+        Obj::Continuation(k) => Some(k.fp),
+        _ => None,
+    }
+}
+
+fn release_self_refs(fp: u32, frame_stack: &mut Vec<Option<Frame>>) {
+    let self_refs: Vec<_> = frame_stack[fp as usize]
+        .as_ref()
+        .unwrap()
+        .table
+        .values()
+        .filter(|obj| frame_of(obj) == Some(fp))
+        .cloned()
+        .collect();
+
+    for obj in self_refs {
+        update_ref_cnt(&obj, frame_stack, false);
+    }
+}
+
+fn retain_all(objs: &[Obj], frame_stack: &mut Vec<Option<Frame>>) {
+    for obj in objs {
+        update_ref_cnt(obj, frame_stack, true);
+    }
+}
+
 fn update_ref_cnt(obj: &Obj, frame_stack: &mut Vec<Option<Frame>>, increment: bool) {
-    let Obj::Closure { addr, fp } = obj else {
+    let Some(fp) = frame_of(obj) else {
         return;
     };
 
-    let mut fp = *fp;
+    let mut fps = vec![fp];
 
-    loop {
-        let frame = frame_stack.get_mut(fp as usize).unwrap();
-        let parent_fp = frame.as_ref().unwrap().parent;
+    while let Some(mut fp) = fps.pop() {
+        loop {
+            let frame = frame_stack.get_mut(fp as usize).unwrap();
+            let parent_fp = frame.as_ref().unwrap().parent;
 
-        if increment {
-            frame.as_mut().unwrap().ref_cnt += 1;
-        } else {
-            frame.as_mut().unwrap().ref_cnt -= 1;
+            if increment {
+                frame.as_mut().unwrap().ref_cnt += 1;
+            } else {
+                frame.as_mut().unwrap().ref_cnt -= 1;
+
+                if frame.as_mut().unwrap().ref_cnt == 0 {
+                    let freed = frame.take().unwrap();
+
+                    fps.extend(
+                        freed.table.values().filter_map(frame_of).filter(|child| *child != fp),
+                    );
+                }
+            }
+
+            let Some(parent_fp) = parent_fp else {
+                break;
+            };
+
+            fp = parent_fp;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm() -> VM {
+        let mut vm = VM::new();
+        vm.load_prelude(crate::prelude()).unwrap();
+        vm
+    }
+
+    fn exec(vm: &mut VM, src: &str) -> Obj {
+        let ret = vm.exec(src.into(), Limits::default(), None, false).unwrap();
+        vm.release(&ret);
+        ret
+    }
+
+    fn exec_held(vm: &mut VM, src: &str) -> Obj {
+        vm.exec(src.into(), Limits::default(), None, false).unwrap()
+    }
 
-            if frame.as_mut().unwrap().ref_cnt == 0 {
-                *frame = None;
+    fn int(n: i64) -> Obj {
+        Obj::Number(Number::Int(n))
+    }
+
+    #[test]
+    fn call_closure_returned_by_exec() {
+        let mut vm = vm();
+
+        let f = exec_held(&mut vm, "(let ((k 10)) (lambda (x) (+ x k)))");
+        assert_eq!(vm.call(&f, &[int(1)]).unwrap(), int(11));
+
+        // Enough garbage to run the collector while the host holds the closure.
+        exec(
+            &mut vm,
+            "(define (churn n) (when (> n 0) (let ((l (list n))) (churn (- n 1))))) (churn 5000)",
+        );
+        assert_eq!(vm.call(&f, &[int(2)]).unwrap(), int(12));
+
+        vm.release(&f);
+        assert!(vm.held.is_empty());
+    }
+
+    #[test]
+    fn host_closure_as_sort_comparator() {
+        let mut vm = vm();
+
+        let less = exec_held(
+            &mut vm,
+            "(let ((key (lambda (x) (- x)))) (lambda (a b) (< (key a) (key b))))",
+        );
+        let sorter = exec_held(&mut vm, "(lambda (less) (sort (list 1 3 2) less))");
+
+        let expected = Obj::list(vec![int(3), int(2), int(1)]);
+        vm.define("host-less", less.clone());
+        assert_eq!(exec(&mut vm, "(sort (list 2 1 3) host-less)"), expected);
+
+        let sorted = vm.call(&sorter, &[less.clone()]).unwrap();
+        assert_eq!(sorted, expected);
+
+        for obj in [sorted, sorter, less] {
+            vm.release(&obj);
+        }
+        assert!(vm.held.is_empty());
+    }
+
+    #[test]
+    fn released_results_are_no_longer_held() {
+        let mut vm = vm();
+
+        let f = exec_held(&mut vm, "(lambda () (list (lambda () 1)))");
+        let l = vm.call(&f, &[]).unwrap();
+        assert_eq!(vm.held.len(), 2);
+
+        vm.release(&l);
+        vm.release(&f);
+        assert!(vm.held.is_empty());
+    }
+
+    #[test]
+    fn toplevel_locals_are_not_globals() {
+        let mut vm = vm();
+
+        let src = "(let ((x 1)) (let* ((y x)) (letrec ((z y)) (+ x y z))))";
+        assert_eq!(exec(&mut vm, src), int(3));
+        assert_eq!(exec(&mut vm, "(case 1 ((1) => -))"), int(-1));
+        assert_eq!(exec(&mut vm, "(cond ((memq 1 '(1)) => car))"), int(1));
+
+        let is_alias = |id: &Id| id.0.contains(' ');
+        assert!(!vm.frame_stack[0].as_ref().unwrap().table.keys().any(is_alias));
+        assert!(!vm.report_env.keys().any(is_alias));
+    }
+
+    #[test]
+    fn each_limit_fails_with_its_own_error() {
+        let mut vm = vm();
+
+        exec(&mut vm, "(define (spin) (spin)) (define (grow l) (grow (cons (iota 100) l)))");
+
+        let cases = [
+            (
+                "(spin)",
+                Limits {
+                    fuel: Some(10_000),
+                    ..Limits::default()
+                },
+            ),
+            (
+                "(spin)",
+                Limits {
+                    timeout: Some(Duration::from_millis(50)),
+                    ..Limits::default()
+                },
+            ),
+            (
+                "(grow '())",
+                Limits {
+                    max_heap: Some(1_000_000),
+                    ..Limits::default()
+                },
+            ),
+        ];
+
+        for (src, limits) in cases {
+            let e = vm.exec(src.into(), limits, None, false).unwrap_err();
+
+            match (e.downcast_ref::<LimitExceeded>(), limits) {
+                (Some(LimitExceeded::Fuel(10_000)), Limits { fuel: Some(_), .. }) => (),
+                (
+                    Some(LimitExceeded::Deadline(_)),
+                    Limits {
+                        timeout: Some(_), ..
+                    },
+                ) => (),
+                (
+                    Some(LimitExceeded::Heap(1_000_000)),
+                    Limits {
+                        max_heap: Some(_), ..
+                    },
+                ) => (),
+                _ => panic!("Unexpected error for {}: {:#}", src, e),
             }
+
+            assert_eq!(exec(&mut vm, "(+ 1 2)"), int(3));
+        }
+    }
+
+    #[test]
+    fn runtime_errors_leave_the_vm_usable() {
+        let mut vm = vm();
+
+        exec(&mut vm, "(define (f) (5))");
+
+        for (src, msg) in [
+            ("(f)", "Not closure"),
+            ("(/ 1 0)", "Division by zero"),
+            ("(/ (- 0 9223372036854775807 1) -1)", "Integer overflow"),
+            ("(* 9223372036854775807 2)", "Integer overflow"),
+        ] {
+            let e = vm.exec(src.into(), Limits::default(), None, false).unwrap_err();
+            assert!(format!("{:#}", e).contains(msg), "Unexpected error for {}: {:#}", src, e);
+
+            assert_eq!(exec(&mut vm, "(+ 1 2)"), int(3));
         }
+    }
+
+    #[test]
+    fn limits_apply_to_macro_expansion() {
+        let mut vm = vm();
+
+        exec(
+            &mut vm,
+            "(define-syntax spin-syntax (er-macro-transformer (lambda (f r c) (let lp () (lp)))))",
+        );
 
-        let Some(parent_fp) = parent_fp else {
-            break;
+        let fuel = Limits {
+            fuel: Some(10_000),
+            ..Limits::default()
         };
+        let timeout = Limits {
+            timeout: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        };
+
+        for src in [
+            "(spin-syntax)",
+            "(eval '(spin-syntax) (interaction-environment))",
+            "(define-syntax m (er-macro-transformer (let lp () (lp))))",
+        ] {
+            for limits in [fuel, timeout] {
+                let e = vm.exec(src.into(), limits, None, false).unwrap_err();
+                assert!(e.is::<LimitExceeded>(), "Unexpected error for {}: {:#}", src, e);
+
+                assert_eq!(exec(&mut vm, "(+ 1 2)"), int(3));
+            }
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        vm.set_stopper(rx);
+
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            tx.send(()).unwrap();
+        });
+
+        let e = vm.exec("(spin-syntax)".into(), Limits::default(), None, false).unwrap_err();
+        assert!(e.is::<Interrupted>(), "Unexpected error: {:#}", e);
+
+        assert_eq!(exec(&mut vm, "(+ 1 2)"), int(3));
+    }
+
+    #[test]
+    fn limit_errors_name_the_file() {
+        let mut vm = vm();
+
+        // The limits run out while the prelude's map and append are running.
+        let path = std::env::temp_dir().join("mini-scheme-limits.scm");
+        std::fs::write(&path, "(define (f l) (f (map - (append l (iota 100))))) (f '())").unwrap();
+
+        for limits in [
+            Limits {
+                fuel: Some(100_000),
+                ..Limits::default()
+            },
+            Limits {
+                timeout: Some(Duration::from_millis(50)),
+                ..Limits::default()
+            },
+            Limits {
+                max_heap: Some(200_000),
+                ..Limits::default()
+            },
+        ] {
+            let e = vm.exec_file(&path, limits).unwrap_err();
+            assert!(format!("{:#}", e).starts_with(&format!("In {}: ", path.display())));
+        }
+    }
+
+    #[test]
+    fn parameters_are_restored_after_errors() {
+        let mut vm = vm();
+
+        exec(&mut vm, "(define p (make-parameter 1))");
+
+        let src = "(parameterize ((p 2)) (car '()))";
+        assert!(vm.exec(src.into(), Limits::default(), None, false).is_err());
+        assert_eq!(exec(&mut vm, "(p)"), int(1));
+    }
+
+    #[test]
+    fn failed_sort_drops_its_roots() {
+        let mut vm = vm();
+
+        exec(&mut vm, "(define (less a b) (car a))");
+
+        for src in [
+            "(sort (list 2 1) less)",
+            "(sort! (vector 2 1) less)",
+            "(merge '(1) '(2) less)",
+        ] {
+            assert!(vm.exec(src.into(), Limits::default(), None, false).is_err());
+            assert!(vm.roots.is_empty());
+        }
+
+        assert_eq!(exec(&mut vm, "(sort (list 2 1) <)"), Obj::list(vec![int(1), int(2)]));
+    }
+
+    #[test]
+    fn sandboxed_eval_cannot_reach_the_interaction_environment() {
+        let mut vm = vm();
+
+        exec(&mut vm, "(define secret 1)");
+
+        for src in [
+            "(eval '(eval '(set! secret 0) (interaction-environment)) (null-environment 5))",
+            "(eval '(eval '(set! secret 0) (interaction-environment)) (scheme-report-environment 5))",
+            "(eval (list (string->symbol \"~interaction-environment\")) (null-environment 5))",
+            "(eval '(define-syntax m (er-macro-transformer car)) (null-environment 5))",
+        ] {
+            assert!(vm.exec(src.into(), Limits::default(), None, false).is_err());
+        }
+
+        assert_eq!(exec(&mut vm, "secret"), int(1));
+    }
+
+    #[test]
+    fn imports_are_limited_to_what_libraries_export() {
+        let mut vm = vm();
+
+        exec(&mut vm, "(define secret 1) (define-syntax m (syntax-rules () ((_) 2)))");
+
+        for (src, msg) in [
+            ("(set! map 0)", "Imported map cannot be set!"),
+            (
+                "(import (rename (scheme base) (car head))) (set! head 0)",
+                "Imported head cannot be set!",
+            ),
+            ("(import (only (scheme base) ~er-expand))", "~er-expand is not exported"),
+            (
+                "(define-library (l1) (export f) (begin (define (f) secret))) (import (l1)) (f)",
+                "secret is not defined",
+            ),
+            (
+                "(define-library (l2) (export f) (begin (define (f) (m)))) (import (l2)) (f)",
+                "m is not defined",
+            ),
+            (
+                "(define-library (l3) (export f) (begin (define (f) (map 1 '(1))))) (import (l3)) (f)",
+                "map is not defined",
+            ),
+        ] {
+            let e = vm.exec(src.into(), Limits::default(), None, false).unwrap_err();
+            assert!(format!("{:#}", e).contains(msg), "Unexpected error for {}: {:#}", src, e);
+
+            assert_eq!(exec(&mut vm, "(map car '((1)))"), Obj::list(vec![int(1)]));
+        }
 
-        fp = parent_fp;
+        // Definitions and local bindings shadow imports.
+        let src = "(define (f map) (set! map 2) map) (define map f) (set! map list) (f 1)";
+        assert_eq!(exec(&mut vm, src), int(2));
+        assert_eq!(exec(&mut vm, "secret"), int(1));
     }
 }
@@ -0,0 +1,78 @@
+(define (classify n)
+  (case n
+    ((0) 'zero)
+    ((1 2 3) 'small)
+    ((a b) 'symbol)
+    (else 'other)))
+
+(display (list (classify 0) (classify 2) (classify 'b) (classify 10)))
+(newline)
+
+(display (case 5 ((5) => (lambda (x) (* x x))) (else 'none)))
+(newline)
+
+(display (case 7 ((5) 'five) (else => (lambda (x) (+ x 1)))))
+(newline)
+
+(display (cond ((memq 'c '(a b c d)) => cdr) (else 'no)))
+(newline)
+
+(display (list (when (> 3 2) 'a 'b) (unless (> 3 2) 'c)))
+(newline)
+
+(define (count-down n)
+  (when (> n 0)
+    (count-down (- n 1))))
+
+(count-down 5000)
+
+(define (count-case n)
+  (case n
+    ((0) 'done)
+    (else (count-case (- n 1)))))
+
+(display (count-case 5000))
+(newline)
+
+(define (count-cond n)
+  (cond ((= n 0) 'done)
+        ((- n 1) => count-cond)))
+
+(display (count-cond 5000))
+(newline)
+
+(display
+  (letrec* ((a 1)
+            (b (+ a 1)))
+    (list a b)))
+(newline)
+
+(define area
+  (case-lambda
+    ((r) (* 3 r r))
+    ((w h) (* w h))
+    ((a b . rest) (list a b rest))))
+
+(display (list (area 2) (area 3 4) (area 1 2 3 4)))
+(newline)
+
+(define (loop-case-lambda n)
+  ((case-lambda
+     ((n) (if (= n 0) 'done (loop-case-lambda (- n 1))))
+     ((n m) 'unused))
+   n))
+
+(display (loop-case-lambda 5000))
+(newline)
+
+(display area)
+(newline)
+
+(assert (= (area 2) 12))
+
+(display (list (case "a" (("a") 'same) (else 'different))
+               (case 'a (("a") 'string) ((a) 'symbol))))
+(newline)
+
+(display (do ((i 0 (+ i 1))) ((= i 10) i)))
+(newline)
@@ -0,0 +1,57 @@
+(display (+ 1 (call/cc (lambda (k) (+ 10 (k 5))))))
+(newline)
+(define (find-first pred l)
+  (call/cc (lambda (return)
+    (for-each (lambda (x) (if (pred x) (return x))) l)
+    #f)))
+(display (list (find-first (lambda (x) (> x 2)) '(1 2 3 4)) (find-first (lambda (x) (> x 9)) '(1 2))))
+(newline)
+(define trail '())
+(define (note x) (set! trail (cons x trail)))
+(define result
+  (call/cc (lambda (k)
+    (dynamic-wind
+      (lambda () (note 'before))
+      (lambda () (note 'during) (k 'escaped) (note 'not-here))
+      (lambda () (note 'after))))))
+(display (list result (reverse trail)))
+(newline)
+(define saved #f)
+(define count 0)
+(define v (call/cc (lambda (k) (set! saved k) 0)))
+(set! count (+ count 1))
+(if (< v 3) (saved (+ v 1)))
+(display (list v count))
+(newline)
+(set! trail '())
+(define re #f)
+(dynamic-wind
+  (lambda () (note 'in))
+  (lambda () (call/cc (lambda (k) (set! re k))) (note 'body))
+  (lambda () (note 'out)))
+(if (< (length trail) 6) (re #f))
+(display (reverse trail))
+(newline)
+(call-with-values (lambda () (call/cc (lambda (k) (k 1 2)))) (lambda (a b) (display (list a b))))
+(newline)
+(define p (make-parameter 1))
+(display (list (call/cc (lambda (k) (parameterize ((p 2)) (k (p))))) (p)))
+(newline)
+
+(define (gen-list l)
+  (define return #f)
+  (define resume-point #f)
+  (lambda ()
+    (call/cc (lambda (r)
+      (set! return r)
+      (if resume-point
+        (resume-point #f)
+        (begin
+          (for-each (lambda (x) (call/cc (lambda (resume) (set! resume-point resume) (return x)))) l)
+          (return 'done)))))))
+(define g (gen-list (iota 2000)))
+(define (drain acc)
+  (let ((v (g)))
+    (if (eq? v 'done) acc (drain (+ acc v)))))
+(display (drain 0))
+(newline)
@@ -0,0 +1,67 @@
+(define-syntax count-args
+  (er-macro-transformer
+    (lambda (form rename compare)
+      (length (cdr form)))))
+
+(display (count-args a b c))
+(newline)
+
+(define-syntax define-getter
+  (er-macro-transformer
+    (lambda (form rename compare)
+      (let ((name (car (cdr form))))
+        `(,(rename 'define)
+          (,(string->symbol (string-append "get-" (symbol->string name))))
+          ,name)))))
+
+(define width 10)
+(define-getter width)
+(display (get-width))
+(newline)
+
+(define-syntax swap!
+  (er-macro-transformer
+    (lambda (form rename compare)
+      (let ((a (car (cdr form)))
+            (b (car (cdr (cdr form)))))
+        `(,(rename 'let) ((,(rename 'tmp) ,a))
+          (,(rename 'set!) ,a ,b)
+          (,(rename 'set!) ,b ,(rename 'tmp)))))))
+
+(define tmp 1)
+(define other 2)
+(swap! tmp other)
+(display (list tmp other))
+(newline)
+
+(define-syntax my-if
+  (er-macro-transformer
+    (lambda (form rename compare)
+      (let ((test (car (cdr form)))
+            (conseq (car (cdr (cdr (cdr form)))))
+            (alt (car (cdr (cdr (cdr (cdr (cdr form))))))))
+        (if (compare (car (cdr (cdr form))) 'then)
+          `(,(rename 'if) ,test ,conseq ,alt)
+          (error "then expected"))))))
+
+(display (my-if #t then 'yes else 'no))
+(newline)
+
+(define (f x)
+  (let-syntax ((twice (er-macro-transformer
+                        (lambda (form rename compare)
+                          `(,(rename '*) 2 ,(car (cdr form)))))))
+    (twice x)))
+
+(display (f 21))
+(newline)
+
+(define-syntax my-or2
+  (er-macro-transformer
+    (lambda (form rename compare)
+      `(,(rename 'let) ((,(rename 't) ,(car (cdr form))))
+        (,(rename 'if) ,(rename 't) ,(rename 't) ,(car (cdr (cdr form))))))))
+
+(define t 5)
+(display (my-or2 #f t))
+(newline)
@@ -0,0 +1,29 @@
+(define env (scheme-report-environment 5))
+
+(eval '(define (square x) (* x x)) env)
+
+(display (eval '(square 12) env))
+(newline)
+
+(display (eval (list 'square (+ 1 2)) env))
+(newline)
+
+(eval '(define counter 0) (interaction-environment))
+(eval '(set! counter (+ counter 1)) (interaction-environment))
+
+(display counter)
+(newline)
+
+(display (eval ''(if define lambda) (null-environment 5)))
+(newline)
+
+(display (map (lambda (exp) (eval exp (interaction-environment))) '((+ 1 2) (car '(a)))))
+(newline)
+
+(define (apply-to-env f) (f '(* 6 7) (scheme-report-environment 5)))
+(display (apply-to-env eval))
+(newline)
+
+(define (eval exp) 'shadowed)
+(display (eval '(+ 1 2)))
+(newline)
@@ -0,0 +1,102 @@
+(define g (make-generator (lambda (yield) (yield 1) (yield 2) (yield 3))))
+(let* ((a (g)) (b (g)) (c (g)) (d (g)))
+  (display (list a b c (eof-object? d) (eof-object? (g)))))
+(newline)
+
+(define (naturals)
+  (make-generator
+    (lambda (yield)
+      (let loop ((i 0))
+        (yield i)
+        (loop (+ i 1))))))
+
+(display (generator->list (gtake (gfilter (lambda (x) (> x 3)) (naturals)) 5)))
+(newline)
+
+(define (tree-walk tree)
+  (make-generator
+    (lambda (yield)
+      (let walk ((t tree))
+        (cond
+          ((null? t) 'skip)
+          ((pair? t) (walk (car t)) (walk (cdr t)))
+          (else (yield t)))))))
+
+(display (generator->list (tree-walk '((1 (2 3)) 4 ((5))))))
+(newline)
+
+(define (count-up n)
+  (make-generator
+    (lambda (yield)
+      (let loop ((i 0))
+        (when (< i n)
+          (yield i)
+          (loop (+ i 1)))))))
+
+(define (doubler gen)
+  (make-generator
+    (lambda (yield)
+      (let loop ()
+        (let ((v (gen)))
+          (unless (eof-object? v)
+            (yield (* 2 v))
+            (loop)))))))
+
+(display (generator->list (doubler (count-up 5))))
+(newline)
+
+(define (deep n) (if (= n 0) 0 (+ 1 (deep (- n 1)))))
+(define d (make-generator (lambda (yield) (yield (deep 2000)))))
+(display (d))
+(newline)
+
+(define trail '())
+(define w
+  (make-generator
+    (lambda (yield)
+      (dynamic-wind
+        (lambda () (set! trail (cons 'in trail)))
+        (lambda () (yield 1) (yield 2))
+        (lambda () (set! trail (cons 'out trail)))))))
+
+(let* ((a (w)) (b (w)) (c (w)))
+  (display (list a b (eof-object? c) (reverse trail))))
+(newline)
+
+(display (generator->list (generator 'a 'b 'c)))
+(display (generator->list (make-iota-generator 5 10 2)))
+(display (generator->list (make-range-generator 0 10 3)))
+(display (generator->vector (vector->generator #(1 2 3 4) 1 3)))
+(newline)
+
+(display (generator->list (gappend (generator 1 2) (generator) (gcons* 3 4 (generator 5)))))
+(display (generator->list (gmap + (generator 1 2 3) (make-iota-generator 10 100))))
+(display (generator->list (gremove (lambda (x) (> x 2)) (generator 1 2 3 4 1))))
+(newline)
+
+(display (generator->list (gdrop (make-iota-generator 6) 4)))
+(display (generator->list (gtake (generator 1 2) 4 'pad)))
+(display (generator->list (gtake-while (lambda (x) (< x 3)) (naturals))))
+(display (generator->list (gdrop-while (lambda (x) (< x 3)) (make-iota-generator 6))))
+(display (generator->list (naturals) 3))
+(newline)
+
+(display (list (generator-fold + 0 (make-iota-generator 20000))
+               (generator-count (lambda (x) (> x 1)) (generator 1 2 3))
+               (generator-find (lambda (x) (> x 1)) (generator 1 2 3))
+               (generator-any (lambda (x) (and (> x 1) (* x 10))) (generator 1 2 3))
+               (generator-every (lambda (x) (> x 0)) (generator 1 2 3))))
+(newline)
+
+(define (accumulate acc gen)
+  (generator-for-each acc gen)
+  (acc (eof-object)))
+
+(display (list (accumulate (list-accumulator) (generator 1 2 3))
+               (accumulate (reverse-list-accumulator) (generator 1 2 3))
+               (accumulate (vector-accumulator) (generator 1 2 3))
+               (accumulate (count-accumulator) (generator 1 2 3))
+               (accumulate (sum-accumulator) (generator 1 2 3))
+               (accumulate (product-accumulator) (generator 1 2 3 4))
+               (accumulate (make-accumulator (lambda (x acc) (if (> x acc) x acc)) 0 (lambda (x) (* x 100))) (generator 3 9 2))))
+(newline)
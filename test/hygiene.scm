@@ -0,0 +1,79 @@
+(define-syntax swap!
+  (syntax-rules ()
+    ((_ a b)
+      (let ((tmp a))
+        (set! a b)
+        (set! b tmp)))))
+
+(define tmp 1)
+(define y 2)
+(swap! tmp y)
+(display (list tmp y))
+(newline)
+
+(define-syntax my-or
+  (syntax-rules ()
+    ((_) #f)
+    ((_ e) e)
+    ((_ e r ...)
+      (let ((t e))
+        (if t t (my-or r ...))))))
+
+(define t 5)
+(display (my-or #f t))
+(newline)
+
+(let ((t 7))
+  (display (my-or #f t))
+  (newline))
+
+(define-syntax my-list
+  (syntax-rules ()
+    ((_ x ...) (list x ...))))
+
+(let ((list vector))
+  (display (my-list 1 2 3))
+  (newline))
+
+(define counter 0)
+
+(define-syntax bump!
+  (syntax-rules ()
+    ((_) (set! counter (+ counter 1)))))
+
+(let ((counter 100))
+  (bump!)
+  (bump!)
+  (display counter)
+  (newline))
+
+(display counter)
+(newline)
+
+(define-syntax tagged
+  (syntax-rules ()
+    ((_ x) (list 'tag `(x ,x tmp)))))
+
+(display (tagged 1))
+(newline)
+
+(define-syntax my-unless
+  (syntax-rules ()
+    ((_ c e ...) (my-or c (begin e ...)))))
+
+(let ((t #f))
+  (display (my-unless t 'ran))
+  (newline))
+
+(define-syntax ten
+  (syntax-rules ()
+    ((_) 10)))
+
+(define-syntax use-ten
+  (syntax-rules ()
+    ((_) (+ (ten) 1))))
+
+(define (f ten) (use-ten))
+
+(display (f 3))
+(newline)
@@ -0,0 +1,48 @@
+; Keywords added on top of the original syntax can still be used as names.
+(define (f assert) assert)
+(display (f 1))
+(newline)
+
+(define (g case) (case 3))
+(display (g (lambda (x) (* x 2))))
+(newline)
+
+(let ((unless list) (=> 7))
+  (display (list (unless 1 2) (cond (#t =>)))))
+(newline)
+
+(let import ((i 0))
+  (if (< i 2)
+    (import (+ i 1))
+    (display i)))
+(newline)
+
+(display '(when case => assert))
+(newline)
+
+; They still start their forms where nothing shadows them.
+(display (let ((x 1)) (case x ((1) 'one) (else 'other))))
+(newline)
+
+(display (cond ((assv 2 '((1 . a) (2 . b))) => cdr) (else 'none)))
+(newline)
+
+; Definitions shadow them for the rest of the program.
+(define (when x) (list 'when x))
+(display (when 2))
+(newline)
+
+(define receive 'r)
+(display receive)
+(newline)
+
+(define (require x) x)
+(display (require 5))
+(newline)
+
+(define-syntax assert
+  (syntax-rules ()
+    ((_ x) (if x 'ok 'failed))))
+
+(display (assert #f))
+(newline)
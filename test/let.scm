@@ -0,0 +1,73 @@
+(define (shadow x)
+  (+ (let ((x (* x 10))) x) x))
+
+(display (shadow 1))
+(newline)
+
+(define (swap a b)
+  (let ((a b) (b a))
+    (list a b)))
+
+(display (swap 1 2))
+(newline)
+
+(define (sequential x)
+  (let* ((x (+ x 1))
+         (y (* x 2))
+         (x (+ x y)))
+    (list x y)))
+
+(display (sequential 1))
+(newline)
+
+(define (mutate n)
+  (let ((acc 0))
+    (do ((i 0 (+ i 1)))
+        ((= i n) acc)
+      (let ((sq (* i i)))
+        (set! acc (+ acc sq))))))
+
+(display (mutate 10))
+(newline)
+
+(define (rec n)
+  (letrec ((a n) (b (+ n 1)))
+    (list a b)))
+
+(display (rec 5))
+(newline)
+
+(define order '())
+
+(define (note x)
+  (set! order (cons x order))
+  x)
+
+(let ((a (note 1)) (b (note 2)))
+  (display (list a b order))
+  (newline))
+
+(define (make-counter start)
+  (let ((n start))
+    (lambda ()
+      (set! n (+ n 1))
+      n)))
+
+(define counter (make-counter 10))
+(counter)
+(display (counter))
+(newline)
+
+(define (capture x)
+  (let ((y (* x 2)))
+    ((lambda () (+ x y)))))
+
+(display (capture 3))
+(newline)
+
+(define (quoted x)
+  (let ((x (+ x 1)))
+    (list 'x x)))
+
+(display (quoted 1))
+(newline)
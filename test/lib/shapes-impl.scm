@@ -0,0 +1,3 @@
+(define (square x) (* x x))
+
+(define (area r) (* 3 (square r)))
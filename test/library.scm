@@ -0,0 +1,57 @@
+(import (lib counter))
+
+(define first (next!))
+(define second (next!))
+(display (list first second current-count))
+(newline)
+
+(display (inc-twice!))
+(newline)
+
+(define helper 'mine)
+(display (list helper (next!)))
+(newline)
+
+(define c (make-counter))
+(c)
+(display (c))
+(newline)
+
+(import (prefix (lib shapes) shapes-))
+
+(display (list (shapes-square 3) (shapes-area 2) (shapes-next!)))
+(newline)
+
+(import (rename (only (lib shapes) square) (square sq)))
+
+(display (sq 5))
+(newline)
+
+(import (except (lib counter) next! make-counter))
+
+(display current-count)
+(newline)
+
+(define-library (local greeting)
+  (export greet)
+  (import (scheme base))
+  (begin
+    (define greeting "hello, ")
+    (define (greet name) (string-append greeting name))))
+
+(import (local greeting))
+
+(define greeting "bye, ")
+(display (greet "library"))
+(newline)
+
+; Primitives are exported as procedures too.
+(import (rename (only (scheme base) car cdr map) (car head)))
+
+(display (map head '((1 2) (3 4))))
+(newline)
+
+(import (except (scheme base) car cdr))
+
+(display (map cdr '((1 2) (3 4))))
+(newline)
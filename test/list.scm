@@ -0,0 +1,39 @@
+(display (list (length '(1 2 3)) (reverse '(1 2 3)) (list-tail '(1 2 3) 1) (list-ref '(a b c) 2) (list-copy '(1 2))))
+(newline)
+(display (list (append) (append '(1) '(2 3) '() '(4)) (append '(1) 2) (append! (list 1 2) '() (list 3))))
+(newline)
+(display (list (memq 'c '(a b c d)) (memv 2 '(1 2 3)) (member '(1) '((0) (1) (2))) (member 2.0 '(1 2 3) =)))
+(newline)
+(display (list (assq 'b '((a 1) (b 2))) (assv 5 '((2 3) (5 7))) (assoc "b" '(("a" . 1) ("b" . 2))) (assoc 2.0 '((1 one) (2 two)) =)))
+(newline)
+(display (list (iota 5) (iota 5 1) (iota 3 0 0.5)))
+(newline)
+(display (list (map (lambda (x) (* x x)) '(1 2 3)) (map + '(1 2 3) '(10 20 30 40)) (map (lambda (x y z) (list x y z)) '(1 2) '(a b) '("x" "y"))))
+(newline)
+(for-each (lambda (x y) (display (+ x y))) '(1 2 3) '(10 20 30))
+(newline)
+(display (list (fold-left (lambda (a x) (cons a x)) '() '(1 2 3)) (fold-right (lambda (x a) (cons x a)) '() '(1 2 3)) (fold-left + 0 '(1 2) '(3 4)) (fold-right list 'z '(1 2) '(a b))))
+(newline)
+(display (list (reduce + 0 '(1 2 3 4)) (reduce + 0 '()) (filter (lambda (x) (> x 2)) '(1 3 2 4))))
+(newline)
+(call-with-values (lambda () (partition (lambda (x) (> x 2)) '(1 3 2 4))) (lambda (a b) (display (list a b))))
+(newline)
+(display (list (delete 2 '(1 2 3 2)) (delete 2 '(1 2 3 4) <) (delete-duplicates '(a b a c b)) (delete-duplicates '(1 2 3 4) (lambda (x y) (= (- x y) 1)))))
+(newline)
+(display (list (find (lambda (x) (> x 2)) '(1 3 5)) (find (lambda (x) (> x 9)) '(1)) (any (lambda (x) (and (> x 2) (* x 10))) '(1 3 5)) (any < '(3 2) '(1 1)) (every (lambda (x) (and (> x 0) x)) '(1 2 3)) (every < '(1 5) '(2 3)) (every (lambda (x) x) (quote ()))))
+(newline)
+(define big (iota 5000))
+(display (list (length big) (length (map (lambda (x) (+ x 1)) big)) (fold-left + 0 big) (length (filter (lambda (x) (= x 7)) big)) (length (append big big)) (car (reverse big)) (list-ref big 4999)))
+(newline)
+(display (list (length (map + big big)) (fold-right (lambda (x acc) (+ acc 1)) 0 big) (any (lambda (x) (= x 4999)) big) (every (lambda (x) (>= x 0)) big) (length (delete 5 big)) (length (list-copy big)) (car (memv 4999 big))))
+(newline)
+(define n 0)
+(for-each (lambda (x) (set! n (+ n 1))) big)
+(display n)
+(newline)
+
+; Procedures called by map and for-each can escape through continuations and yield.
+(display (call/cc (lambda (k) (map (lambda (x) (if (= x 2) (k 'escaped) x)) '(1 2 3)))))
+(newline)
+(display (generator->list (make-coroutine-generator (lambda (yield) (for-each yield '(1 2 3))))))
+(newline)
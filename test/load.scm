@@ -0,0 +1,18 @@
+(define greet-count 0)
+
+(load "load/greeting.scm")
+(load "load/greeting.scm")
+
+(display greet-count)
+(newline)
+
+(require "load/setup.scm")
+(require "load/setup.scm")
+
+(display setup-count)
+(newline)
+
+(load "load/setup.scm")
+
+(display setup-count)
+(newline)
@@ -0,0 +1,6 @@
+(load "name.scm")
+
+(set! greet-count (+ greet-count 1))
+
+(display (string-append "hello " name))
+(newline)
@@ -0,0 +1,2 @@
+(load "unclosed.scm")
+(display "unreachable")
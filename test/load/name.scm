@@ -0,0 +1 @@
+(define name "world")
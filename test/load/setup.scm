@@ -0,0 +1,6 @@
+(define setup-count 0)
+
+(set! setup-count (+ setup-count 1))
+
+(display "setup")
+(newline)
@@ -0,0 +1,2 @@
+(define (broken x)
+  (+ x 1)
@@ -0,0 +1,69 @@
+(define (f x)
+  (let-syntax ((double (syntax-rules () ((_ e) (* 2 e)))))
+    (double x)))
+
+(display (f 21))
+(newline)
+
+(define (g x)
+  (define-syntax inc!
+    (syntax-rules ()
+      ((_ v) (set! v (+ v 1)))))
+  (inc! x)
+  (inc! x)
+  x)
+
+(display (g 1))
+(newline)
+
+(letrec-syntax
+    ((my-or (syntax-rules ()
+              ((_) #f)
+              ((_ e) e)
+              ((_ e r ...) (let ((t e)) (if t t (my-or r ...)))))))
+  (display (my-or #f #f 3))
+  (newline))
+
+(define-syntax twice
+  (syntax-rules ()
+    ((_ e) (begin e e))))
+
+(let ((twice (lambda (x) (* x 2))))
+  (display (twice 5))
+  (newline))
+
+(twice (display "twice"))
+(newline)
+
+(let ((x 'outer))
+  (let-syntax ((get-x (syntax-rules () ((_) x))))
+    (let ((x 'inner))
+      (display (list x (get-x)))
+      (newline))
+    ((lambda (x) (display (list x (get-x))) (newline)) 'arg)))
+
+(define (h)
+  (let-syntax ((local (syntax-rules () ((_) 'local))))
+    (local)))
+
+(display (h))
+(newline)
+
+(define local 'global)
+(display local)
+(newline)
+
+(let-syntax ((helper (syntax-rules () ((_) 'helper))))
+  (let-syntax ((with-var (syntax-rules () ((_ e) (let ((helper 1)) e)))))
+    (display (list (with-var (helper))
+                   (let ((helper (lambda () 'var))) (helper))))
+    (newline)))
+
+(define (local-shadow helper)
+  (let-syntax ((helper (syntax-rules () ((_) 'macro))))
+    (let-syntax ((call-helper (syntax-rules () ((_) (helper)))))
+      (let ((helper (lambda () 'var)))
+        (list (call-helper) (helper))))))
+
+(display (local-shadow 0))
+(newline)
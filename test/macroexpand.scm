@@ -0,0 +1,31 @@
+(define-syntax swap!
+  (syntax-rules ()
+    ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+
+(define-syntax my-unless
+  (syntax-rules ()
+    ((_ test body ...) (my-when (not test) body ...))))
+
+(define-syntax my-when
+  (syntax-rules ()
+    ((_ test body ...) (if test (begin body ...) #f))))
+
+(display (macroexpand-1 '(swap! x y)))
+(newline)
+
+(display (macroexpand-1 '(my-unless done (display 1))))
+(newline)
+
+(display (macroexpand '(my-unless done (display 1))))
+(newline)
+
+(display (macroexpand '(+ 1 2)))
+(newline)
+
+(define-syntax count-args
+  (er-macro-transformer
+    (lambda (form rename compare)
+      (length (cdr form)))))
+
+(display (macroexpand '(count-args a b c)))
+(newline)
@@ -0,0 +1,3 @@
+(define (adder n) (lambda (x) (+ x n)))
+
+(list (adder 1))
@@ -0,0 +1,4 @@
+(define l (list 1 2 3))
+(set-cdr! (cdr (cdr l)) l)
+
+(vector l l)
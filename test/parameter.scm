@@ -0,0 +1,65 @@
+(define indent (make-parameter 0))
+
+(define (show x)
+  (display (make-string-of-spaces (indent)))
+  (display x)
+  (newline))
+
+(define (make-string-of-spaces n)
+  (if (= n 0) "" (string-append " " (make-string-of-spaces (- n 1)))))
+
+(show 'top)
+(parameterize ((indent 2))
+  (show 'nested)
+  (parameterize ((indent (+ (indent) 2)))
+    (show 'deeper))
+  (show 'back))
+(show 'top)
+
+(define radix
+  (make-parameter 10 (lambda (x)
+                       (if (number? x) x (string->number x)))))
+
+(display (list (radix) (parameterize ((radix "2")) (radix)) (radix)))
+(newline)
+
+(define verbose (make-parameter #f))
+(define (log msg) (if (verbose) (begin (display msg) (newline))))
+
+(log "hidden")
+(parameterize ((verbose #t)) (log "shown"))
+(log "hidden again")
+
+(define (f) (indent))
+(display (parameterize ((indent 7) (verbose 'yes)) (list (f) (verbose))))
+(newline)
+
+(define out (open-output-string))
+(write 'sym out)
+(display " and " out)
+(write "str" out)
+(display (list (output-port? out) (get-output-string out)))
+(newline)
+
+(define captured
+  (let ((port (open-output-string)))
+    (parameterize ((current-output-port port))
+      (display "captured ")
+      (show 'text))
+    (get-output-string port)))
+
+(write captured)
+(newline)
+(display "back on stdout")
+(newline)
+
+; Bindings are undone however the body exits.
+(display (list (call/cc (lambda (k) (parameterize ((indent 9)) (k 'escaped)))) (indent)))
+(newline)
+
+(define failing (spawn (lambda () (parameterize ((indent 5)) (car '())))))
+(define after-failure (spawn (lambda () (indent))))
+(let* ((in-thread (thread-join after-failure))
+       (in-main (indent)))
+  (display (list in-thread in-main)))
+(newline)
@@ -0,0 +1,37 @@
+(define count 0)
+
+(define p
+  (delay (begin (set! count (+ count 1))
+                (if (> count x)
+                  count
+                  (force p)))))
+
+(define x 5)
+
+(display (force p))
+(set! x 10)
+(display (force p))
+(newline)
+
+(define q (delay (begin (display "computed ") 42)))
+(display (list (force q) (force q)))
+(newline)
+
+(display (list (promise? q) (promise? 42) (force (make-promise 7)) (force 8)))
+(newline)
+
+(display (eq? q (make-promise q)))
+(newline)
+
+(define (countdown n)
+  (delay-force
+    (if (= n 0)
+      (delay 'done)
+      (countdown (- n 1)))))
+
+(display (force (countdown 3000)))
+(newline)
+
+(define r (delay (values 1 2)))
+(call-with-values (lambda () (force r)) (lambda (a b) (display (list a b))))
+(newline)
@@ -0,0 +1,35 @@
+(define x 1)
+(define l '(2 3))
+
+(display `(a b c))
+(newline)
+(display `(x ,x))
+(newline)
+(display `(0 ,@l 4))
+(newline)
+(display `(,@l))
+(newline)
+(display `(1 . ,x))
+(newline)
+(display `(1 ,(+ x 1) ,@(list (* x 2) (* x 3)) . end))
+(newline)
+(display (quasiquote (x (unquote x) (unquote-splicing l))))
+(newline)
+(display `(1 `(2 ,(3 ,x))))
+(newline)
+(display `(1 `(2 ,(3 ,@l))))
+(newline)
+(display `((nested ,x) ,'(quoted ,x)))
+(newline)
+(display `,x)
+(newline)
+(display `#(1 ,(+ 1 1)))
+(newline)
+(display `#(0 ,@l ,x))
+(newline)
+(display `(a #(b ,x) . #(,@l)))
+(newline)
+(display `#(1 `#(2 ,(3 ,x))))
+(newline)
+(display `#(a b))
+(newline)
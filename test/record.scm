@@ -0,0 +1,32 @@
+(define-record-type <point>
+  (make-point x y)
+  point?
+  (x point-x set-point-x!)
+  (y point-y))
+
+(define p (make-point 1 2))
+
+(write p)
+(newline)
+
+(write (list (point? p) (point? 5) (point-x p) (point-y p)))
+(newline)
+
+(set-point-x! p 10)
+(write (point-x p))
+(newline)
+
+(write (list (equal? (make-point 1 2) (make-point 1 2))
+             (eq? (make-point 1 2) (make-point 1 2))
+             (equal? (make-point 1 2) (make-point 1 3))))
+(newline)
+
+(write point-x)
+(newline)
+
+(define (make-node v)
+  (define-record-type node (new-node value next) node? (value node-value) (next node-next set-node-next!))
+  (new-node v '()))
+
+(write (make-node "a"))
+(newline)
@@ -0,0 +1,47 @@
+(display (sort '(3 1 2 5 4) <))
+(newline)
+
+(display (sort (vector '(1 2 3) '(1) '(1 2)) (lambda (a b) (< (length a) (length b)))))
+(newline)
+
+(display (list-sort > '(3 1 2)))
+(newline)
+
+(display (vector-sort < #(9 8 7)))
+(newline)
+
+(display (merge '(1 4 7) '(2 3 8 9) <))
+(newline)
+
+(define-record-type person
+  (make-person name age)
+  person?
+  (name person-name)
+  (age person-age))
+
+(define people
+  (list (make-person "ann" 31) (make-person "bob" 25) (make-person "cy" 31) (make-person "di" 25)))
+
+(display (map person-name (sort people (lambda (a b) (< (person-age a) (person-age b))))))
+(newline)
+
+(define v (vector 5 3 1 4 2))
+(sort! v <)
+(display v)
+(newline)
+
+(define l (list 5 3 1 4 2))
+(define tail (cdr l))
+(sort! l <)
+(display (list l tail))
+(newline)
+
+(define (by key) (lambda (a b) (< (key a) (key b))))
+(display (sort '((b . 2) (a . 1) (c . 3)) (by (lambda (p) (cdr p)))))
+(newline)
+
+(display (sort (list '(3 1) '(1 2) '(2 0)) (lambda (a b) (< (car (sort a <)) (car (sort b <))))))
+(newline)
+
+(display (length (sort (reverse (iota 2000)) <)))
+(newline)
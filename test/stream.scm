@@ -0,0 +1,53 @@
+(define naturals (stream-from 0))
+
+(display (stream->list (stream-take 10 naturals)))
+(newline)
+
+(define squares (stream-map (lambda (x) (* x x)) naturals))
+(display (stream->list squares 5))
+(newline)
+
+(display (stream->list (stream-map + (stream-from 1) (list->stream '(10 20 30)))))
+(newline)
+
+(define (divides? d n)
+  (let loop ((n n))
+    (cond
+      ((< n d) (= n 0))
+      (else (loop (- n d))))))
+
+(define primes
+  (let sieve ((s (stream-from 2)))
+    (stream-cons
+      (stream-car s)
+      (sieve (stream-filter (lambda (x) (not (divides? (stream-car s) x))) (stream-cdr s))))))
+
+(display (stream->list primes 10))
+(newline)
+
+(display (list (stream? naturals) (stream-pair? naturals) (stream-null? stream-null)
+               (stream-pair? stream-null) (stream? '(1 2))))
+(newline)
+
+(define evaluated 0)
+(define lazy (stream-cons (begin (set! evaluated (+ evaluated 1)) 'a)
+                          (begin (set! evaluated (+ evaluated 1)) stream-null)))
+(display evaluated)
+(stream-car lazy)
+(stream-car lazy)
+(display evaluated)
+(stream-cdr lazy)
+(display evaluated)
+(newline)
+
+(stream-for-each (lambda (x) (display x) (display " ")) (list->stream '(a b c)))
+(newline)
+
+(display (stream-car (stream-filter (lambda (x) (= x 1500)) naturals)))
+(newline)
+
+(display (stream-ref (stream-drop 1000 (stream-from 0 3)) 500))
+(newline)
+
+(display (length (stream->list (stream-take 1500 (stream-map (lambda (x) (list x)) (stream-from 0))))))
+(newline)
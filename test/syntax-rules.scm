@@ -0,0 +1,77 @@
+(define-syntax my-let*
+  (syntax-rules ()
+    ((_ () body ...) (let () body ...))
+    ((_ ((x v) rest ...) body ...)
+      (let ((x v)) (my-let* (rest ...) body ...)))))
+
+(display (my-let* ((a 1) (b (+ a 1)) (c (* b 3))) (list a b c)))
+(newline)
+
+(define-syntax my-cond
+  (syntax-rules (else)
+    ((_ (else e ...)) (begin e ...))
+    ((_ (c e ...) clause ...) (if c (begin e ...) (my-cond clause ...)))))
+
+(display (my-cond (#f 1) ((= 1 2) 2) (else 3)))
+(newline)
+
+(define-syntax flatten-pairs
+  (syntax-rules ()
+    ((_ (a b ...) ...) '(a ... (b ... ...)))))
+
+(display (flatten-pairs (1 2 3) (4) (5 6)))
+(newline)
+
+(define-syntax last-of
+  (syntax-rules ()
+    ((_ x ... y) 'y)))
+
+(display (last-of 1 2 3 4))
+(newline)
+
+(define-syntax rest-of
+  (syntax-rules ()
+    ((_ a . b) 'b)))
+
+(display (rest-of 1 2 3))
+(newline)
+
+(define-syntax my-list
+  (syntax-rules etc ()
+    ((_ x etc) (list x etc '...))))
+
+(display (my-list 1 2 3))
+(newline)
+
+(define-syntax ellipsis-escape
+  (syntax-rules ()
+    ((_ x ...) '((x (... ...)) ...))))
+
+(display (ellipsis-escape 1 2))
+(newline)
+
+(define-syntax vector-sum
+  (syntax-rules ()
+    ((_ #(x ...)) (+ x ...))))
+
+(display (vector-sum #(1 2 3 4)))
+(newline)
+
+(define-syntax swap!
+  (syntax-rules ()
+    ((_ a b)
+      (let ((tmp a))
+        (set! a b)
+        (set! b tmp)))))
+
+(define tmp 1)
+(define other 2)
+(swap! tmp other)
+(display (list tmp other))
+(newline)
+
+(define v #(1 "two" (3)))
+(display (list (vector? v) (vector-length v) (vector-ref v 1) (vector->list v)))
+(newline)
+(write (list->vector '(a b)))
+(newline)
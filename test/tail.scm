@@ -0,0 +1,89 @@
+(define n 5000)
+
+(define (loop-let i acc)
+  (let ((j (- i 1)))
+    (if (= i 0)
+        acc
+        (loop-let j (+ acc 1)))))
+
+(display (loop-let n 0))
+(newline)
+
+(define (loop-let* i acc)
+  (let* ((j (- i 1))
+         (k (+ acc 1)))
+    (if (= i 0)
+        acc
+        (loop-let* j k))))
+
+(display (loop-let* n 0))
+(newline)
+
+(define (loop-letrec i acc)
+  (letrec ((next (lambda (x) (- x 1))))
+    (if (= i 0)
+        acc
+        (loop-letrec (next i) (+ acc 1)))))
+
+(display (loop-letrec n 0))
+(newline)
+
+(display
+  (let loop ((i n) (acc 0))
+    (if (= i 0)
+        acc
+        (loop (- i 1) (+ acc 1)))))
+(newline)
+
+(define (loop-named i)
+  (let loop ((j i))
+    (if (= j 0)
+        (if (= i 0) 'done (loop-named (- i 1)))
+        (loop (- j 1)))))
+
+(display (loop-named 100))
+(newline)
+
+(define (loop-internal i acc)
+  (define (next x) (- x 1))
+  (if (= i 0)
+      acc
+      (loop-internal (next i) (+ acc 1))))
+
+(display (loop-internal n 0))
+(newline)
+
+(define (loop-do i acc)
+  (if (= i 0)
+      acc
+      (do ((k 0 (+ k 1)))
+          ((= k 1) (loop-do (- i 1) (+ acc 1)))
+        k)))
+
+(display (loop-do n 0))
+(newline)
+
+(define (even2? i)
+  (let ((j i))
+    (if (= j 0) #t (odd2? (- j 1)))))
+
+(define (odd2? i)
+  (let ((j i))
+    (if (= j 0) #f (even2? (- j 1)))))
+
+(display (even2? n))
+(newline)
+
+(define (collect . xs) xs)
+
+(define (rest-tail . xs)
+  (collect 1))
+
+(define (define-tail)
+  (define (unused) 0)
+  (collect 2))
+
+(display (rest-tail 'a 'b))
+(newline)
+(display (define-tail))
+(newline)
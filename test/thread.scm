@@ -0,0 +1,102 @@
+(define ch (make-channel))
+
+(define (producer name n)
+  (lambda ()
+    (let loop ((i 0))
+      (when (< i n)
+        (channel-send ch (list name i))
+        (yield)
+        (loop (+ i 1))))
+    name))
+
+(define a (spawn (producer 'a 3)))
+(define b (spawn (producer 'b 2)))
+
+(display (list (thread? a) (channel? ch) (thread-join a) (thread-join b)))
+(newline)
+
+(let loop ((i 0))
+  (when (< i 5)
+    (display (channel-receive ch))
+    (loop (+ i 1))))
+(newline)
+
+; A thread that never yields is still preempted, so the short one finishes first.
+(define (spin n) (if (= n 0) 'done (spin (- n 1))))
+(define order '())
+(define long (spawn (lambda () (spin 20000) (set! order (cons 'long order)))))
+(define short (spawn (lambda () (set! order (cons 'short order)))))
+(thread-join long)
+(thread-join short)
+(display (reverse order))
+(newline)
+
+(define slow (spawn (lambda () (sleep 0.1) 'slow)))
+(define fast (spawn (lambda () (sleep 0.05) 'fast)))
+(define finished (make-channel))
+(spawn (lambda () (channel-send finished (thread-join slow))))
+(spawn (lambda () (channel-send finished (thread-join fast))))
+(let* ((first (channel-receive finished))
+       (second (channel-receive finished)))
+  (display (list first second)))
+(newline)
+
+(define requests (make-channel))
+(define replies (make-channel))
+(define server
+  (spawn
+    (lambda ()
+      (let loop ((served 0))
+        (let ((n (channel-receive requests)))
+          (if (eq? n 'stop)
+            served
+            (begin
+              (channel-send replies (* n n))
+              (loop (+ served 1)))))))))
+
+(define (square n)
+  (channel-send requests n)
+  (channel-receive replies))
+
+(let* ((x (square 3)) (y (square 4)))
+  (channel-send requests 'stop)
+  (display (list x y (thread-join server))))
+(newline)
+
+(define trail '())
+(define wound
+  (spawn
+    (lambda ()
+      (dynamic-wind
+        (lambda () (set! trail (cons 'in trail)))
+        (lambda () (yield) 'body)
+        (lambda () (set! trail (cons 'out trail)))))))
+(let ((result (thread-join wound)))
+  (display (list result (reverse trail))))
+(newline)
+
+; The main program is preempted as well, so a thread runs while it is busy.
+(define ticks 0)
+(define stop #f)
+(define ticker
+  (spawn
+    (lambda ()
+      (let loop ()
+        (unless stop
+          (set! ticks (+ ticks 1))
+          (loop))))))
+(spin 20000)
+(set! stop #t)
+(thread-join ticker)
+(display (> ticks 0))
+(newline)
+
+; An error ends only the thread that raised it, and joining that thread raises it again.
+(define reached #f)
+(define failing (spawn (lambda () (car '()))))
+(define watcher (spawn (lambda () (thread-join failing) (set! reached #t))))
+(define survivor (spawn (lambda () 'survived)))
+(display (list reached (thread-join survivor)))
+(newline)
+
+(channel-receive (make-channel))
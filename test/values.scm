@@ -0,0 +1,29 @@
+(define (div-mod a b)
+  (let ((q (/ a b)))
+    (values q (- a (* b q)))))
+
+(call-with-values
+  (lambda () (div-mod 17 5))
+  (lambda (q r)
+    (write (list q r))
+    (newline)))
+
+(receive (q . rest) (values 1 2 3)
+  (write (list q rest))
+  (newline))
+
+(define x 'outer)
+
+(let-values (((a b) (values 1 2))
+             ((x) (values x)))
+  (write (list a b x))
+  (newline))
+
+(let*-values (((a b) (values 1 2))
+              ((c) (values (+ a b))))
+  (write c)
+  (newline))
+
+(define-values (p q . r) (div-mod 9 4))
+(write (list p q r))
+(newline)
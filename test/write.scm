@@ -0,0 +1,28 @@
+(write "quote \" backslash \\ newline \n tab \t return \r")
+(newline)
+(display "quote \" backslash \\")
+(newline)
+
+(write '())
+(newline)
+(write (list #t #f))
+(newline)
+(write (list "a" 'b 1 '(c . d)))
+(newline)
+
+(define cycle (list 1 2 3))
+(set-cdr! (cdr (cdr cycle)) cycle)
+(write cycle)
+(newline)
+
+(define shared (list 1 2))
+(write-shared (list shared shared))
+(newline)
+(write (list shared shared))
+(newline)
+
+(define (square x) (* x x))
+(write square)
+(newline)
+(write (lambda (x) x))
+(newline)
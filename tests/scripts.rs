@@ -0,0 +1,81 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Runs every script in test/ from that directory and compares what it prints, followed by
+// what it reports on stderr, with the .out file next to it.
+#[test]
+fn scripts_print_expected_output() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+
+    let mut scripts: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "scm"))
+        .collect();
+    scripts.sort();
+
+    // Each script runs in a process of its own, so they can all run at once.
+    let runs: Vec<_> = scripts
+        .into_iter()
+        .map(|script| {
+            let dir = dir.clone();
+
+            std::thread::spawn(move || {
+                let name = script.file_name().unwrap().to_string_lossy().to_string();
+
+                let Ok(expected) = fs::read_to_string(script.with_extension("out")) else {
+                    return Some(format!("{}: no .out file", name));
+                };
+
+                let output = Command::new(env!("CARGO_BIN_EXE_mini-scheme"))
+                    .arg(&name)
+                    .current_dir(&dir)
+                    .output()
+                    .unwrap();
+
+                let actual = String::from_utf8_lossy(&output.stdout).to_string()
+                    + &String::from_utf8_lossy(&output.stderr);
+
+                (actual != expected)
+                    .then(|| format!("{}:\n--- expected\n{}--- actual\n{}", name, expected, actual))
+            })
+        })
+        .collect();
+
+    let failed: Vec<_> = runs.into_iter().filter_map(|run| run.join().unwrap()).collect();
+
+    assert!(failed.is_empty(), "{}", failed.join("\n"));
+}
+
+// A syntax error in a loaded file is reported with the file it is in, and fails the run.
+#[test]
+fn load_reports_syntax_errors() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/load");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_mini-scheme"))
+        .arg("load-unclosed.scm")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(stderr.contains("unclosed.scm: Unexpected end of input"), "{}", stderr);
+}
+
+#[test]
+fn expand_reports_missing_files() {
+    let output = Command::new(env!("CARGO_BIN_EXE_mini-scheme"))
+        .args(["--expand", "missing.scm"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.starts_with("Failed to open missing.scm: "), "{}", stderr);
+}